@@ -0,0 +1,417 @@
+use pyo3::PyResult;
+use sisprog::processor::{
+    assembler::assemble,
+    cpu::{cycle, feed_read, get_acc, get_pc, get_print, get_state, read_memory, CPUState},
+    executable::Executable,
+    expression::Expr,
+    linker::link,
+    object::Instruction,
+    objfile::objdump,
+};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::{self, BufRead, Write},
+    path::Path,
+    process::ExitCode,
+};
+
+const USAGE: &str = "Usage:
+  sisprog asm SOURCE [-o OUT] [-l LISTING] [-D NAME=VALUE]...
+  sisprog link FILES... [-o OUT] [-m] [-g] [-l LAYOUT] [-s]
+  sisprog run PROGRAM [--max-steps N] [--trace]
+  sisprog dis FILE
+  sisprog dump PROGRAM
+
+run prints PRINT to stdout, reads READ from stdin, one number per line, and exits with the
+low byte of ACC when the program halts, or 124 when it runs out of steps";
+
+// Same status timeout(1) gives a command it had to stop
+const OUT_OF_STEPS: u8 = 124;
+
+struct Args {
+    files: Vec<String>,
+    options: HashMap<String, Vec<String>>,
+}
+
+impl Args {
+    // `valued` options take the next argument, `flags` stand alone
+    fn parse(args: &[String], valued: &[&str], flags: &[&str]) -> Result<Args, String> {
+        let mut files = Vec::new();
+        let mut options: HashMap<String, Vec<String>> = HashMap::new();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                option if valued.contains(&option) => match args.next() {
+                    None => return Err(format!("Expected value after {}", option)),
+
+                    Some(value) => options.entry(option.to_owned()).or_default().push(value.clone()),
+                },
+
+                flag if flags.contains(&flag) => {
+                    options.entry(flag.to_owned()).or_default();
+                }
+
+                option if option.starts_with('-') => return Err(format!("Unknown option {}", option)),
+
+                file => files.push(file.to_owned()),
+            }
+        }
+
+        Ok(Args { files, options })
+    }
+
+    fn value(&self, option: &str) -> Option<&str> {
+        self.options.get(option).and_then(|values| values.last()).map(|value| value.as_str())
+    }
+
+    fn flag(&self, option: &str) -> bool {
+        self.options.contains_key(option)
+    }
+
+    fn file(&self) -> Result<&str, String> {
+        match self.files.as_slice() {
+            [file] => Ok(file),
+            [] => Err("Expected a file".to_owned()),
+            [_, extra, ..] => Err(format!("Unexpected argument {}", extra)),
+        }
+    }
+}
+
+fn with_extension(file: &str, extension: &str) -> String {
+    Path::new(file).with_extension(extension).to_string_lossy().into_owned()
+}
+
+fn py<T>(result: PyResult<T>) -> Result<T, String> {
+    result.map_err(|why| why.to_string())
+}
+
+fn read_executable(fita: &str) -> Result<Executable, String> {
+    match fs::read(fita) {
+        Err(why) => Err(format!("Read error: {}", why)),
+
+        Ok(bytes) => Executable::from_bytes(&bytes, fita),
+    }
+}
+
+fn report((ok, message): (bool, String)) -> Result<u8, String> {
+    match ok {
+        true => {
+            println!("{}", message);
+            Ok(0)
+        }
+
+        false => Err(message),
+    }
+}
+
+fn asm(args: &[String]) -> Result<u8, String> {
+    let args = Args::parse(args, &["-o", "-l", "-D"], &[])?;
+    let source = args.file()?;
+
+    let mut defines = HashMap::new();
+    for define in args.options.get("-D").into_iter().flatten() {
+        let (name, value) = match define.split_once('=') {
+            None => (define.as_str(), Some(1)),
+
+            Some((name, value)) => (name, Expr::parse(value).ok().and_then(|expr| expr.value())),
+        };
+
+        match value {
+            None => return Err(format!("Expected number for {}\n\tfound {} instead", name, define)),
+
+            Some(value) => defines.insert(name.to_owned(), value),
+        };
+    }
+
+    let out = args.value("-o").map_or(with_extension(source, "bdc"), str::to_owned);
+    report(py(assemble(source, Some(&out), Some(defines), args.value("-l")))?)
+}
+
+fn link_files(args: &[String]) -> Result<u8, String> {
+    let args = Args::parse(args, &["-o", "-l"], &["-m", "-g", "-s"])?;
+    let first = match args.files.first() {
+        None => return Err("Expected files to link".to_owned()),
+
+        Some(first) => first,
+    };
+
+    let out = args.value("-o").map_or(with_extension(first, "fita"), str::to_owned);
+    let map = args.flag("-m").then(|| with_extension(&out, "map"));
+
+    report(py(link(
+        args.files.iter().map(|file| file.as_str()).collect(),
+        Some(&out),
+        map.as_deref(),
+        Some(args.flag("-g")),
+        args.value("-l"),
+        Some(args.flag("-s")),
+    ))?)
+}
+
+// Text when PRINT points at printable UTF-8, otherwise the number its bytes make
+fn print(bytes: &[u8]) {
+    let text = &bytes[..bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len())];
+
+    match std::str::from_utf8(text) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => println!("{}", text),
+        _ => println!("{}", text.iter().rev().fold(0u128, |number, byte| number << 8 | u128::from(*byte))),
+    }
+}
+
+fn read(input: &mut impl BufRead) -> Result<u32, String> {
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Err(why) => Err(format!("Read error: {}", why)),
+
+        Ok(0) => Err("Input ended while the program waits for READ".to_owned()),
+
+        Ok(_) => match line.trim().parse::<i64>() {
+            Ok(number) if (-(1 << 31)..1 << 32).contains(&number) => Ok(number as u32),
+            _ => Err(format!("Expected number for READ\n\tfound {} instead", line.trim())),
+        },
+    }
+}
+
+fn run(args: &[String]) -> Result<u8, String> {
+    let args = Args::parse(args, &["--max-steps"], &["--trace"])?;
+    let executable = read_executable(args.file()?)?;
+
+    let max_steps = match args.value("--max-steps").map(str::parse::<u64>) {
+        None => None,
+
+        Some(Err(_)) => return Err(format!("Expected number after --max-steps\n\tfound {} instead", args.value("--max-steps").unwrap_or_default())),
+
+        Some(Ok(steps)) => Some(steps),
+    };
+    let trace = args.flag("--trace");
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut steps = 0;
+
+    py(executable.run(true))?;
+    loop {
+        match unsafe { py(get_state())? } {
+            CPUState::IDLE => break,
+
+            CPUState::OUTPUT => {
+                print(&unsafe { py(get_print())? });
+                continue;
+            }
+
+            CPUState::INPUT => {
+                io::stdout().flush().map_err(|why| why.to_string())?;
+                unsafe { py(feed_read(read(&mut input)?))? };
+                continue;
+            }
+
+            _ => (),
+        }
+
+        let pc = unsafe { py(get_pc())? };
+        if max_steps == Some(steps) {
+            eprintln!("Stopped after {} steps at {:05X}", steps, pc);
+            return Ok(OUT_OF_STEPS);
+        }
+
+        if trace {
+            let word = unsafe { py(read_memory(pc))? };
+            let instr = Instruction::decode(word, None, 0).map_or("?".to_owned(), |instr| instr.to_string());
+            eprintln!("{:05X}  {:08X}  {:24}  ACC {:08X}", pc, word, instr, unsafe { py(get_acc())? });
+        }
+
+        unsafe { py(cycle())? };
+        steps += 1;
+    }
+
+    Ok(unsafe { py(get_acc())? } as u8)
+}
+
+// Operands are shown as numbers with the label at that address, if any, after them
+fn disassemble(executable: &Executable, name: &str) -> String {
+    let label = |address: u32| executable.symbols.iter().filter(|(_, other)| *other == address).map(|(label, _)| label.as_str()).collect::<Vec<&str>>();
+    let mut buf = format!("{}: entry {:05X}\n", name, executable.entry);
+
+    buf.push_str("DATA\n");
+    for (address, word) in (executable.data_address..).zip(&executable.data) {
+        for label in label(address) {
+            buf.push_str(format!("{}:\n", label).as_str());
+        }
+        buf.push_str(format!("  {:05X}  {:08X}\n", address, word).as_str());
+    }
+
+    buf.push_str("CODE\n");
+    for (address, word) in (executable.code_address..).zip(&executable.code) {
+        for label in label(address) {
+            buf.push_str(format!("{}:\n", label).as_str());
+        }
+
+        let (instr, operand) = match Instruction::decode(*word, None, 0) {
+            Err(_) => ("?".to_owned(), None),
+
+            Ok(instr) => (instr.to_string(), instr.expects().and(instr.operand.as_ref()).and_then(|operand| operand.value())),
+        };
+
+        match operand.map(label).filter(|labels| !labels.is_empty()) {
+            None => buf.push_str(format!("  {:05X}  {:08X}  {}\n", address, word, instr).as_str()),
+
+            Some(labels) => buf.push_str(format!("  {:05X}  {:08X}  {:24}  // {}\n", address, word, instr, labels.join(", ")).as_str()),
+        }
+    }
+
+    buf
+}
+
+fn dis(args: &[String]) -> Result<u8, String> {
+    let args = Args::parse(args, &[], &[])?;
+    let file = args.file()?;
+
+    match file.ends_with(".fita") {
+        true => {
+            print!("{}", disassemble(&read_executable(file)?, file));
+            Ok(0)
+        }
+
+        false => report(py(objdump(file, None))?),
+    }
+}
+
+fn words(buf: &mut String, start: u32, words: &[u32]) {
+    for (address, line) in (start..).step_by(8).zip(words.chunks(8)) {
+        let line: Vec<String> = line.iter().map(|word| format!("{:08X}", word)).collect();
+        buf.push_str(format!("  {:05X}  {}\n", address, line.join(" ")).as_str());
+    }
+}
+
+fn dump(args: &[String]) -> Result<u8, String> {
+    let args = Args::parse(args, &[], &[])?;
+    let file = args.file()?;
+    let executable = read_executable(file)?;
+
+    let mut buf = format!(
+        "{}: entry {:05X}, data {:05X}+{}, code {:05X}+{}, {}\n",
+        file,
+        executable.entry,
+        executable.data_address,
+        executable.data.len(),
+        executable.code_address,
+        executable.code.len(),
+        match &executable.relocations {
+            None => "absolute".to_owned(),
+            Some(relocations) => format!("{} relocations", relocations.len()),
+        }
+    );
+
+    buf.push_str("DATA\n");
+    words(&mut buf, executable.data_address, &executable.data);
+    buf.push_str("CODE\n");
+    words(&mut buf, executable.code_address, &executable.code);
+
+    for overlay in &executable.overlays {
+        buf.push_str(format!("OVERLAY {}\n", overlay.name).as_str());
+        words(&mut buf, overlay.address, &overlay.code);
+    }
+    for (label, address) in &executable.exports {
+        buf.push_str(format!("EXPORT {} {:05X}\n", label, address).as_str());
+    }
+    for (shared, label, slot) in &executable.imports {
+        buf.push_str(format!("IMPORT {} {} {:05X}\n", shared, label, slot).as_str());
+    }
+
+    print!("{}", buf);
+    Ok(0)
+}
+
+fn main() -> ExitCode {
+    // The processor reports some errors as Python exceptions, which need an interpreter to print
+    pyo3::prepare_freethreaded_python();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(|command| command.as_str()) {
+        Some("asm") => asm(&args[1..]),
+        Some("link") => link_files(&args[1..]),
+        Some("run") => run(&args[1..]),
+        Some("dis") => dis(&args[1..]),
+        Some("dump") => dump(&args[1..]),
+
+        Some("-h" | "--help" | "help") => {
+            println!("{}", USAGE);
+            Ok(0)
+        }
+
+        Some(command) => Err(format!("Unknown command {}\n{}", command, USAGE)),
+
+        None => Err(USAGE.to_owned()),
+    };
+
+    match result {
+        Err(why) => {
+            eprintln!("{}", why);
+            ExitCode::FAILURE
+        }
+
+        Ok(status) => ExitCode::from(status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sisprog::processor::{assembler::assemble_module, layout::Layout, linker::link_objects};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn options_take_their_values_and_the_rest_are_files() {
+        let parsed = Args::parse(&args(&["a.bdc", "-o", "x.fita", "-m", "b.bdc", "-o", "y.fita"]), &["-o"], &["-m"]).unwrap();
+        assert_eq!(parsed.files, ["a.bdc", "b.bdc"]);
+        assert_eq!(parsed.value("-o"), Some("y.fita"));
+        assert!(parsed.flag("-m") && !parsed.flag("-g"));
+        assert_eq!(parsed.file(), Err("Unexpected argument b.bdc".to_owned()));
+
+        let parsed = Args::parse(&args(&["a.qck"]), &["-o"], &["-m"]).unwrap();
+        assert_eq!(parsed.file(), Ok("a.qck"));
+        assert_eq!(parsed.value("-o"), None);
+        assert_eq!(Args::parse(&[], &[], &[]).unwrap().file(), Err("Expected a file".to_owned()));
+
+        let parse_error = |arguments: &[&str]| Args::parse(&args(arguments), &["-o"], &["-m"]).err();
+        assert_eq!(parse_error(&["a.bdc", "-o"]).as_deref(), Some("Expected value after -o"));
+        assert_eq!(parse_error(&["-x", "a.bdc"]).as_deref(), Some("Unknown option -x"));
+        assert_eq!(with_extension("dir/a.qck", "bdc"), "dir/a.bdc");
+    }
+
+    #[test]
+    fn read_takes_one_number_per_line() {
+        let mut input = "12\n -1 \n4294967295\n4294967296\nx\n".as_bytes();
+        assert_eq!(read(&mut input), Ok(12));
+        assert_eq!(read(&mut input), Ok(u32::MAX));
+        assert_eq!(read(&mut input), Ok(u32::MAX));
+        assert_eq!(read(&mut input), Err("Expected number for READ\n\tfound 4294967296 instead".to_owned()));
+        assert_eq!(read(&mut input), Err("Expected number for READ\n\tfound x instead".to_owned()));
+        assert_eq!(read(&mut input), Err("Input ended while the program waits for READ".to_owned()));
+    }
+
+    #[test]
+    fn disassembly_names_the_labels_operands_point_at() {
+        let module = assemble_module("n: .word 5\nBEGIN main\nmain: LDA n\n JMP main\n HALT\nEND\n", HashMap::new()).unwrap();
+        let executable = link_objects(&[&module], &Layout::default()).unwrap();
+
+        assert_eq!(
+            disassemble(&executable, "main.fita"),
+            "main.fita: entry 00000
+DATA
+n:
+  10000  00000005
+CODE
+main:
+  00000  00050000  LDA 65536                 // n
+  00001  004C0000  JMP 0                     // main
+  00002  00000000  IRQ 0
+"
+        );
+    }
+}
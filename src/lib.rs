@@ -6,93 +6,76 @@ extern crate strum_macros;
 pub mod processor;
 
 use processor::{
-    assembler::{assemble, OpCodes},
+    assembler::{assemble, assemble_source, OpCodes},
     cpu::{CPUState, cycle, read_memory, write_many, write_memory, get_acc, get_c, get_la, get_n, get_p, get_pc, get_print, feed_read, get_saved_reg, get_sp, get_state,  get_v, get_z, execute},
-    linker::link,
+    executable::Executable,
+    library::archive,
+    linker::{link, link_modules},
+    loader::Loader,
+    object::ObjectModule,
+    objfile::objdump,
+    project::build,
+    tape::{get_tape, mount_tape, unmount_tape},
+    xref::xref,
 };
 use std::fs;
 
+fn read_executable(fita: &str) -> Result<Executable, String> {
+    match fs::read(fita) {
+        Ok(bin) => Executable::from_bytes(&bin, fita),
+        Err(why) => Err(format!("Read error: {}", why)),
+    }
+}
+
 #[pyfunction]
 fn print_debug(fita: &str) -> PyResult<()> {
-    match fs::read(fita) {
-        Ok(bin) => {
-            let mut chunks = bin.chunks_exact(4);
-            match chunks.next() {
-                Some(first_line) => {
-                    let n_data = u32::from_le_bytes([
-                        first_line[0],
-                        first_line[1],
-                        first_line[2],
-                        first_line[3],
-                    ]);
-                    chunks.by_ref().take(n_data as usize).for_each(|chunk| {
-                        let data = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
-                        println!("{:032X}", data);
-                    });
-                    while let Some(chunk) = chunks.next() {
-                        let line = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
-                        let instr = line >> 18;
-                        println!(
-                            "{:?}  {:018b}",
-                            OpCodes::from_repr(instr as u16).unwrap(),
-                            line % (1 << 18)
-                        );
-                    }
+    match read_executable(fita) {
+        Ok(executable) => {
+            println!(
+                "entry {:05X}, data {:05X}+{}, code {:05X}+{}",
+                executable.entry,
+                executable.data_address,
+                executable.data.len(),
+                executable.code_address,
+                executable.code.len()
+            );
+            executable.data.iter().for_each(|data| println!("{:032X}", data));
+            for line in &executable.code {
+                let instr = line >> 18;
+                match OpCodes::from_repr(instr as u16) {
+                    Some(op) => println!("{:?}  {:018b}", op, line % (1 << 18)),
+                    None => println!("{:08X}", line),
                 }
-                None => println!("ex.fita is empty"),
+            }
+            for (name, address) in &executable.symbols {
+                println!("{:05X} {}", address, name);
             }
         }
-        Err(why) => println!("Read error: {}", why.to_string()),
+        Err(why) => println!("{}", why),
     }
     Ok(())
 }
 
 #[pyfunction]
 fn parse_binary(bin_file: &str) -> PyResult<(u32, u32, Vec<u32>, Vec<u32>)> {
-    let mut n_data = 0;
-    let mut n_instr = 0;
-    let mut data: Vec<u32> = Vec::new();
-    let mut instr: Vec<u32> = Vec::new();
-
-    match fs::read(bin_file) {
-        Ok(bin) => {
-            let mut chunks = bin.chunks_exact(4);
-
-            match chunks.next() {
-                Some(first_line) => {
-                    n_data = u32::from_le_bytes([
-                        first_line[0],
-                        first_line[1],
-                        first_line[2],
-                        first_line[3],
-                    ]);
-
-                    chunks.by_ref().take(n_data as usize).for_each(|chunk| {
-                        data.push(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
-                    });
-
-                    while let Some(chunk) = chunks.next() {
-                        n_instr += 1;
-                        instr.push(u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
-                    }
-                }
-
-                None => println!("Trying to parse empty binary file."),
-            }
-        }
-
-        Err(why) => println!("Read error: {}", why.to_string()),
+    match read_executable(bin_file) {
+        Ok(executable) => Ok((executable.data.len() as u32, executable.code.len() as u32, executable.data, executable.code)),
+        Err(why) => Err(PyValueError::new_err(why)),
     }
-
-    Ok((n_data, n_instr, data, instr))
 }
 
-use pyo3::prelude::*;
+use pyo3::{exceptions::PyValueError, prelude::*};
 
 #[pymodule]
 fn sisprog(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(assemble, m)?)?;
+    m.add_function(wrap_pyfunction!(assemble_source, m)?)?;
+    m.add_function(wrap_pyfunction!(archive, m)?)?;
     m.add_function(wrap_pyfunction!(link, m)?)?;
+    m.add_function(wrap_pyfunction!(link_modules, m)?)?;
+    m.add_function(wrap_pyfunction!(build, m)?)?;
+    m.add_function(wrap_pyfunction!(objdump, m)?)?;
+    m.add_function(wrap_pyfunction!(xref, m)?)?;
     m.add_function(wrap_pyfunction!(print_debug, m)?)?;
     m.add_function(wrap_pyfunction!(parse_binary, m)?)?;
     m.add_function(wrap_pyfunction!(cycle, m)?)?;
@@ -113,6 +96,12 @@ fn sisprog(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(get_v, m)?)?;
     m.add_function(wrap_pyfunction!(get_z, m)?)?;
     m.add_function(wrap_pyfunction!(execute, m)?)?;
+    m.add_function(wrap_pyfunction!(mount_tape, m)?)?;
+    m.add_function(wrap_pyfunction!(unmount_tape, m)?)?;
+    m.add_function(wrap_pyfunction!(get_tape, m)?)?;
     m.add_class::<CPUState>()?;
+    m.add_class::<ObjectModule>()?;
+    m.add_class::<Executable>()?;
+    m.add_class::<Loader>()?;
     Ok(())
 }
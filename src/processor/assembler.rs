@@ -1,8 +1,15 @@
-use pyo3::prelude::*;
-use std::{fs, str::FromStr};
+use super::{
+    expression::{is_anonymous_reference, parse_string, strip_comment, BinaryOp, Expr},
+    layout::{CODE_PAGE, DATA_PAGE},
+    listing::listing,
+    objfile::ObjectFile,
+    object::{CodeSection, DataEntry, DataSection, DataValue, Instruction, ObjectModule, SymbolKind},
+};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::{collections::HashMap, fs, str::FromStr};
 
 #[repr(u16)]
-#[derive(EnumString, FromRepr, Debug)]
+#[derive(EnumString, FromRepr, Debug, Clone, Copy, PartialEq)]
 pub enum OpCodes {
     IRQ, //0
     LDA, //1
@@ -52,364 +59,845 @@ pub enum PseudoOps {
     EXTERN,
 }
 
-#[pyfunction]
-pub fn assemble(in_asm: &str, breadcrumb: Option<&str>) -> PyResult<(bool, String)> {
-    let s = match fs::read_to_string(in_asm) {
-        Ok(s) => s,
-        Err(why) => return Ok((false, why.to_string())),
+fn constant_value(expr: &str, constants: &HashMap<String, u32>, i: usize) -> Result<u32, String> {
+    match Expr::parse(expr).and_then(|expr| expr.evaluate(&|name| constants.get(name).copied())) {
+        Err(why) => Err(format!("{} at line {}\n\t{}", why, i + 1, expr.trim())),
+
+        Ok(v) => Ok(v),
+    }
+}
+
+fn section_offset(section: &str, offset: usize) -> Expr {
+    Expr::Binary(BinaryOp::Add, Box::new(Expr::Symbol(section.to_owned())), Box::new(Expr::Number(offset as u32)))
+}
+
+// Index of the section lines are currently assembled into
+#[derive(Clone, Copy)]
+enum Section {
+    Data(usize),
+    Code(usize),
+}
+
+// Where the next word of a section goes, known right away only for .org sections
+fn data_position(section: &DataSection) -> Expr {
+    match section.origin {
+        None => section_offset(".data", section.size()),
+        Some(origin) => Expr::Number(DATA_PAGE | (origin + section.size() as u32)),
+    }
+}
+
+fn code_position(section: &CodeSection) -> Expr {
+    match section.origin {
+        None => section_offset(".code", section.instructions.len()),
+        Some(origin) => Expr::Number(CODE_PAGE | (origin + section.instructions.len() as u32)),
+    }
+}
+
+// Local and anonymous labels never leave the assembler. References to them are renamed
+// to a key holding a space, which can't clash with any symbol written in the source,
+// and later replaced by an offset from the module's own section.
+fn rename_locals(expr: Expr, scope: &Option<String>, anonymous: &HashMap<String, usize>, i: usize) -> Result<Expr, String> {
+    expr.map_symbols(&mut |name| {
+        if name == ".code" || name == ".data" {
+            Ok(Expr::Symbol(name.to_owned()))
+        } else if name.starts_with('.') {
+            match scope {
+                None => Err(format!("Local label {} used before any global label at line {}", name, i + 1)),
+
+                Some(scope) => Ok(Expr::Symbol(format!("{} {}", scope, name))),
+            }
+        } else if is_anonymous_reference(name) {
+            let (number, direction) = name.split_at(name.len() - 1);
+            let defined = anonymous.get(number).copied().unwrap_or(0);
+            match (direction, defined) {
+                ("b", 0) => Err(format!("No previous anonymous label {} for {} at line {}", number, name, i + 1)),
+                ("b", n) => Ok(Expr::Symbol(format!("{} {}", number, n - 1))),
+                (_, n) => Ok(Expr::Symbol(format!("{} {}", number, n))),
+            }
+        } else {
+            Ok(Expr::Symbol(name.to_owned()))
+        }
+    })
+}
+
+fn replace_locals(expr: &Expr, locals: &HashMap<String, (Expr, SymbolKind)>, i: usize) -> Result<Expr, String> {
+    expr.map_symbols(&mut |name| match name.split_once(' ') {
+        None => Ok(Expr::Symbol(name.to_owned())),
+
+        Some((scope, local)) => match locals.get(name) {
+            Some((position, _)) => Ok(position.clone()),
+
+            None if local.starts_with('.') => Err(format!("Local label {} not defined in {} at line {}", local, scope, i + 1)),
+
+            None => Err(format!("No following anonymous label {} for {}f at line {}", scope, scope, i + 1)),
+        },
+    })
+}
+
+// Operands have to point at the kind of label their instruction expects. Constants and local
+// labels are folded into plain numbers, so they are checked before that; the labels of other
+// modules are left to the linker.
+fn check_operand(module: &ObjectModule, instr: &Instruction, locals: &HashMap<String, (Expr, SymbolKind)>, constants: &HashMap<String, u32>) -> Result<(), String> {
+    let (expected, operand) = match (instr.expects(), &instr.operand) {
+        (Some(expected), Some(operand)) => (expected, operand),
+        _ => return Ok(()),
     };
 
-    let mut buf = String::with_capacity(s.len());
-    buf.push('\n');
+    let symbols = operand.symbols();
+    let kinds: Vec<(&str, SymbolKind)> = symbols
+        .iter()
+        .filter_map(|name| match *name {
+            ".code" => Some((*name, SymbolKind::Code)),
+            ".data" => Some((*name, SymbolKind::Data)),
+            _ if constants.contains_key(*name) => Some((*name, SymbolKind::Constant)),
+            _ => match locals.get(*name) {
+                Some((_, kind)) => Some((*name, *kind)),
+                None => module.symbol_kind(name).map(|kind| (*name, kind)),
+            },
+        })
+        .collect();
+
+    // Numbers and constants are fixed addresses and go anywhere, undefined labels are reported later
+    let labels: Vec<&(&str, SymbolKind)> = kinds.iter().filter(|(_, kind)| *kind != SymbolKind::Constant).collect();
+    let (name, kind) = match labels.first() {
+        Some(label) if kinds.len() == symbols.len() && labels.iter().all(|(_, kind)| *kind != expected && *kind != SymbolKind::Extern) => label,
+
+        _ => return Ok(()),
+    };
+
+    let found = match name.split_once(' ') {
+        None => format!("{} label {}", kind.name(), name),
+        Some((_, local)) if local.starts_with('.') => format!("{} label {}", kind.name(), local),
+        Some((number, _)) => format!("anonymous {} label {}", kind.name(), number),
+    };
+
+    Err(format!("{} at line {} expects a {} label\n\tfound {}", instr.mnemonic(), instr.line + 1, expected.name(), found))
+}
+
+// Records the label given to .entry or BEGIN
+fn set_entry(entry: &mut Option<(String, usize)>, name: &str, i: usize) -> Result<(), String> {
+    if name.starts_with('.') || name.chars().all(|c| c.is_ascii_digit()) || name.chars().any(|c| c.is_whitespace()) {
+        return Err(format!("Only global labels can be entry points at line {}\n\t{}", i + 1, name));
+    }
+
+    if let Some((_, first)) = entry {
+        return Err(format!("Found repeated entry point at line {}\n\tfirst declared at line {}", i + 1, *first + 1));
+    }
+
+    *entry = Some((name.to_owned(), i));
+    Ok(())
+}
+
+pub fn assemble_module(s: &str, defines: HashMap<String, u32>) -> Result<ObjectModule, String> {
+    // The first section of each kind is the relocatable one, .org opens new ones
+    let mut module = ObjectModule {
+        data: vec![DataSection::default()],
+        code: vec![CodeSection::default()],
+        ..Default::default()
+    };
+    let mut section = Section::Data(0);
 
     let mut began = false;
     let mut ended = false;
 
-    let mut header_len: u32 = 1;
-    let mut offset = 0;
+    let mut constants = defines;
+
+    let mut scope: Option<String> = None;
+    let mut locals: HashMap<String, (Expr, SymbolKind)> = HashMap::new();
+    let mut anonymous: HashMap<String, usize> = HashMap::new();
+    let mut exports: Vec<(String, usize)> = Vec::new();
+    let mut entry: Option<(String, usize)> = None;
+    // One entry per open .if block: (branch taken, .else seen, line of the .if)
+    let mut conditionals: Vec<(bool, bool, usize)> = Vec::new();
+
+    for (i, line) in s.lines().enumerate() {
+        let line = strip_comment(line, "//").trim();
+
+        let active = conditionals.iter().all(|(taken, _, _)| *taken);
+
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some(".if") => {
+                let taken = active && constant_value(line.trim_start_matches(".if"), &constants, i)? != 0;
+                conditionals.push((taken, false, i));
+                continue;
+            }
+            Some(directive @ (".ifdef" | ".ifndef")) => {
+                let name = match tokens.next() {
+                    None => return Err(format!("Expected constant name after {} at line {}", directive, i + 1)),
+
+                    Some(name) => name,
+                };
+                if let Some(token) = tokens.next() {
+                    return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, token));
+                }
+
+                conditionals.push((constants.contains_key(name) == (directive == ".ifdef"), false, i));
+                continue;
+            }
+            Some(".else") => match conditionals.last_mut() {
+                None => return Err(format!("Found .else without .if at line {}", i + 1)),
+
+                Some((_, true, _)) => return Err(format!("Found repeated .else at line {}", i + 1)),
+
+                Some((taken, else_seen, _)) => {
+                    *taken = !*taken;
+                    *else_seen = true;
+                    continue;
+                }
+            }
+            Some(".endif") => match conditionals.pop() {
+                None => return Err(format!("Found .endif without .if at line {}", i + 1)),
+
+                Some(_) => continue,
+            }
+            _ => (),
+        }
+
+        if !active || line.is_empty() {
+            continue;
+        }
+
+        if ended {
+            return Err("File continues after END".to_owned());
+        }
 
-    match s.lines().enumerate().try_for_each(|(i, line)| {
-        let line = match line.split_once("//") {
-            Some((code, _comment)) => code.trim(),
-            None => line.trim()
+        let (label, text) = match line.split_once(':') {
+            Some((label, text)) if !label.contains('"') && label.split_whitespace().next() != Some(".text") => (Some(label.trim_end()), text.trim_start()),
+            _ => (None, line),
         };
 
-        if !line.is_empty() {
-            if ended {
-                return Err("File continues after END".to_owned());
-            } else if let Some((label, text)) = line.split_once(".text") {
-                if began {
-                    return Err(format!("Found .text directive after BEGIN statement at line {}", i + 1));
+        if let Some(label) = label {
+            if label.is_empty() {
+                return Err(format!("Expected label before ':' at line {}", i + 1));
+            }
+
+            if label.chars().any(|c| c.is_whitespace()) {
+                return Err(format!("Found whitespace in label at line {}\n\t{}", i + 1, label));
+            }
+
+            if label == ".code" || label == ".data" {
+                return Err(format!("Reserved section name used as label at line {}\n\t{}", i + 1, label));
+            }
+        }
+
+        // Local and anonymous labels get their key here and their position once the line is placed
+        let local = match label {
+            None => None,
+
+            Some(label) if label.starts_with('.') => match &scope {
+                None => return Err(format!("Local label {} defined before any global label at line {}", label, i + 1)),
+
+                Some(scope) => {
+                    let key = format!("{} {}", scope, label);
+                    if locals.contains_key(&key) {
+                        return Err(format!("Found label redefinition at line {}\n\t{}", i + 1, label));
+                    }
+                    Some(key)
                 }
+            },
 
-                let label = label.trim_end();
-                match label.strip_suffix(':') {
-                    None => return Err(format!("Expected label before .text directive at line {}", i + 1)),
+            Some(label) if label.chars().all(|c| c.is_ascii_digit()) => {
+                let count = anonymous.entry(label.to_owned()).or_insert(0);
+                *count += 1;
+                Some(format!("{} {}", label, *count - 1))
+            }
 
-                    Some(label) => match label.trim_end().chars().any(|c| c.is_whitespace()) {
-                        true => return Err(format!("Found whitespace in label at line {}\n\t{}", i + 1, label)),
+            Some(label) => {
+                if module.defines(label) || module.externs.iter().any(|e| e == label) || constants.contains_key(label) {
+                    return Err(format!("Found label redefinition at line {}\n\t{}", i + 1, label));
+                }
 
-                        false => if buf.lines().take(match usize::try_from(header_len) {
-                            Err(_) => return Err("16 bit architecture unsupported".to_owned()),
+                scope = Some(label.to_owned());
+                None
+            }
+        };
 
-                            Ok(v) => v,
-                        }).any(|line| line.starts_with(label)) {
-                            return Err(format!("Found label redefinition at line {}\n\t{}", i + 1, label));
-                        }
+
+        let (token, args) = match text.split_once(char::is_whitespace) {
+            Some((token, args)) => (token, args.trim()),
+            None => (text, ""),
+        };
+
+        match token {
+            ".data" | ".code" => {
+                if label.is_some() {
+                    return Err(format!("Unexpected label before {} directive at line {}", token, i + 1));
+                }
+
+                if !args.is_empty() {
+                    return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, args));
+                }
+
+                section = match token {
+                    ".data" => Section::Data(0),
+                    _ => Section::Code(0),
+                };
+            }
+            ".org" => {
+                if label.is_some() {
+                    return Err(format!("Unexpected label before .org directive at line {}", i + 1));
+                }
+
+                if args.is_empty() {
+                    return Err(format!("Expected address after .org directive at line {}", i + 1));
+                }
+
+                let origin = constant_value(args, &constants, i)?;
+                if origin.leading_zeros() < 16 {
+                    return Err(format!("Address out of range at line {}\n\t{}", i + 1, origin));
+                }
+
+                section = match section {
+                    Section::Data(_) => {
+                        module.data.push(DataSection {
+                            origin: Some(origin),
+                            ..Default::default()
+                        });
+                        Section::Data(module.data.len() - 1)
                     }
+                    Section::Code(_) => {
+                        module.code.push(CodeSection {
+                            origin: Some(origin),
+                            ..Default::default()
+                        });
+                        Section::Code(module.code.len() - 1)
+                    }
+                };
+            }
+            ".equ" => {
+                if label.is_some() {
+                    return Err(format!("Unexpected label before .equ directive at line {}", i + 1));
                 }
 
-                let text = match text.strip_prefix(|c: char| c.is_whitespace()) {
-                    None => return Err(format!("Expected whitespace after .text directive at line {}", i + 1)),
+                let (name, value) = match args.split_once(',') {
+                    None => return Err(format!("Expected constant name and value after .equ at line {}", i + 1)),
 
-                    Some(text) => text,
+                    Some((name, value)) => (name.trim(), value),
                 };
 
-                buf.extend(label.chars().filter(|c| !c.is_whitespace()));
-                buf.push_str(text);
-                buf.push_str("\"\n");
-                header_len += 1;
-            } else if let Some((label, words)) = line.split_once(".word") {
-                if began {
-                    return Err(format!("Found .word directive after BEGIN statement at line {}", i + 1));
+                if name.is_empty() || name.chars().any(|c| c.is_whitespace()) {
+                    return Err(format!("Invalid constant name at line {}\n\t{}", i + 1, name));
+                }
+
+                if module.defines(name) || module.externs.iter().any(|e| e == name) {
+                    return Err(format!("Found label redefinition at line {}\n\t{}", i + 1, name));
                 }
 
-                let label = label.trim_end();
-                match label.strip_suffix(':') {
-                    None => return Err(format!("Expected label before .word directive at line {}", i + 1)),
+                let value = constant_value(value, &constants, i)?;
+                if constants.insert(name.to_owned(), value).is_some() {
+                    return Err(format!("Found constant redefinition at line {}\n\t{}", i + 1, name));
+                }
+            }
+            ".entry" => {
+                if label.is_some() {
+                    return Err(format!("Unexpected label before .entry directive at line {}", i + 1));
+                }
 
-                    Some(label) => match label.trim_end().chars().any(|c| c.is_whitespace()) {
-                        true => return Err(format!("Found whitespace in label at line {}\n\t{}", i + 1, label)),
+                if args.is_empty() {
+                    return Err(format!("Expected label after .entry directive at line {}", i + 1));
+                }
 
-                        false => if buf.lines().take(match usize::try_from(header_len) {
-                            Err(_) => return Err("16 bit architecture unsupported".to_owned()),
+                set_entry(&mut entry, args, i)?;
+            }
+            ".global" | ".export" | ".weak" => {
+                if label.is_some() {
+                    return Err(format!("Unexpected label before {} directive at line {}", token, i + 1));
+                }
 
-                            Ok(v) => v,
-                        }).any(|line| line.starts_with(label)) {
-                            return Err(format!("Found label redefinition at line {}\n\t{}", i + 1, label));
-                        }
+                if args.is_empty() {
+                    return Err(format!("Expected label after {} directive at line {}", token, i + 1));
+                }
+
+                for name in args.split(',').map(|name| name.trim()) {
+                    if name.is_empty() || name.starts_with('.') || name.chars().all(|c| c.is_ascii_digit()) || name.chars().any(|c| c.is_whitespace()) {
+                        return Err(format!("Only global labels can be exported at line {}\n\t{}", i + 1, name));
+                    }
+
+                    if !exports.iter().any(|(e, _)| e == name) {
+                        exports.push((name.to_owned(), i));
+                    }
+
+                    if token == ".weak" && !module.is_weak(name) {
+                        module.weak.push(name.to_owned());
                     }
                 }
+            }
+            ".text" => {
+                let index = match section {
+                    Section::Code(_) => return Err(format!("Found .text directive in code section at line {}", i + 1)),
+
+                    Section::Data(index) => index,
+                };
+
+                let text = match text[".text".len()..].strip_prefix(|c: char| c.is_whitespace()) {
+                    None => return Err(format!("Expected whitespace after .text directive at line {}", i + 1)),
+
+                    Some(text) => text,
+                };
+
+                let bytes = match text.trim_start().starts_with('"') {
+                    false => text.as_bytes().to_vec(),
 
-                match words.starts_with(|c: char| c.is_whitespace()) {
-                    false => return Err(format!("Expected whitespace after .word directive at line {}", i + 1)),
+                    true => match parse_string(text.trim_start()) {
+                        Err(why) => return Err(format!("{} at line {}", why, i + 1)),
 
-                    true => words.split(',').try_for_each(|word| match word.trim().parse::<u32>() {
-                        Err(_) => Err(format!("Couldn't parse word at line {}\n\t{}", i + 1, word)),
-                        Ok(_) => Ok(()),
-                    })?,
+                        Ok((_, rest)) if !rest.trim().is_empty() => return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, rest.trim())),
+
+                        Ok((bytes, _)) => bytes,
+                    },
+                };
+
+                if let Some(key) = &local {
+                    locals.insert(key.clone(), (data_position(&module.data[index]), SymbolKind::Data));
                 }
 
-                buf.extend(label.chars().chain(words.chars()).filter(|c| !c.is_whitespace()));
-                buf.push('\n');
-                header_len += 1;
-            } else if let Some((label, text)) = line.split_once(':') {
-                if !began {
-                    return Err(format!("Expected directive after label at line {}", i + 1));
+                module.data[index].entries.push(DataEntry {
+                    label: match (label, local) {
+                        (Some(label), None) => label.to_owned(),
+                        _ => String::new(),
+                    },
+                    value: DataValue::Text(bytes),
+                    line: i,
+                });
+            }
+            ".word" => {
+                let index = match section {
+                    Section::Code(_) => return Err(format!("Found .word directive in code section at line {}", i + 1)),
+
+                    Section::Data(index) => index,
+                };
+
+                if args.is_empty() {
+                    return Err(format!("Expected whitespace after .word directive at line {}", i + 1));
                 }
 
-                let label = label.trim_end();
-                match label.chars().any(|c| c.is_whitespace()) {
-                    true => return Err(format!("Found whitespace in label at line {}\n\t{}", i + 1, label)),
+                let words = args.split(',').map(|word| match Expr::parse(word) {
+                    Err(why) => Err(format!("{} at line {}\n\t{}", why, i + 1, word.trim())),
 
-                    false => if buf.lines().take(match usize::try_from(header_len) {
-                        Err(_) => return Err("16 bit architecture unsupported".to_owned()),
+                    Ok(expr) => rename_locals(expr, &scope, &anonymous, i),
+                }).collect::<Result<Vec<_>, _>>()?;
 
-                        Ok(v) => v,
-                    }).any(|line| line.starts_with(label)) {
-                        return Err(format!("Label {} found at line {} previously defined", label, i + 1));
-                    }
+                if let Some(key) = &local {
+                    locals.insert(key.clone(), (data_position(&module.data[index]), SymbolKind::Data));
                 }
 
-                let string = label.to_owned() + " " + match u32::try_from(i - match usize::try_from(offset) {
-                    Err(_) => return Err("16 bit architecture unsupported".to_owned()),
+                module.data[index].entries.push(DataEntry {
+                    label: match (label, local) {
+                        (Some(label), None) => label.to_owned(),
+                        _ => String::new(),
+                    },
+                    value: DataValue::Words(words),
+                    line: i,
+                });
+            }
+            ".space" | ".zero" | ".fill" => {
+                let index = match section {
+                    Section::Code(_) => return Err(format!("Found {} directive in code section at line {}", token, i + 1)),
 
-                    Ok(v) => v,
-                }) {
-                    Err(_) => return Err("File too big!".to_owned()),
+                    Section::Data(index) => index,
+                };
 
-                    Ok(v) => {
-                        if v.leading_zeros() < 7 {
-                            return Err("File too big!".to_owned());
-                        }
+                if args.is_empty() {
+                    return Err(format!("Expected whitespace after {} directive at line {}", token, i + 1));
+                }
 
-                        v
-                    }
-                }.to_string().as_str() + "\n";
-
-                buf.insert_str(buf.lines().take(match usize::try_from(header_len) {
-                    Err(_) => return Err("16 bit architecture unsupported".to_owned()),
-
-                    Ok(v) => v,
-                }).map(|line| line.bytes().count() + 1).reduce(|acc, n| acc + n).unwrap_or(0), string.as_str());
-                header_len += 1;
-
-                let mut tokens = text.split_whitespace();
-                match tokens.next() {
-                    None => offset += 1,
-                    Some(token) => {
-                        if let Ok(op) = OpCodes::from_str(token) {
-                            match op {
-                                OpCodes::IRQ => match tokens.next() {
-                                    None => return Err(format!("Expected integer at line {}", i + 1)),
-
-                                    Some(irq_type) => match irq_type.parse::<u8>() {
-                                        Err(_) => return Err(format!("Expected integer at line {}\n\tfound {} instead", i + 1, irq_type)),
-
-                                        Ok(v) => match tokens.next() {
-                                            Some(arg) => match v {
-                                                1..=3 => {
-                                                    buf.push_str("IRQ ");
-                                                    buf.push_str(irq_type);
-                                                    buf.push(' ');
-                                                    buf.push_str(arg);
-                                                    buf.push('\n');
-                                                }
-                                                0 | 4 => return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, token)),
-                                                _ => return Err(format!("Unknown IRQ type at line {}\n\t{}", i + 1, irq_type)),
-                                            }
-                                            None => match v {
-                                                0 | 4 => {
-                                                    buf.push_str("IRQ ");
-                                                    buf.push_str(irq_type);
-                                                    buf.push('\n');
-                                                }
-                                                1..=3 => return Err(format!("Expected label at line {}", i + 1)),
-                                                _ => return Err(format!("Unknown IRQ type at line {}\n\t{}", i + 1, irq_type)),
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => match tokens.next() {
-                                    None => return Err(format!("Expected label at line {}", i + 1)),
-
-                                    Some(arg) => {
-                                        buf.push_str(token);
-                                        buf.push(' ');
-                                        buf.push_str(arg);
-                                        buf.push('\n');
-                                    }
-                                }
-                            }
-                        } else {
-                            match PseudoOps::from_str(token) {
-                                Err(_) => return Err(format!("Expected instruction at line {}\t\nfound {} instead", i + 1, token)),
+                let (count, value) = match (token, args.split_once(',')) {
+                    (".fill", Some((count, value))) => (count, match Expr::parse(value) {
+                        Err(why) => return Err(format!("{} at line {}\n\t{}", why, i + 1, value.trim())),
 
-                                Ok(psop) => match psop {
-                                    PseudoOps::EXTERN => return Err(format!("Found EXTERN statement after BEGIN at line {}", i + 1)),
+                        Ok(expr) => rename_locals(expr, &scope, &anonymous, i)?,
+                    }),
+                    (".fill", None) => return Err(format!("Expected count and value after .fill directive at line {}", i + 1)),
+                    _ => (args, Expr::Number(0)),
+                };
 
-                                    PseudoOps::BEGIN => return Err(format!("Found repeated BEGIN statement at line {}", i + 1)),
+                // A negative count wraps around to more words than a page holds
+                let size = match constant_value(count, &constants, i)? {
+                    size if size > 1 << 16 => return Err(format!("Count out of range at line {}\n\t{}", i + 1, count.trim())),
 
-                                    PseudoOps::END => ended = true,
+                    size => size,
+                };
 
-                                    PseudoOps::SET => match tokens.next() {
-                                        None => return Err(format!("Expected label at line {}", i + 1)),
+                if let Some(key) = &local {
+                    locals.insert(key.clone(), (data_position(&module.data[index]), SymbolKind::Data));
+                }
 
-                                        Some(arg) => match u32::from_str_radix(arg, 2) {
-                                            Err(_) => return Err(format!("Expected binary number as argument at line {}\n\tfound {} instead", i + 1, token)),
+                module.data[index].entries.push(DataEntry {
+                    label: match (label, local) {
+                        (Some(label), None) => label.to_owned(),
+                        _ => String::new(),
+                    },
+                    value: DataValue::Fill(size, value),
+                    line: i,
+                });
+            }
+            _ => {
+                if let Some(label) = label {
+                    let code = match section {
+                        Section::Data(_) => return Err(format!("Expected directive after label at line {}", i + 1)),
 
-                                            Ok(_) => {
-                                                buf.push_str("IRQ 3 ");
-                                                buf.push_str(arg);
-                                                buf.push('\n');
-                                            }
-                                        }
-                                    }
-                                    PseudoOps::PRINT | PseudoOps::READ => match tokens.next() {
-                                        None => return Err(format!("Expected label at line {}", i + 1)),
-
-                                        Some(arg) => {
-                                            buf.push_str("IRQ ");
-                                            buf.push_str((psop as u8).to_string().as_str());
-                                            buf.push(' ');
-                                            buf.push_str(arg);
-                                            buf.push('\n');
-                                        }
-                                    }
-                                    _ => {
-                                        buf.push_str("IRQ ");
-                                        buf.push_str((psop as u8).to_string().as_str());
-                                        buf.push('\n');
-                                    }
-                                }
-                            }
+                        Section::Code(index) => &mut module.code[index],
+                    };
+
+                    let location = match u32::try_from(code.instructions.len()) {
+                        Err(_) => return Err("File too big!".to_owned()),
+
+                        Ok(v) => v,
+                    };
+
+                    match &local {
+                        None => code.labels.push((label.to_owned(), location)),
+
+                        Some(key) => {
+                            locals.insert(key.clone(), (code_position(code), SymbolKind::Code));
                         }
                     }
                 }
-                if let Some(token) = tokens.next() {
-                    return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, token));
+
+                if token.is_empty() {
+                    continue;
                 }
-            } else {
-                let mut tokens = line.split_whitespace();
-                if let Some(token) = tokens.next() {
-                    if let Ok(op) = OpCodes::from_str(token) {
-                        if !began {
-                            return Err(format!("Found instruction before BEGIN statement at line {}", i + 1));
-                        }
-                        match op {
-                            OpCodes::IRQ => match tokens.next() {
-                                None => return Err(format!("Expected integer at line {}", i + 1)),
-
-                                Some(irq_type) => match irq_type.parse::<u8>() {
-                                    Err(_) => return Err(format!("Expected integer at line {}\n\tfound {} instead", i + 1, irq_type)),
-
-                                    Ok(v) => match tokens.next() {
-                                        Some(arg) => match v {
-                                            1..=3 => {
-                                                buf.push_str("IRQ ");
-                                                buf.push_str(irq_type);
-                                                buf.push(' ');
-                                                buf.push_str(arg);
-                                                buf.push('\n');
-                                            }
-                                            0 | 4 => return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, token)),
-                                            _ => return Err(format!("Unknown IRQ type at line {}\n\t{}", i + 1, irq_type)),
-                                        }
-                                        None => match v {
-                                            0 | 4 => {
-                                                buf.push_str("IRQ ");
-                                                buf.push_str(irq_type);
-                                                buf.push('\n');
-                                            }
-                                            1..=3 => return Err(format!("Expected label at line {}", i + 1)),
-                                            _ => return Err(format!("Unknown IRQ type at line {}\n\t{}", i + 1, irq_type)),
-                                        }
-                                    }
-                                }
-                            }
-                            _ => match tokens.next() {
-                                None => return Err(format!("Expected label at line {}", i + 1)),
-
-                                Some(arg) => {
-                                    buf.push_str(token);
-                                    buf.push(' ');
-                                    buf.push_str(arg);
-                                    buf.push('\n');
-                                }
-                            }
-                        }
-                    } else {
-                        match PseudoOps::from_str(token) {
-                            Err(_) => return Err(format!("Expected label or instruction at line {}\t\nfound {} instead", i + 1, token)),
 
-                            Ok(psop) => match began {
-                                true => match psop {
-                                    PseudoOps::EXTERN => return Err(format!("Found EXTERN statement after BEGIN at line {}", i + 1)),
+                let mut instr = if OpCodes::from_str(token).is_ok() {
+                    Instruction::parse(token, args, i)?
+                } else {
+                    match PseudoOps::from_str(token) {
+                        Err(_) => return Err(format!("Expected label or instruction at line {}\t\nfound {} instead", i + 1, token)),
 
-                                    PseudoOps::BEGIN => return Err(format!("Found repeated BEGIN statement at line {}", i + 1)),
+                        Ok(psop) => match began {
+                            true => match psop {
+                                PseudoOps::EXTERN => return Err(format!("Found EXTERN statement after BEGIN at line {}", i + 1)),
 
-                                    PseudoOps::END => ended = true,
+                                PseudoOps::BEGIN => return Err(format!("Found repeated BEGIN statement at line {}", i + 1)),
 
-                                    PseudoOps::SET => match tokens.next() {
-                                        None => return Err(format!("Expected label at line {}", i + 1)),
+                                PseudoOps::END => {
+                                    if !args.is_empty() {
+                                        return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, args));
+                                    }
 
-                                        Some(arg) => match u32::from_str_radix(arg, 2) {
-                                            Err(_) => return Err(format!("Expected binary number as argument at line {}\n\tfound {} instead", i + 1, token)),
+                                    ended = true;
+                                    continue;
+                                }
 
-                                            Ok(_) => {
-                                                buf.push_str("IRQ 3 ");
-                                                buf.push_str(arg);
-                                                buf.push('\n');
-                                            }
-                                        }
-                                    }
-                                    PseudoOps::PRINT | PseudoOps::READ => match tokens.next() {
-                                        None => return Err(format!("Expected label at line {}", i + 1)),
-
-                                        Some(arg) => {
-                                            buf.push_str("IRQ ");
-                                            buf.push_str((psop as u8).to_string().as_str());
-                                            buf.push(' ');
-                                            buf.push_str(arg);
-                                            buf.push('\n');
-                                        }
-                                    }
-                                    _ => {
-                                        buf.push_str("IRQ ");
-                                        buf.push_str((psop as u8).to_string().as_str());
-                                        buf.push('\n');
+                                _ => Instruction::parse("IRQ", format!("{} {}", psop as u8, args).as_str(), i)?,
+                            }
+                            false => match psop {
+                                PseudoOps::BEGIN => {
+                                    if !args.is_empty() {
+                                        set_entry(&mut entry, args, i)?;
                                     }
+
+                                    began = true;
+                                    section = Section::Code(0);
+                                    continue;
                                 }
-                                false => match psop {
-                                    PseudoOps::BEGIN => {
-                                        offset = match u32::try_from(i + 1) {
-                                            Err(_) => return Err("File too big!".to_owned()),
-
-                                            Ok(v) => v,
-                                        };
-                                        began = true;
-                                    }
-                                    PseudoOps::EXTERN => match tokens.next() {
+                                PseudoOps::EXTERN => {
+                                    let mut names = args.split_whitespace();
+                                    match names.next() {
                                         None => return Err(format!("Expected label after EXTERN at line {}", i + 1)),
 
-                                        Some(label) => {
-                                            buf.push_str(label);
-                                            buf.push('\n');
-                                            header_len += 1;
+                                        Some(name) => {
+                                            if module.defines(name) || constants.contains_key(name) {
+                                                return Err(format!("Found label redefinition at line {}\n\t{}", i + 1, name));
+                                            }
+
+                                            if !module.externs.iter().any(|e| e == name) {
+                                                module.externs.push(name.to_owned());
+                                            }
                                         }
                                     }
-                                    _ => return Err(format!("Expected BEGIN or EXTERN statement or label at line {}\n\tfound {} instead", i + 1, token))
+
+                                    if let Some(token) = names.next() {
+                                        return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, token));
+                                    }
+                                    continue;
                                 }
+                                PseudoOps::END => return Err(format!("Expected BEGIN or EXTERN statement or label at line {}\n\tfound {} instead", i + 1, token)),
+
+                                _ => match section {
+                                    Section::Code(_) => Instruction::parse("IRQ", format!("{} {}", psop as u8, args).as_str(), i)?,
+
+                                    Section::Data(_) => return Err(format!("Expected BEGIN or EXTERN statement or label at line {}\n\tfound {} instead", i + 1, token)),
+                                },
                             }
                         }
                     }
+                };
+
+                if let Some(operand) = instr.operand.take() {
+                    instr.operand = Some(rename_locals(operand, &scope, &anonymous, i)?);
+                }
+
+                match section {
+                    Section::Data(_) => return Err(format!("Found instruction outside code section at line {}", i + 1)),
+
+                    Section::Code(index) => module.code[index].instructions.push(instr),
                 }
             }
         }
-        Ok(())
-    }) {
-        Ok(()) => (),
-        Err(why) => return Ok((false, why)),
+    }
+
+    if let Some((_, _, i)) = conditionals.last() {
+        return Err(format!("Missing .endif for .if at line {}", i + 1));
     }
 
     if !ended {
-        return Ok((false, "END statement missing".to_owned()));
+        return Err("END statement missing".to_owned());
+    }
+
+    for (name, i) in exports {
+        if !module.defines(&name) {
+            return Err(format!("Exported label {} not defined in module at line {}", name, i + 1));
+        }
+
+        module.exports.push(name);
+    }
+
+    if let Some((name, i)) = entry {
+        if module.entries().any(|entry| entry.label == name) {
+            return Err(format!("Entry label {} is not a code label at line {}", name, i + 1));
+        }
+
+        module.entry = Some(name);
+    }
+
+    // Constants may be used before their .equ and local labels before their definition,
+    // so both are resolved again once the whole file was read
+    for instr in module.instructions() {
+        check_operand(&module, instr, &locals, &constants)?;
+    }
+
+    let lookup = |name: &str| constants.get(name).copied();
+    for entry in module.data.iter_mut().flat_map(|section| section.entries.iter_mut()) {
+        let words = match &mut entry.value {
+            DataValue::Words(words) => words.iter_mut().collect(),
+            DataValue::Fill(_, value) => vec![value],
+            DataValue::Text(_) => Vec::new(),
+        };
+
+        for word in words {
+            *word = match replace_locals(word, &locals, entry.line)?.fold(&lookup) {
+                Err(why) => return Err(format!("{} at line {}", why, entry.line + 1)),
+
+                Ok(expr) => expr,
+            };
+        }
     }
 
-    buf.insert_str(0, (header_len - 1).to_string().as_str());
+    for instr in module.code.iter_mut().flat_map(|section| section.instructions.iter_mut()) {
+        if let Some(operand) = &instr.operand {
+            instr.operand = match replace_locals(operand, &locals, instr.line)?.fold(&lookup) {
+                Err(why) => return Err(format!("{} at line {}", why, instr.line + 1)),
 
-    match fs::write(breadcrumb.unwrap_or("a.bdc"), buf) {
+                Ok(expr) => Some(expr),
+            };
+        }
+    }
+
+    Ok(module)
+}
+
+#[pyfunction]
+pub fn assemble_source(source: &str, defines: Option<HashMap<String, u32>>, name: Option<&str>) -> PyResult<ObjectModule> {
+    match assemble_module(source, defines.unwrap_or_default()) {
+        Err(why) => Err(PyValueError::new_err(why)),
+
+        Ok(mut module) => {
+            module.name = name.unwrap_or("<source>").to_owned();
+            Ok(module)
+        }
+    }
+}
+
+#[pyfunction]
+pub fn assemble(in_asm: &str, breadcrumb: Option<&str>, defines: Option<HashMap<String, u32>>, listing_file: Option<&str>) -> PyResult<(bool, String)> {
+    let s = match fs::read_to_string(in_asm) {
+        Ok(s) => s,
+        Err(why) => return Ok((false, why.to_string())),
+    };
+
+    let module = match assemble_module(&s, defines.unwrap_or_default()) {
+        Ok(mut module) => {
+            module.name = in_asm.to_owned();
+            module
+        }
+        Err(why) => return Ok((false, why)),
+    };
+
+    if let Some(lst) = listing_file {
+        if let Err(why) = fs::write(lst, listing(&s, &module)) {
+            return Ok((false, why.to_string()));
+        }
+    }
+
+    let obj = match ObjectFile::from_module(&module) {
+        Err(why) => return Ok((false, why)),
+
+        Ok(obj) => obj,
+    };
+
+    match fs::write(breadcrumb.unwrap_or("a.bdc"), obj.write()) {
         Ok(_) => Ok((true, "Assembly successful".to_owned())),
         Err(why) => Ok((false, why.to_string())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{
+        executable::Executable,
+        layout::Layout,
+        linker::link_objects,
+        testing::{error, scratch},
+    };
+
+    fn assembly_error(s: &str) -> String {
+        error(assemble_module(s, HashMap::new()))
+    }
+
+    fn link_str(s: &str) -> Executable {
+        let module = assemble_module(s, HashMap::new()).unwrap();
+        link_objects(&[&module], &Layout::default()).unwrap()
+    }
+
+    #[test]
+    fn assemble_writes_the_object_and_its_listing() {
+        let root = scratch("assemble", &[("a.qck", "n: .word 1\nBEGIN\nmain: LDA n\n HALT\nEND\n"), ("b.qck", "BEGIN\n")]);
+        let path = |file: &str| root.join(file).to_string_lossy().into_owned();
+
+        assert_eq!(assemble(&path("a.qck"), Some(&path("a.bdc")), None, Some(&path("a.lst"))).unwrap(), (true, "Assembly successful".to_owned()));
+        assert!(root.join("a.bdc").exists());
+        assert!(fs::read_to_string(path("a.lst")).unwrap().starts_with(" LINE  LOC     WORD      SOURCE\n    1  D 0000  00000001  n: .word 1\n"));
+
+        assert_eq!(assemble(&path("b.qck"), Some(&path("b.bdc")), None, None).unwrap(), (false, "END statement missing".to_owned()));
+        assert!(!root.join("b.bdc").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn conditionals_keep_only_the_taken_branches() {
+        let s = ".equ LEVEL, 2\n.if LEVEL > 1\na: .word 1\n.if LEVEL > 5\nb: .word 2 !!\n.else\nc: .word 3\n.endif\n.else\nd: .word 4\n.endif\n.ifdef EXTRA\ne: .word 5\n.endif\n.ifndef EXTRA\nf: .word 6\n.endif\nBEGIN\nmain: HALT\nEND\n";
+        assert_eq!(link_str(s).data, [1, 3, 6]);
+
+        let module = assemble_module(s, HashMap::from([("EXTRA".to_owned(), 0)])).unwrap();
+        assert_eq!(link_objects(&[&module], &Layout::default()).unwrap().data, [1, 3, 5]);
+    }
+
+    #[test]
+    fn unbalanced_conditionals_are_errors() {
+        assert_eq!(assembly_error(".else\n"), "Found .else without .if at line 1");
+        assert_eq!(assembly_error(".if 1\n.else\n.else\n.endif\n"), "Found repeated .else at line 3");
+        assert_eq!(assembly_error(".endif\n"), "Found .endif without .if at line 1");
+        assert_eq!(assembly_error(".if 1\nBEGIN\nmain: HALT\nEND\n"), "Missing .endif for .if at line 1");
+        assert_eq!(assembly_error(".if SIZE\n.endif\n"), "Undefined symbol SIZE at line 1\n\tSIZE");
+        assert_eq!(assembly_error(".if 1 <> 2\n.endif\n"), "Unexpected operator > in expression at line 1\n\t1 <> 2");
+        assert_eq!(assembly_error(".ifdef\n"), "Expected constant name after .ifdef at line 1");
+    }
+
+    #[test]
+    fn constants_are_defined_once() {
+        assert_eq!(assembly_error(".equ A 1\n"), "Expected constant name and value after .equ at line 1");
+        assert_eq!(assembly_error(".equ A, x\n"), "Undefined symbol x at line 1\n\tx");
+        assert_eq!(assembly_error(".equ A, 1\n.equ A, 2\n"), "Found constant redefinition at line 2\n\tA");
+    }
+
+    #[test]
+    fn storage_directives_reserve_their_count_of_words() {
+        let module = assemble_module(".equ N, 4\na: .space 3\nb: .zero N\nc: .fill 2, N + 1\nd: .space 0\ne: .word 1\nBEGIN\nmain: HALT\nEND\n", HashMap::new()).unwrap();
+        assert_eq!(module.data[0].entries.iter().map(|entry| entry.size()).collect::<Vec<usize>>(), [3, 4, 2, 0, 1]);
+        assert_eq!(link_objects(&[&module], &Layout::default()).unwrap().data, [0, 0, 0, 0, 0, 0, 0, 5, 5, 1]);
+    }
+
+    #[test]
+    fn storage_directives_need_a_count() {
+        assert_eq!(assembly_error("a: .space\n"), "Expected whitespace after .space directive at line 1");
+        assert_eq!(assembly_error("a: .zero x\n"), "Undefined symbol x at line 1\n\tx");
+        assert_eq!(assembly_error("a: .space -1\n"), "Count out of range at line 1\n\t-1");
+        assert_eq!(assembly_error("a: .zero 0x10001\n"), "Count out of range at line 1\n\t0x10001");
+        assert_eq!(assembly_error("a: .fill 3\n"), "Expected count and value after .fill directive at line 1");
+        assert_eq!(assembly_error("a: .fill 3,\n"), "Expected expression at line 1\n\t");
+        assert_eq!(assembly_error("BEGIN\nb: .zero 1\n"), "Found .zero directive in code section at line 2");
+    }
+
+    #[test]
+    fn sections_can_switch_back_and_forth() {
+        let executable = link_str("a: .word 1\nBEGIN\nmain: LDA b\n.data\nb: .word 2\n.code\n JMP main\n.data\n.org 4\nc: .word 3\n.code\nEND\n");
+        assert_eq!(executable.data, [1, 2, 0, 0, 3]);
+        assert_eq!(executable.code, [1 << 18 | 0x10001, 19 << 18]);
+
+        assert_eq!(assembly_error("a: .data\n"), "Unexpected label before .data directive at line 1");
+        assert_eq!(assembly_error(".org\n"), "Expected address after .org directive at line 1");
+        assert_eq!(assembly_error(".org 0x10000\n"), "Address out of range at line 1\n\t65536");
+        assert_eq!(assembly_error("BEGIN\nmain: HALT\n.data\n HALT\n"), "Found instruction outside code section at line 4");
+    }
+
+    #[test]
+    fn anonymous_labels_resolve_to_the_nearest_one_in_their_direction() {
+        let executable = link_str("n: .word 3\none: .word 1\nBEGIN\nmain: LDA n\n1: SUB one\n BEQ 1f\n JMP 1b\n1: HALT\nEND\n");
+        let operands: Vec<u32> = executable.code.iter().map(|word| word & 0x3FFFF).collect();
+
+        assert_eq!(operands[..4], [0x10000, 0x10001, 4, 1]);
+    }
+
+    #[test]
+    fn local_labels_are_private_to_their_scope() {
+        let executable = link_str("a: .word 1\n.x: .word 2\nb: .word 3\n.x: .word 4\n.p: .word .x\nBEGIN\nmain: HALT\nEND\n");
+        assert_eq!(executable.data, [1, 2, 3, 4, 0x10003]);
+
+        assert_eq!(assembly_error(".x: .word 1\n"), "Local label .x defined before any global label at line 1");
+        assert_eq!(assembly_error("a: .word 1\n.x: .word 2\n.x: .word 3\n"), "Found label redefinition at line 3\n\t.x");
+        assert_eq!(assembly_error("BEGIN\nmain: JMP 1b\n1: HALT\nEND\n"), "No previous anonymous label 1 for 1b at line 2");
+        assert_eq!(assembly_error("BEGIN\n1: JMP 2f\nEND\n"), "No following anonymous label 2 for 2f at line 2");
+    }
+
+    #[test]
+    fn exports_name_global_labels_the_module_defines() {
+        assert_eq!(assembly_error(".global\n"), "Expected label after .global directive at line 1");
+        assert_eq!(assembly_error("a: .global b\n"), "Unexpected label before .global directive at line 1");
+        assert_eq!(assembly_error("a: .word 1\n.x: .word 1\n.global .x\n"), "Only global labels can be exported at line 3\n\t.x");
+        assert_eq!(assembly_error(".global q\nBEGIN\nmain: HALT\nEND\n"), "Exported label q not defined in module at line 1");
+    }
+
+    #[test]
+    fn unlabeled_data_lines_stay_in_the_scope_of_the_last_global_label() {
+        let executable = link_str("table: .word 1, 2\n.end: .word 3\n .word .end - table\n .text a:b\n .fill 2, .end\nBEGIN\n HALT\nEND\n");
+
+        assert_eq!(executable.data, vec![1, 2, 3, 2, 0x623A61, 0x10002, 0x10002]);
+        assert_eq!(executable.symbols.iter().filter(|(name, _)| name == "table").count(), 1);
+    }
+
+    #[test]
+    fn global_data_labels_open_a_new_scope() {
+        assert_eq!(assembly_error("a: .word 1\n.x: .word 2\nb: .word .x\nBEGIN\n HALT\nEND\n"), "Local label .x not defined in b at line 3");
+    }
+
+    #[test]
+    fn numbers_and_constants_are_addresses_but_labels_must_be_of_the_right_kind() {
+        assert_eq!(link_str("BEGIN\n JMP 5\nEND\n").code[0] & 0x3FFFF, 5);
+        assert_eq!(link_str(".equ SIZE, 4\nBEGIN\n LDA SIZE + 1\nEND\n").code[0] & 0x3FFFF, 5);
+
+        assert_eq!(assembly_error("n: .word 1\nBEGIN\nmain: JMP n\nEND\n"), "JMP at line 3 expects a code label\n\tfound data label n");
+        assert_eq!(
+            assembly_error(".equ SIZE, 4\nn: .word 1\nBEGIN\nmain: JMP SIZE + n\nEND\n"),
+            "JMP at line 4 expects a code label\n\tfound data label n"
+        );
+    }
+
+    #[test]
+    fn local_labels_in_org_sections_keep_their_kind() {
+        assert_eq!(
+            assembly_error(".org 0x20\n1: .word 0\nBEGIN\nmain: JMP 1b\nEND\n"),
+            "JMP at line 4 expects a code label\n\tfound anonymous data label 1"
+        );
+        assert_eq!(
+            assembly_error("BEGIN\n.org 0x40\nmain: LDA .l\n.l: HALT\nEND\n"),
+            "LDA at line 3 expects a data label\n\tfound code label .l"
+        );
+
+        let executable = link_str(".equ BASE, 0x20\n.org BASE\nn: .word 0\n.v: .word 1\n.p: .word .v\nBEGIN\nmain: LDA n + 1\n JMP .l\n.l: HALT\nEND\n");
+        assert_eq!(executable.data[0x21..], [1, 0x10021]);
+    }
+}
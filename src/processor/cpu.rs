@@ -1,6 +1,7 @@
 use super::{
     assembler::OpCodes,
     memory::{MemoryCache, MEM_SIZE},
+    overlay, tape,
 };
 use pyo3::exceptions::PyTypeError;
 use pyo3::prelude::*;
@@ -20,6 +21,10 @@ pub static mut V: bool = false;
 pub static mut STATE: CPUState = CPUState::IDLE;
 pub static mut LAST_STATE: CPUState = CPUState::IDLE;
 
+// There is a single machine, so tests that run programs on it take turns
+#[cfg(test)]
+pub static TEST_MACHINE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
 pub static mut MEM: [MemoryCache; 4] = [
     MemoryCache {
         content: [0; MEM_SIZE],
@@ -98,9 +103,10 @@ pub unsafe fn get_v() -> PyResult<bool> {
 pub unsafe fn cycle() -> PyResult<()> {
     match STATE {
         CPUState::STEP => {
+            overlay::enter(PC)?;
             let instr = read_memory(PC).expect("Error while reading memory");
             let halted = process_instruction(instr);
-            PC += 1;
+            PC = PC.wrapping_add(1);
 
             if halted {
                 STATE = CPUState::IDLE;
@@ -111,9 +117,10 @@ pub unsafe fn cycle() -> PyResult<()> {
 
         CPUState::RUNNING => {
             loop {
+                overlay::enter(PC)?;
                 let instr = read_memory(PC).expect("Error while reading memory");
                 let halted = process_instruction(instr);
-                PC += 1;
+                PC = PC.wrapping_add(1);
 
                 if halted {
                     STATE = CPUState::IDLE;
@@ -201,8 +208,16 @@ pub unsafe fn process_instruction(instr: u32) -> bool {
 
         OpCodes::IRQ if irq_field == 2 => {
             SAVED_REG = argument & 0xFFFF | 0x10000; /* Saved register has memory position to be overwritten. */
-            LAST_STATE = STATE;
-            STATE = CPUState::INPUT;
+
+            // A mounted tape answers right away, otherwise the CPU waits for feed_read
+            match tape::next() {
+                Some(word) => write_memory(SAVED_REG, word).expect("Error while writing memory"),
+
+                None => {
+                    LAST_STATE = STATE;
+                    STATE = CPUState::INPUT;
+                }
+            }
 
             false
         }
@@ -292,7 +307,7 @@ pub unsafe fn process_instruction(instr: u32) -> bool {
 
         OpCodes::BEQ => {
             if Z == true {
-                PC = argument - 1;
+                PC = argument.wrapping_sub(1);
             }
 
             false
@@ -300,7 +315,7 @@ pub unsafe fn process_instruction(instr: u32) -> bool {
 
         OpCodes::BGT => {
             if (Z == false) && (V == N) {
-                PC = argument - 1;
+                PC = argument.wrapping_sub(1);
             }
 
             false
@@ -308,7 +323,7 @@ pub unsafe fn process_instruction(instr: u32) -> bool {
 
         OpCodes::BLT => {
             if V != N {
-                PC = argument - 1;
+                PC = argument.wrapping_sub(1);
             }
 
             false
@@ -316,7 +331,7 @@ pub unsafe fn process_instruction(instr: u32) -> bool {
 
         OpCodes::BHS => {
             if C == true {
-                PC = argument - 1;
+                PC = argument.wrapping_sub(1);
             }
 
             false
@@ -324,7 +339,7 @@ pub unsafe fn process_instruction(instr: u32) -> bool {
 
         OpCodes::BMI => {
             if N == true {
-                PC = argument - 1;
+                PC = argument.wrapping_sub(1);
             }
 
             false
@@ -332,7 +347,7 @@ pub unsafe fn process_instruction(instr: u32) -> bool {
 
         OpCodes::BVS => {
             if V == true {
-                PC = argument - 1;
+                PC = argument.wrapping_sub(1);
             }
 
             false
@@ -340,7 +355,7 @@ pub unsafe fn process_instruction(instr: u32) -> bool {
 
         OpCodes::BHI => {
             if C == true && Z == false {
-                PC = argument - 1;
+                PC = argument.wrapping_sub(1);
             }
 
             false
@@ -364,13 +379,13 @@ pub unsafe fn process_instruction(instr: u32) -> bool {
 
         OpCodes::JAL => {
             LA = PC;
-            PC = argument - 1;
+            PC = argument.wrapping_sub(1);
 
             false
         }
 
         OpCodes::JMP => {
-            PC = argument - 1;
+            PC = argument.wrapping_sub(1);
 
             false
         }
@@ -0,0 +1,421 @@
+use super::{
+    assembler::OpCodes,
+    expression::{BinaryOp, Expr},
+    object::{DataValue, Instruction, ObjectModule},
+};
+use std::collections::{HashMap, HashSet};
+
+// A routine or data block: a label and everything after it up to the next label
+struct Block {
+    module: usize,
+    code: bool,
+    section: usize,
+    start: usize,
+    end: usize,
+    words: usize,
+    // Offset from `.code` or `.data`, only sections the linker places have one
+    position: Option<usize>,
+    labels: Vec<String>,
+    // Labels used in the block and whether they are used bare, without arithmetic
+    uses: Vec<(String, bool)>,
+    // Offsets from `.code` or `.data` used bare, which is what local and anonymous labels become
+    offsets: Vec<(bool, usize)>,
+    falls_through: bool,
+    fixed: bool,
+}
+
+fn ends_routine(instr: &Instruction) -> bool {
+    matches!(instr.op, OpCodes::JMP | OpCodes::RET) || (instr.op == OpCodes::IRQ && instr.irq == Some(0))
+}
+
+fn words(value: &DataValue) -> Vec<&Expr> {
+    match value {
+        DataValue::Words(words) => words.iter().collect(),
+        DataValue::Fill(_, value) => vec![value],
+        DataValue::Text(_) => Vec::new(),
+    }
+}
+
+// Whether `expr` is `.code + n` or `.data + n`, and n
+fn section_offset(expr: &Expr) -> Option<(bool, usize)> {
+    match expr {
+        Expr::Binary(BinaryOp::Add, lhs, rhs) => match (&**lhs, &**rhs) {
+            (Expr::Symbol(name), Expr::Number(offset)) if name == ".code" || name == ".data" => Some((name == ".code", *offset as usize)),
+
+            _ => None,
+        },
+
+        _ => None,
+    }
+}
+
+// Moves section offsets to where what they point to ends up
+fn shift_offsets(expr: &mut Expr, moved: &dyn Fn(bool, usize) -> usize) {
+    if let Some((code, offset)) = section_offset(expr) {
+        if let Expr::Binary(_, _, rhs) = expr {
+            **rhs = Expr::Number(moved(code, offset) as u32);
+        }
+        return;
+    }
+
+    match expr {
+        Expr::Negate(inner) => shift_offsets(inner, moved),
+
+        Expr::Binary(_, lhs, rhs) => {
+            shift_offsets(lhs, moved);
+            shift_offsets(rhs, moved);
+        }
+
+        _ => {}
+    }
+}
+
+fn word_count(words: usize) -> String {
+    match words {
+        1 => "1 word".to_owned(),
+        _ => format!("{} words", words),
+    }
+}
+
+fn uses<'a>(exprs: impl Iterator<Item = &'a Expr>) -> Vec<(String, bool)> {
+    exprs
+        .filter(|expr| section_offset(expr).is_none())
+        .flat_map(|expr| {
+            let bare = matches!(expr, Expr::Symbol(_));
+            expr.symbols().into_iter().map(move |name| (name.to_owned(), bare))
+        })
+        .collect()
+}
+
+// Splits every section at its labels, in module and section order
+fn blocks(modules: &[ObjectModule]) -> Vec<Block> {
+    let mut blocks = Vec::new();
+
+    for (index, module) in modules.iter().enumerate() {
+        // Sections without .org follow each other, the offsets run on across them
+        let mut position = 0;
+
+        for (number, section) in module.data.iter().enumerate() {
+            let mut starts: Vec<usize> = section.entries.iter().enumerate().filter(|(_, entry)| !entry.label.is_empty()).map(|(i, _)| i).collect();
+            starts.insert(0, 0);
+            starts.dedup();
+
+            for (i, start) in starts.iter().enumerate() {
+                let end = starts.get(i + 1).copied().unwrap_or(section.entries.len());
+                let entries = &section.entries[*start..end];
+                let size = entries.iter().map(|entry| entry.size()).sum();
+
+                blocks.push(Block {
+                    module: index,
+                    code: false,
+                    section: number,
+                    start: *start,
+                    end,
+                    words: size,
+                    position: section.origin.map_or(Some(position), |_| None),
+                    labels: entries.iter().take(1).filter(|entry| !entry.label.is_empty()).map(|entry| entry.label.clone()).collect(),
+                    uses: uses(entries.iter().flat_map(|entry| words(&entry.value))),
+                    offsets: entries.iter().flat_map(|entry| words(&entry.value)).filter_map(section_offset).collect(),
+                    falls_through: false,
+                    fixed: section.origin.is_some(),
+                });
+
+                if section.origin.is_none() {
+                    position += size;
+                }
+            }
+        }
+
+        let mut position = 0;
+
+        for (number, section) in module.code.iter().enumerate() {
+            let mut starts: Vec<usize> = section.labels.iter().map(|(_, offset)| *offset as usize).collect();
+            starts.push(0);
+            starts.sort_unstable();
+            starts.dedup();
+
+            for (i, start) in starts.iter().enumerate() {
+                let end = starts.get(i + 1).copied().unwrap_or(section.instructions.len());
+                let instructions = &section.instructions[*start..end];
+
+                blocks.push(Block {
+                    module: index,
+                    code: true,
+                    section: number,
+                    start: *start,
+                    end,
+                    words: end - start,
+                    position: section.origin.map_or(Some(position + start), |_| None),
+                    labels: section.labels.iter().filter(|(_, offset)| *offset as usize == *start).map(|(name, _)| name.clone()).collect(),
+                    uses: uses(instructions.iter().filter_map(|instr| instr.operand.as_ref())),
+                    offsets: instructions.iter().filter_map(|instr| instr.operand.as_ref()).filter_map(section_offset).collect(),
+                    falls_through: i + 1 < starts.len() && instructions.last().is_none_or(|instr| !ends_routine(instr)),
+                    fixed: section.origin.is_some(),
+                });
+            }
+
+            if section.origin.is_none() {
+                position += section.instructions.len();
+            }
+        }
+    }
+
+    blocks
+}
+
+// Drops every routine and data block that can't be reached from the entry point, or from the
+// first instruction when there is none. Sections placed with .org are always kept. Returns the
+// modules left and what was removed.
+pub fn eliminate_dead_code(mut modules: Vec<ObjectModule>) -> (Vec<ObjectModule>, Vec<String>) {
+    let blocks = blocks(&modules);
+
+    // Strong definitions win over weak ones, as in the linker
+    let mut local: HashMap<(usize, &str), usize> = HashMap::new();
+    let mut global: HashMap<&str, usize> = HashMap::new();
+    for (index, block) in blocks.iter().enumerate() {
+        for label in &block.labels {
+            local.insert((block.module, label.as_str()), index);
+
+            let module = &modules[block.module];
+            if module.is_exported(label) {
+                let replaces = match global.get(label.as_str()) {
+                    None => true,
+                    Some(other) => modules[blocks[*other].module].is_weak(label) && !module.is_weak(label),
+                };
+                if replaces {
+                    global.insert(label.as_str(), index);
+                }
+            }
+        }
+    }
+    let lookup = |module: usize, name: &str| match modules[module].is_exported(name) {
+        true => global.get(name).copied(),
+        false => local.get(&(module, name)).or_else(|| global.get(name)).copied(),
+    };
+
+    let mut stack: Vec<usize> = (0..blocks.len()).filter(|index| blocks[*index].fixed).collect();
+    for (index, module) in modules.iter().enumerate() {
+        if let Some(entry) = &module.entry {
+            stack.extend(lookup(index, entry));
+        }
+    }
+    if modules.iter().all(|module| module.entry.is_none()) {
+        stack.extend(blocks.iter().position(|block| block.code && block.section == 0 && block.start == 0 && block.end > 0));
+    }
+
+    let mut reached = vec![false; blocks.len()];
+    while let Some(index) = stack.pop() {
+        if reached[index] {
+            continue;
+        }
+        reached[index] = true;
+
+        let block = &blocks[index];
+        if block.falls_through {
+            stack.push(index + 1);
+        }
+
+        // Local and anonymous labels point into the block that holds their offset
+        for (code, offset) in &block.offsets {
+            stack.extend(blocks.iter().position(|other| {
+                other.module == block.module && other.code == *code && other.position.is_some_and(|position| (position..position + other.words).contains(offset))
+            }));
+        }
+
+        for (name, bare) in &block.uses {
+            // Arithmetic on a section offset may point anywhere the linker places the module
+            if name == ".code" || name == ".data" {
+                stack.extend((0..blocks.len()).filter(|other| {
+                    let other = &blocks[*other];
+                    other.module == block.module && other.code == (name == ".code") && other.position.is_some()
+                }));
+                continue;
+            }
+
+            let target = match lookup(block.module, name) {
+                None => continue,
+
+                Some(target) => target,
+            };
+            stack.push(target);
+
+            // Arithmetic on a label may point anywhere in its section
+            if !bare {
+                let target = &blocks[target];
+                stack.extend((0..blocks.len()).filter(|other| {
+                    let other = &blocks[*other];
+                    other.module == target.module && other.code == target.code && other.section == target.section
+                }));
+            }
+        }
+    }
+
+    let mut removed = Vec::new();
+    let mut emptied = Vec::new();
+    for (index, module) in modules.iter_mut().enumerate() {
+        let module_blocks: Vec<(&Block, bool)> = blocks.iter().zip(reached.iter().copied()).filter(|(block, _)| block.module == index).collect();
+        if module_blocks.iter().all(|(_, reached)| !reached) && module_blocks.iter().any(|(block, _)| block.words != 0) {
+            removed.push(format!("Removed module {} ({})", module.name, word_count(module_blocks.iter().map(|(block, _)| block.words).sum())));
+            emptied.push(index);
+            continue;
+        }
+
+        let mut dropped: HashSet<&str> = HashSet::new();
+        for (block, reached) in &module_blocks {
+            let size = block.words;
+            if *reached || (size == 0 && block.labels.is_empty()) {
+                continue;
+            }
+
+            let kind = match block.code {
+                true => "routine",
+                false => "data",
+            };
+            let line = match block.code {
+                true => module.code[block.section].instructions.get(block.start).map(|instr| instr.line),
+                false => module.data[block.section].entries.get(block.start).map(|entry| entry.line),
+            };
+
+            removed.push(match (block.labels.first(), line) {
+                (Some(label), _) => format!("Removed {} {} ({}) from {}", kind, label, word_count(size), module.name),
+                (None, Some(line)) => format!("Removed unlabeled {} at line {} ({}) from {}", kind, line + 1, word_count(size), module.name),
+                (None, None) => continue,
+            });
+            dropped.extend(block.labels.iter().map(|label| label.as_str()));
+        }
+
+        // Which words the linker places are kept, for local and anonymous labels to move down with them
+        let mut kept_words = [Vec::new(), Vec::new()];
+        for (block, reached) in module_blocks.iter().filter(|(block, _)| block.position.is_some()) {
+            kept_words[block.code as usize].extend(std::iter::repeat_n(*reached, block.words));
+        }
+        let moved = |code: bool, offset: usize| kept_words[code as usize].iter().take(offset).filter(|kept| **kept).count();
+
+        // Keeps what was reached and moves labels down over what was dropped
+        for (number, section) in module.data.iter_mut().enumerate() {
+            let mut kept = vec![false; section.entries.len()];
+            for (block, reached) in module_blocks.iter().filter(|(block, _)| !block.code && block.section == number) {
+                kept[block.start..block.end].fill(*reached);
+            }
+
+            let mut flags = kept.into_iter();
+            section.entries.retain(|_| flags.next().unwrap_or(true));
+        }
+
+        for (number, section) in module.code.iter_mut().enumerate() {
+            let mut kept = vec![false; section.instructions.len()];
+            for (block, reached) in module_blocks.iter().filter(|(block, _)| block.code && block.section == number) {
+                kept[block.start..block.end].fill(*reached);
+            }
+
+            let before = |offset: u32| kept[..offset as usize].iter().filter(|kept| **kept).count() as u32;
+            section.labels = section
+                .labels
+                .iter()
+                .filter(|(name, _)| !dropped.contains(name.as_str()))
+                .map(|(name, offset)| (name.clone(), before(*offset)))
+                .collect();
+
+            let mut flags = kept.iter();
+            section.instructions.retain(|_| *flags.next().unwrap_or(&true));
+        }
+
+        for entry in module.data.iter_mut().flat_map(|section| section.entries.iter_mut()) {
+            match &mut entry.value {
+                DataValue::Words(words) => words.iter_mut().for_each(|word| shift_offsets(word, &moved)),
+                DataValue::Fill(_, value) => shift_offsets(value, &moved),
+                DataValue::Text(_) => {}
+            }
+        }
+        for operand in module.code.iter_mut().flat_map(|section| section.instructions.iter_mut()).filter_map(|instr| instr.operand.as_mut()) {
+            shift_offsets(operand, &moved);
+        }
+
+        let dropped: Vec<String> = dropped.into_iter().map(|label| label.to_owned()).collect();
+        module.exports.retain(|name| !dropped.contains(name));
+        module.weak.retain(|name| !dropped.contains(name));
+    }
+
+    for index in emptied.into_iter().rev() {
+        modules.remove(index);
+    }
+
+    // Nothing left may need a label from a module that is gone
+    for module in modules.iter_mut() {
+        let used: HashSet<String> = module.references().into_iter().map(|(name, _)| name.to_owned()).chain(module.entry.clone()).collect();
+        module.externs.retain(|name| used.contains(name));
+    }
+
+    (modules, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{layout::Layout, linker::link_objects, testing::module};
+
+    #[test]
+    fn unreached_routines_data_and_modules_are_removed() {
+        let main = module(
+            "main",
+            "n: .word 1\ntable: .word 2, 3\nEXTERN put\nBEGIN main\nmain: LDA n\n JAL used\n HALT\nused: RET 0\nunused: LDA table\n JAL put\n RET 0\nEND\n",
+        );
+        let util = module("util", ".global put\nBEGIN\nput: RET 0\nEND\n");
+
+        let (modules, removed) = eliminate_dead_code(vec![main, util]);
+        assert_eq!(
+            removed,
+            ["Removed data table (2 words) from main", "Removed routine unused (3 words) from main", "Removed module util (1 word)"]
+        );
+        assert_eq!(modules.len(), 1);
+        assert!(modules[0].externs.is_empty());
+
+        let executable = link_objects(&[&modules[0]], &Layout::default()).unwrap();
+        assert_eq!(executable.data, [1]);
+        assert_eq!(executable.code.len(), 4);
+        assert_eq!(executable.code[1] & 0x3FFFF, 3);
+    }
+
+    #[test]
+    fn fall_through_org_and_label_arithmetic_keep_what_they_reach() {
+        let main = module(
+            "main",
+            "a: .word 1\ntable: .word 2\nb: .word 3\nunused: .word 4\n.org 0x40\nflag: .word 5\nBEGIN main\nmain: LDA table + 1\nnext: HALT\n.org 0x80\nvector: RET 0\nEND\n",
+        );
+
+        let (modules, removed) = eliminate_dead_code(vec![main]);
+        assert_eq!(removed, Vec::<String>::new());
+        assert_eq!(modules[0].data[0].size(), 4);
+        assert_eq!(modules[0].code[0].instructions.len(), 2);
+
+        let main = module("main", "a: .word 1\nb: .word 2\nBEGIN main\nmain: LDA b\nnext: HALT\nEND\n");
+        let (_, removed) = eliminate_dead_code(vec![main]);
+        assert_eq!(removed, ["Removed data a (1 word) from main"]);
+    }
+    #[test]
+    fn local_and_anonymous_labels_keep_the_block_they_point_into() {
+        let main = module(
+            "main",
+            "a: .word 1\nb: .word 2\n1: .word 3\nc: .word 4\nBEGIN main\nmain: LDA 1b\n JAL 2f\n HALT\ndead: RET 0\nutil: LDA c\n.loop: JMP .loop\n2: RET 0\nEND\n",
+        );
+
+        let (modules, removed) = eliminate_dead_code(vec![main]);
+        assert_eq!(removed, ["Removed data a (1 word) from main", "Removed routine dead (1 word) from main"]);
+
+        let executable = link_objects(&[&modules[0]], &Layout::default()).unwrap();
+        assert_eq!(executable.data, [2, 3, 4]);
+        assert_eq!(executable.code.len(), 6);
+        assert_eq!(executable.code[0] & 0x3FFFF, 0x10001);
+        assert_eq!(executable.code[1] & 0x3FFFF, 5);
+        assert_eq!(executable.code[4] & 0x3FFFF, 4);
+    }
+    #[test]
+    fn removed_labels_are_no_longer_exported_or_weak() {
+        let main = module("main", ".global put\n.weak hook\nBEGIN main\nmain: HALT\nput: RET 0\nhook: RET 0\nEND\n");
+
+        let (modules, removed) = eliminate_dead_code(vec![main]);
+        assert_eq!(removed, ["Removed routine put (1 word) from main", "Removed routine hook (1 word) from main"]);
+        assert!(modules[0].exports.is_empty());
+        assert!(modules[0].weak.is_empty());
+    }
+}
@@ -0,0 +1,456 @@
+use super::{cpu::{execute, write_many}, objfile::Reader, overlay};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::fs;
+
+// Layout, all integers little endian:
+//   magic, version u16, flags u16
+//   entry, data address, data size, code address, code size, crc, u32 each
+//   data words, code words
+//   symbols: count u32, then name length u16, name, address u32
+//   debug:   file count u32, file names as above, line count u32, then address u32, file u16, line u32
+//   relocations: count u32, then address u32, kind u8
+//   overlays: count u32, then name, parent u32 (0 for the root, otherwise index + 1), address u32,
+//             stub count u32, stub addresses u32, word count u32, words
+//   exports:  count u32, then name, address u32
+//   imports:  count u32, then shared module name, label name, slot address u32
+// The crc covers everything after the header. Files without the magic are the legacy
+// layout: data count, data words, code words.
+pub const MAGIC: &[u8; 4] = b"FITA";
+pub const VERSION: u16 = 1;
+
+const SYMBOLS: u16 = 1;
+const DEBUG_LINES: u16 = 2;
+const RELOCATIONS: u16 = 4;
+const OVERLAYS: u16 = 8;
+const EXPORTS: u16 = 16;
+const IMPORTS: u16 = 32;
+
+// Which load address a relocated word is relative to
+pub const RELOCATE_CODE: u8 = 0;
+pub const RELOCATE_DATA: u8 = 1;
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => crc >> 1 ^ 0xEDB88320,
+            };
+        }
+    }
+
+    !crc
+}
+
+pub fn write_name(buf: &mut Vec<u8>, name: &str) {
+    buf.extend((name.len() as u16).to_le_bytes());
+    buf.extend(name.as_bytes());
+}
+
+pub fn read_name(reader: &mut Reader) -> Result<String, String> {
+    let len = reader.u16()? as usize;
+    match String::from_utf8(reader.take(len)?.to_vec()) {
+        Err(_) => Err(format!("Invalid name in {}", reader.name)),
+
+        Ok(name) => Ok(name),
+    }
+}
+
+// Code that shares its addresses with other overlays and is only loaded when one of its
+// stubs in the root code is reached
+#[derive(Clone)]
+pub struct Segment {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub address: u32,
+    pub stubs: Vec<u32>,
+    pub code: Vec<u32>,
+}
+
+#[pyclass]
+pub struct Executable {
+    #[pyo3(get)]
+    pub entry: u32,
+    #[pyo3(get)]
+    pub data_address: u32,
+    #[pyo3(get)]
+    pub data: Vec<u32>,
+    #[pyo3(get)]
+    pub code_address: u32,
+    #[pyo3(get)]
+    pub code: Vec<u32>,
+    #[pyo3(get)]
+    pub symbols: Vec<(String, u32)>,
+    #[pyo3(get)]
+    pub files: Vec<String>,
+    // Code address, index in files and line of every instruction
+    #[pyo3(get)]
+    pub lines: Vec<(u32, u16, u32)>,
+    // Address and kind of every word holding an address, None when it can only run where it was linked
+    #[pyo3(get)]
+    pub relocations: Option<Vec<(u32, u8)>>,
+    pub overlays: Vec<Segment>,
+    // Routines a shared module offers to the programs loaded after it
+    #[pyo3(get)]
+    pub exports: Vec<(String, u32)>,
+    // Shared module and label every jump table slot is patched with at load time
+    #[pyo3(get)]
+    pub imports: Vec<(String, String, u32)>,
+    #[pyo3(get)]
+    pub warnings: Vec<String>,
+}
+
+impl Executable {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(4 * (self.data.len() + self.code.len()));
+
+        for word in self.data.iter().chain(&self.code) {
+            body.extend(word.to_le_bytes());
+        }
+
+        let mut flags = 0;
+
+        if !self.symbols.is_empty() {
+            flags |= SYMBOLS;
+            body.extend((self.symbols.len() as u32).to_le_bytes());
+            for (name, address) in &self.symbols {
+                write_name(&mut body, name);
+                body.extend(address.to_le_bytes());
+            }
+        }
+
+        if !self.lines.is_empty() {
+            flags |= DEBUG_LINES;
+            body.extend((self.files.len() as u32).to_le_bytes());
+            for file in &self.files {
+                write_name(&mut body, file);
+            }
+
+            body.extend((self.lines.len() as u32).to_le_bytes());
+            for (address, file, line) in &self.lines {
+                body.extend(address.to_le_bytes());
+                body.extend(file.to_le_bytes());
+                body.extend(line.to_le_bytes());
+            }
+        }
+
+        if let Some(relocations) = &self.relocations {
+            flags |= RELOCATIONS;
+            body.extend((relocations.len() as u32).to_le_bytes());
+            for (address, kind) in relocations {
+                body.extend(address.to_le_bytes());
+                body.push(*kind);
+            }
+        }
+
+        if !self.overlays.is_empty() {
+            flags |= OVERLAYS;
+            body.extend((self.overlays.len() as u32).to_le_bytes());
+            for segment in &self.overlays {
+                write_name(&mut body, &segment.name);
+                body.extend(segment.parent.map_or(0, |parent| parent as u32 + 1).to_le_bytes());
+                body.extend(segment.address.to_le_bytes());
+                for words in [&segment.stubs, &segment.code] {
+                    body.extend((words.len() as u32).to_le_bytes());
+                    for word in words {
+                        body.extend(word.to_le_bytes());
+                    }
+                }
+            }
+        }
+
+        if !self.exports.is_empty() {
+            flags |= EXPORTS;
+            body.extend((self.exports.len() as u32).to_le_bytes());
+            for (name, address) in &self.exports {
+                write_name(&mut body, name);
+                body.extend(address.to_le_bytes());
+            }
+        }
+
+        if !self.imports.is_empty() {
+            flags |= IMPORTS;
+            body.extend((self.imports.len() as u32).to_le_bytes());
+            for (shared, name, slot) in &self.imports {
+                write_name(&mut body, shared);
+                write_name(&mut body, name);
+                body.extend(slot.to_le_bytes());
+            }
+        }
+
+        let mut buf = Vec::with_capacity(36 + body.len());
+        buf.extend(MAGIC);
+        buf.extend(VERSION.to_le_bytes());
+        buf.extend(flags.to_le_bytes());
+        for field in [self.entry, self.data_address, self.data.len() as u32, self.code_address, self.code.len() as u32, crc32(&body)] {
+            buf.extend(field.to_le_bytes());
+        }
+        buf.extend(body);
+
+        buf
+    }
+
+    pub fn from_bytes(bytes: &[u8], fita: &str) -> Result<Executable, String> {
+        let mut reader = Reader { bytes, name: fita };
+
+        if !bytes.starts_with(MAGIC) {
+            return Executable::from_legacy(reader);
+        }
+
+        reader.take(MAGIC.len())?;
+        let version = reader.u16()?;
+        if version > VERSION {
+            return Err(format!("Unsupported executable version {} in {}", version, fita));
+        }
+
+        let flags = reader.u16()?;
+        let entry = reader.u32()?;
+        let data_address = reader.u32()?;
+        let data_size = reader.u32()?;
+        let code_address = reader.u32()?;
+        let code_size = reader.u32()?;
+        let crc = reader.u32()?;
+
+        if crc32(reader.bytes) != crc {
+            return Err(format!("Checksum mismatch in {}, file is corrupted", fita));
+        }
+
+        let mut executable = Executable {
+            entry,
+            data_address,
+            data: (0..data_size).map(|_| reader.u32()).collect::<Result<_, _>>()?,
+            code_address,
+            code: (0..code_size).map(|_| reader.u32()).collect::<Result<_, _>>()?,
+            symbols: Vec::new(),
+            files: Vec::new(),
+            lines: Vec::new(),
+            relocations: None,
+            overlays: Vec::new(),
+            exports: Vec::new(),
+            imports: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        if flags & SYMBOLS != 0 {
+            for _ in 0..reader.u32()? {
+                executable.symbols.push((read_name(&mut reader)?, reader.u32()?));
+            }
+        }
+
+        if flags & DEBUG_LINES != 0 {
+            for _ in 0..reader.u32()? {
+                executable.files.push(read_name(&mut reader)?);
+            }
+
+            for _ in 0..reader.u32()? {
+                executable.lines.push((reader.u32()?, reader.u16()?, reader.u32()?));
+            }
+        }
+
+        if flags & RELOCATIONS != 0 {
+            executable.relocations = Some((0..reader.u32()?).map(|_| Ok((reader.u32()?, reader.u8()?))).collect::<Result<_, String>>()?);
+        }
+
+        if flags & OVERLAYS != 0 {
+            let count = reader.u32()?;
+            for index in 0..count {
+                let name = read_name(&mut reader)?;
+                let parent = match reader.u32()? {
+                    0 => None,
+                    parent if parent <= index => Some(parent as usize - 1),
+                    _ => return Err(format!("Invalid parent for overlay {} in {}", name, fita)),
+                };
+                let address = reader.u32()?;
+                let stubs = (0..reader.u32()?).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+                let code = (0..reader.u32()?).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+
+                executable.overlays.push(Segment { name, parent, address, stubs, code });
+            }
+        }
+
+        if flags & EXPORTS != 0 {
+            for _ in 0..reader.u32()? {
+                executable.exports.push((read_name(&mut reader)?, reader.u32()?));
+            }
+        }
+
+        if flags & IMPORTS != 0 {
+            for _ in 0..reader.u32()? {
+                executable.imports.push((read_name(&mut reader)?, read_name(&mut reader)?, reader.u32()?));
+            }
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(format!("Unexpected bytes after the end of {}", fita));
+        }
+
+        Ok(executable)
+    }
+
+    fn from_legacy(mut reader: Reader) -> Result<Executable, String> {
+        if reader.bytes.len() & 3 != 0 {
+            return Err(format!("{} is truncated, size is not a whole number of words", reader.name));
+        }
+
+        let data_size = reader.u32()?;
+        if data_size as usize > reader.bytes.len() / 4 {
+            return Err(format!("{} is truncated, expected {} data words", reader.name, data_size));
+        }
+
+        let data = (0..data_size).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+        let code = (0..reader.bytes.len() / 4).map(|_| reader.u32()).collect::<Result<_, _>>()?;
+
+        Ok(Executable {
+            entry: 0,
+            data_address: 1 << 16,
+            data,
+            code_address: 0,
+            code,
+            symbols: Vec::new(),
+            files: Vec::new(),
+            lines: Vec::new(),
+            relocations: None,
+            overlays: Vec::new(),
+            exports: Vec::new(),
+            imports: Vec::new(),
+            warnings: Vec::new(),
+        })
+    }
+}
+
+#[pymethods]
+impl Executable {
+    #[staticmethod]
+    pub fn open(fita: &str) -> PyResult<Executable> {
+        let bytes = match fs::read(fita) {
+            Err(why) => return Err(PyValueError::new_err(why.to_string())),
+
+            Ok(bytes) => bytes,
+        };
+
+        match Executable::from_bytes(&bytes, fita) {
+            Err(why) => Err(PyValueError::new_err(why)),
+            Ok(executable) => Ok(executable),
+        }
+    }
+
+    pub fn write(&self, fita: &str) -> PyResult<()> {
+        match fs::write(fita, self.to_bytes()) {
+            Err(why) => Err(PyValueError::new_err(why.to_string())),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    // Drops the symbol and debug sections
+    pub fn strip(&mut self) {
+        self.symbols.clear();
+        self.files.clear();
+        self.lines.clear();
+    }
+
+    // Name, address and size of every overlay
+    #[getter]
+    pub fn overlays(&self) -> Vec<(String, u32, u32)> {
+        self.overlays.iter().map(|segment| (segment.name.clone(), segment.address, segment.code.len() as u32)).collect()
+    }
+
+    // Overlays are left to be loaded by their stubs
+    pub fn load(&self) -> PyResult<()> {
+        if let Some((shared, _, _)) = self.imports.first() {
+            return Err(PyValueError::new_err(format!("Program needs shared module {}, load both with a Loader", shared)));
+        }
+
+        overlay::install("", &self.overlays);
+        unsafe {
+            write_many(self.data_address, self.data.clone())?;
+            write_many(self.code_address, self.code.clone())
+        }
+    }
+
+    // Loads at the linked addresses and starts from the entry point
+    pub fn run(&self, step: bool) -> PyResult<()> {
+        self.load()?;
+        unsafe { execute(self.entry, step) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::testing::error;
+
+    fn executable() -> Executable {
+        Executable {
+            entry: 0x12,
+            data_address: 0x10000,
+            data: vec![1, 0x10000],
+            code_address: 0x10,
+            code: vec![0x4C0012, 0x40010000, 0],
+            symbols: vec![("main".to_owned(), 0x12), ("n".to_owned(), 0x10000)],
+            files: vec!["main.bdc".to_owned()],
+            lines: vec![(0x10, 0, 3), (0x11, 0, 4)],
+            relocations: Some(vec![(0x10001, RELOCATE_DATA), (0x10, RELOCATE_CODE)]),
+            overlays: vec![Segment {
+                name: "menu".to_owned(),
+                parent: None,
+                address: 0x100,
+                stubs: vec![0x13],
+                code: vec![0x7C0000],
+            }],
+            exports: vec![("main".to_owned(), 0x12)],
+            imports: vec![("io.fita".to_owned(), "put".to_owned(), 0x14)],
+            warnings: Vec::new(),
+        }
+    }
+
+    fn read_error(bytes: &[u8]) -> String {
+        error(Executable::from_bytes(bytes, "prog.fita"))
+    }
+
+    #[test]
+    fn crc_is_the_usual_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn executables_read_back_the_same() {
+        let bytes = executable().to_bytes();
+        let read = Executable::from_bytes(&bytes, "prog.fita").unwrap();
+
+        assert_eq!(read.to_bytes(), bytes);
+        assert_eq!((read.entry, read.data_address, read.code_address), (0x12, 0x10000, 0x10));
+        assert_eq!((&read.data, &read.code), (&vec![1, 0x10000], &vec![0x4C0012, 0x40010000, 0]));
+        assert_eq!((&read.symbols, &read.files, &read.lines), (&executable().symbols, &executable().files, &executable().lines));
+        assert_eq!(read.relocations, Some(vec![(0x10001, RELOCATE_DATA), (0x10, RELOCATE_CODE)]));
+        assert_eq!(read.overlays(), [("menu".to_owned(), 0x100, 1)]);
+        assert_eq!(read.imports, [("io.fita".to_owned(), "put".to_owned(), 0x14)]);
+    }
+
+    #[test]
+    fn damaged_executables_are_rejected() {
+        let bytes = executable().to_bytes();
+
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(read_error(&flipped), "Checksum mismatch in prog.fita, file is corrupted");
+        assert_eq!(read_error(&bytes[..bytes.len() - 1]), "Checksum mismatch in prog.fita, file is corrupted");
+        assert_eq!(read_error(&bytes[..20]), "Unexpected end of file prog.fita");
+
+        let mut newer = bytes.clone();
+        newer[4] = VERSION as u8 + 1;
+        assert_eq!(read_error(&newer), format!("Unsupported executable version {} in prog.fita", VERSION + 1));
+    }
+
+    #[test]
+    fn legacy_executables_hold_data_then_code() {
+        let words: Vec<u8> = [2, 5, 6, 0x4C0000].iter().flat_map(|word: &u32| word.to_le_bytes()).collect();
+        let read = Executable::from_bytes(&words, "old.fita").unwrap();
+
+        assert_eq!((read.data, read.code, read.data_address, read.relocations), (vec![5, 6], vec![0x4C0000], 0x10000, None));
+        assert_eq!(read_error(&words[..10]), "prog.fita is truncated, size is not a whole number of words");
+        assert_eq!(read_error(&words[..8]), "prog.fita is truncated, expected 2 data words");
+    }
+}
@@ -0,0 +1,507 @@
+use std::fmt;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, FromRepr)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinaryOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 0,
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Shl | BinaryOp::Shr => 3,
+            BinaryOp::Add | BinaryOp::Sub => 4,
+            BinaryOp::Mul | BinaryOp::Div => 5,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "|",
+            BinaryOp::And => "&",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+        }
+    }
+
+    fn apply(self, lhs: u32, rhs: u32) -> Result<u32, String> {
+        Ok(match self {
+            BinaryOp::Or => lhs | rhs,
+            BinaryOp::And => lhs & rhs,
+            BinaryOp::Eq => u32::from(lhs == rhs),
+            BinaryOp::Ne => u32::from(lhs != rhs),
+            BinaryOp::Lt => u32::from(lhs < rhs),
+            BinaryOp::Le => u32::from(lhs <= rhs),
+            BinaryOp::Gt => u32::from(lhs > rhs),
+            BinaryOp::Ge => u32::from(lhs >= rhs),
+            BinaryOp::Shl => lhs.checked_shl(rhs).unwrap_or(0),
+            BinaryOp::Shr => lhs.checked_shr(rhs).unwrap_or(0),
+            BinaryOp::Add => lhs.wrapping_add(rhs),
+            BinaryOp::Sub => lhs.wrapping_sub(rhs),
+            BinaryOp::Mul => lhs.wrapping_mul(rhs),
+            BinaryOp::Div => match lhs.checked_div(rhs) {
+                None => return Err("Division by zero".to_owned()),
+
+                Some(v) => v,
+            },
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(u32),
+    Symbol(String),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Number(u32),
+    Symbol(String),
+    Op(&'static str),
+    Open,
+    Close,
+}
+
+pub fn parse_number(token: &str) -> Option<u32> {
+    if let Some(hex) = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = token.strip_prefix("0b").or_else(|| token.strip_prefix("0B")) {
+        u32::from_str_radix(bin, 2).ok()
+    } else {
+        token.parse::<u32>().ok()
+    }
+}
+
+// Parses a double quoted string literal at the start of `s`, returning its bytes
+// and whatever follows the closing quote.
+pub fn parse_string(s: &str) -> Result<(Vec<u8>, &str), String> {
+    let mut bytes = Vec::new();
+    let mut chars = match s.strip_prefix('"') {
+        None => return Err("Expected string literal".to_owned()),
+
+        Some(rest) => rest.char_indices(),
+    };
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((bytes, &s[i + 2..])),
+
+            '\\' => match chars.next() {
+                None => break,
+
+                Some((_, 'n')) => bytes.push(b'\n'),
+                Some((_, 't')) => bytes.push(b'\t'),
+                Some((_, 'r')) => bytes.push(b'\r'),
+                Some((_, '0')) => bytes.push(0),
+                Some((_, '"')) => bytes.push(b'"'),
+                Some((_, '\\')) => bytes.push(b'\\'),
+                Some((_, 'x')) => {
+                    let digits: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                    match u8::from_str_radix(digits.as_str(), 16) {
+                        Ok(byte) if digits.len() == 2 => bytes.push(byte),
+                        _ => return Err(format!("Invalid escape sequence \\x{}", digits)),
+                    }
+                }
+                Some((_, other)) => return Err(format!("Unknown escape sequence \\{}", other)),
+            },
+
+            c => {
+                let mut utf8 = [0; 4];
+                bytes.extend(c.encode_utf8(&mut utf8).bytes());
+            }
+        }
+    }
+
+    Err("Missing closing quote in string literal".to_owned())
+}
+
+// Drops a trailing comment started by `marker`, ignoring any marker inside a string literal.
+pub fn strip_comment<'a>(line: &'a str, marker: &str) -> &'a str {
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        if quoted {
+            match (escaped, c) {
+                (false, '\\') => escaped = true,
+                (false, '"') => quoted = false,
+                _ => escaped = false,
+            }
+        } else if c == '"' {
+            quoted = true;
+        } else if line[i..].starts_with(marker) {
+            return &line[..i];
+        }
+    }
+
+    line
+}
+
+pub fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+// Anonymous labels are referenced by number and direction, as in 1b or 1f
+pub fn is_anonymous_reference(word: &str) -> bool {
+    match word.strip_suffix('b').or_else(|| word.strip_suffix('f')) {
+        Some(number) => !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+
+        match c {
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '+' => tokens.push(Token::Op("+")),
+            '-' => tokens.push(Token::Op("-")),
+            '*' => tokens.push(Token::Op("*")),
+            '/' => tokens.push(Token::Op("/")),
+            '&' => tokens.push(Token::Op("&")),
+            '|' => tokens.push(Token::Op("|")),
+            '<' | '>' | '=' | '!' => {
+                let next = chars.peek().map(|(_, n)| *n);
+                let op = match (c, next) {
+                    ('<', Some('<')) => "<<",
+                    ('>', Some('>')) => ">>",
+                    ('<', Some('=')) => "<=",
+                    ('>', Some('=')) => ">=",
+                    ('=', Some('=')) => "==",
+                    ('!', Some('=')) => "!=",
+                    ('<', _) => "<",
+                    ('>', _) => ">",
+                    _ => return Err(format!("Unexpected character {} in expression", c)),
+                };
+                if op.len() == 2 {
+                    chars.next();
+                }
+                tokens.push(Token::Op(op));
+            }
+            c if is_symbol_char(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, n)) = chars.peek() {
+                    if !is_symbol_char(*n) {
+                        break;
+                    }
+                    end = i + n.len_utf8();
+                    chars.next();
+                }
+
+                let word = &s[start..end];
+                if word.starts_with(|c: char| c.is_ascii_digit()) {
+                    match parse_number(word) {
+                        None if is_anonymous_reference(word) => tokens.push(Token::Symbol(word.to_owned())),
+
+                        None => return Err(format!("Couldn't parse number {}", word)),
+
+                        Some(v) => tokens.push(Token::Number(v)),
+                    }
+                } else {
+                    tokens.push(Token::Symbol(word.to_owned()));
+                }
+            }
+            _ => return Err(format!("Unexpected character {} in expression", c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<BinaryOp> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(match *op {
+                "|" => BinaryOp::Or,
+                "&" => BinaryOp::And,
+                "==" => BinaryOp::Eq,
+                "!=" => BinaryOp::Ne,
+                "<" => BinaryOp::Lt,
+                "<=" => BinaryOp::Le,
+                ">" => BinaryOp::Gt,
+                ">=" => BinaryOp::Ge,
+                "<<" => BinaryOp::Shl,
+                ">>" => BinaryOp::Shr,
+                "+" => BinaryOp::Add,
+                "-" => BinaryOp::Sub,
+                "*" => BinaryOp::Mul,
+                "/" => BinaryOp::Div,
+                _ => return None,
+            }),
+            _ => None,
+        }
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+
+        while let Some(op) = self.peek_op() {
+            if op.precedence() < min_precedence {
+                break;
+            }
+            self.pos += 1;
+
+            let rhs = self.binary(op.precedence() + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        self.pos += 1;
+        match self.tokens.get(self.pos - 1) {
+            None => Err("Unexpected end of expression".to_owned()),
+
+            Some(Token::Number(v)) => Ok(Expr::Number(*v)),
+
+            Some(Token::Symbol(name)) => Ok(Expr::Symbol(name.clone())),
+
+            Some(Token::Op("-")) => Ok(Expr::Negate(Box::new(self.unary()?))),
+
+            Some(Token::Op("+")) => self.unary(),
+
+            Some(Token::Open) => {
+                let inner = self.binary(0)?;
+                match self.tokens.get(self.pos) {
+                    Some(Token::Close) => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => Err("Expected closing parenthesis".to_owned()),
+                }
+            }
+
+            Some(Token::Op(op)) => Err(format!("Unexpected operator {} in expression", op)),
+
+            Some(Token::Close) => Err("Unexpected closing parenthesis".to_owned()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+
+        if parser.tokens.is_empty() {
+            return Err("Expected expression".to_owned());
+        }
+
+        let expr = parser.binary(0)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+
+            Some(Token::Close) => Err("Unexpected closing parenthesis".to_owned()),
+
+            Some(_) => Err(format!("Unexpected token in expression {}", s.trim())),
+        }
+    }
+
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Symbol(name) => vec![name.as_str()],
+            Expr::Negate(inner) => inner.symbols(),
+            Expr::Binary(_, lhs, rhs) => {
+                let mut symbols = lhs.symbols();
+                symbols.extend(rhs.symbols());
+                symbols
+            }
+        }
+    }
+
+    pub fn value(&self) -> Option<u32> {
+        match self {
+            Expr::Number(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    // Replaces every symbol known to `lookup` and folds constant subexpressions,
+    // leaving whatever can only be resolved later.
+    pub fn fold(&self, lookup: &dyn Fn(&str) -> Option<u32>) -> Result<Expr, String> {
+        Ok(match self {
+            Expr::Number(v) => Expr::Number(*v),
+
+            Expr::Symbol(name) => match lookup(name) {
+                Some(v) => Expr::Number(v),
+                None => Expr::Symbol(name.clone()),
+            },
+
+            Expr::Negate(inner) => match inner.fold(lookup)? {
+                Expr::Number(v) => Expr::Number(v.wrapping_neg()),
+                inner => Expr::Negate(Box::new(inner)),
+            },
+
+            Expr::Binary(op, lhs, rhs) => match (lhs.fold(lookup)?, rhs.fold(lookup)?) {
+                (Expr::Number(l), Expr::Number(r)) => Expr::Number(op.apply(l, r)?),
+                (lhs, rhs) => Expr::Binary(*op, Box::new(lhs), Box::new(rhs)),
+            },
+        })
+    }
+
+    pub fn map_symbols(&self, f: &mut dyn FnMut(&str) -> Result<Expr, String>) -> Result<Expr, String> {
+        Ok(match self {
+            Expr::Number(v) => Expr::Number(*v),
+            Expr::Symbol(name) => f(name)?,
+            Expr::Negate(inner) => Expr::Negate(Box::new(inner.map_symbols(f)?)),
+            Expr::Binary(op, lhs, rhs) => Expr::Binary(*op, Box::new(lhs.map_symbols(f)?), Box::new(rhs.map_symbols(f)?)),
+        })
+    }
+
+    pub fn evaluate(&self, lookup: &dyn Fn(&str) -> Option<u32>) -> Result<u32, String> {
+        match self.fold(lookup)? {
+            Expr::Number(v) => Ok(v),
+            unresolved => Err(format!("Undefined symbol {}", unresolved.symbols().join(", "))),
+        }
+    }
+
+    fn write(&self, f: &mut fmt::Formatter, min_precedence: u8) -> fmt::Result {
+        match self {
+            Expr::Number(v) => write!(f, "{}", v),
+            Expr::Symbol(name) => write!(f, "{}", name),
+            Expr::Negate(inner) => {
+                write!(f, "-")?;
+                inner.write(f, u8::MAX)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let precedence = op.precedence();
+                if precedence < min_precedence {
+                    write!(f, "(")?;
+                }
+                lhs.write(f, precedence)?;
+                write!(f, "{}", op.symbol())?;
+                rhs.write(f, precedence + 1)?;
+                if precedence < min_precedence {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(s: &str) -> Result<u32, String> {
+        Expr::parse(s)?.evaluate(&|name| match name {
+            "a" => Some(10),
+            "b" => Some(3),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn operators_follow_their_precedence() {
+        assert_eq!(value("1 + 2 * 3"), Ok(7));
+        assert_eq!(value("(1 + 2) * 3"), Ok(9));
+        assert_eq!(value("1 << 2 + 1"), Ok(8));
+        assert_eq!(value("a - b - 1"), Ok(6));
+        assert_eq!(value("0x10 | 0b11 & 1"), Ok(17));
+        assert_eq!(value("a > b == 1"), Ok(1));
+        assert_eq!(value("-1"), Ok(u32::MAX));
+        assert_eq!(value("+a / -(-b)"), Ok(3));
+    }
+
+    #[test]
+    fn written_expressions_parse_back_to_the_same_tree() {
+        for s in ["(a+1)*b-c", "a-(b-c)", "-(a+1)", "a<<2|b&1", "(a==b)+1", "1b+2f"] {
+            let expr = Expr::parse(s).unwrap();
+            assert_eq!(expr.to_string(), s);
+            assert_eq!(Expr::parse(&expr.to_string()), Ok(expr));
+        }
+    }
+
+    #[test]
+    fn folding_keeps_the_symbols_it_can_not_resolve() {
+        let expr = Expr::parse("c + 2 * a").unwrap().fold(&|name| (name == "a").then_some(4)).unwrap();
+
+        assert_eq!(expr.to_string(), "c+8");
+        assert_eq!(expr.symbols(), ["c"]);
+        assert_eq!(expr.value(), None);
+    }
+
+    #[test]
+    fn malformed_expressions_are_reported() {
+        assert_eq!(value("1 +"), Err("Unexpected end of expression".to_owned()));
+        assert_eq!(value("(1 + 2"), Err("Expected closing parenthesis".to_owned()));
+        assert_eq!(value("1 + 2)"), Err("Unexpected closing parenthesis".to_owned()));
+        assert_eq!(value("1 2"), Err("Unexpected token in expression 1 2".to_owned()));
+        assert_eq!(value("* 2"), Err("Unexpected operator * in expression".to_owned()));
+        assert_eq!(value("12ab"), Err("Couldn't parse number 12ab".to_owned()));
+        assert_eq!(value("a $ b"), Err("Unexpected character $ in expression".to_owned()));
+        assert_eq!(value("a / (b - 3)"), Err("Division by zero".to_owned()));
+        assert_eq!(value("a + x"), Err("Undefined symbol x".to_owned()));
+        assert_eq!(value(" "), Err("Expected expression".to_owned()));
+    }
+
+    #[test]
+    fn string_literals_decode_escapes_and_stop_at_the_closing_quote() {
+        assert_eq!(parse_string(r#""a\n\x41\"\\\0" rest"#), Ok((b"a\nA\"\\\0".to_vec(), " rest")));
+        assert_eq!(parse_string("\"ç\""), Ok(("ç".as_bytes().to_vec(), "")));
+        assert_eq!(parse_string(r#""\q""#), Err("Unknown escape sequence \\q".to_owned()));
+        assert_eq!(parse_string(r#""\x4""#), Err("Invalid escape sequence \\x4\"".to_owned()));
+        assert_eq!(parse_string("\"open"), Err("Missing closing quote in string literal".to_owned()));
+        assert_eq!(parse_string("bare"), Err("Expected string literal".to_owned()));
+    }
+
+    #[test]
+    fn comments_inside_strings_are_kept() {
+        assert_eq!(strip_comment(r#"msg: .text "a//b" // note"#, "//"), r#"msg: .text "a//b" "#);
+        assert_eq!(strip_comment(r#".text "say \"//\"" x"#, "//"), r#".text "say \"//\"" x"#);
+        assert_eq!(strip_comment(" LDA a // b", "//"), " LDA a ");
+        assert_eq!(strip_comment(r#"name = "a#b" # app"#, "#"), r#"name = "a#b" "#);
+    }
+}
@@ -0,0 +1,378 @@
+use super::expression::Expr;
+use std::fs;
+
+// Layout files have one statement per line, `//` starts a comment:
+//   REGION name start size
+//   SECTION .code|.data region
+//   OVERLAY name region [parent]
+//   MODULE file overlay
+// Overlays in the same region share its addresses and only one of them is loaded at a time.
+// An overlay's code may use labels of the root, of the overlays above it and, through a stub
+// that loads them, of the overlays below it.
+const MEMORY_END: u32 = 4 << 16;
+
+// Pages the CPU runs code from and keeps data in, where .org addresses point
+pub const CODE_PAGE: u32 = 0;
+pub const DATA_PAGE: u32 = 1 << 16;
+
+pub struct Region {
+    pub name: String,
+    pub start: u32,
+    pub size: u32,
+}
+
+impl Region {
+    pub fn contains(&self, address: u32) -> bool {
+        (self.start..self.start + self.size).contains(&address)
+    }
+}
+
+pub struct Overlay {
+    pub name: String,
+    pub region: usize,
+    pub parent: Option<usize>,
+    pub modules: Vec<String>,
+}
+
+pub struct Layout {
+    pub regions: Vec<Region>,
+    pub overlays: Vec<Overlay>,
+    code: usize,
+    data: usize,
+}
+
+impl Default for Layout {
+    // One page per region, as the CPU sees its memory
+    fn default() -> Self {
+        let region = |name: &str, page: u32| Region {
+            name: name.to_owned(),
+            start: page << 16,
+            size: 1 << 16,
+        };
+
+        Layout {
+            regions: vec![region("code", 0), region("data", 1), region("stack", 2), region("io", 3)],
+            overlays: Vec::new(),
+            code: 0,
+            data: 1,
+        }
+    }
+}
+
+fn number(arg: Option<&str>, what: &str, i: usize, name: &str) -> Result<u32, String> {
+    match arg.map(Expr::parse) {
+        None => Err(format!("Expected region {} at line {} in {}", what, i + 1, name)),
+
+        Some(Err(why)) => Err(format!("{} at line {} in {}", why, i + 1, name)),
+
+        Some(Ok(expr)) => match expr.value() {
+            None => Err(format!("Expected number as region {} at line {} in {}\n\tfound {} instead", what, i + 1, name, expr)),
+
+            Some(v) => Ok(v),
+        },
+    }
+}
+
+// Whether a MODULE line names the linked module, by path, file name or library member
+pub fn is_module(module: &str, file: &str) -> bool {
+    module == file || module.ends_with(&format!("/{}", file)) || module.ends_with(&format!("({})", file))
+}
+
+impl Layout {
+    pub fn code(&self) -> &Region {
+        &self.regions[self.code]
+    }
+
+    pub fn data(&self) -> &Region {
+        &self.regions[self.data]
+    }
+
+    // Overlay a module's code goes in, None for the root
+    pub fn overlay(&self, module: &str) -> Option<usize> {
+        self.overlays.iter().position(|overlay| overlay.modules.iter().any(|file| is_module(module, file)))
+    }
+
+    // Whether `inner` is `outer` or hangs below it, so `outer` stays loaded while `inner` runs
+    pub fn encloses(&self, outer: Option<usize>, inner: Option<usize>) -> bool {
+        let mut current = inner;
+        loop {
+            if current == outer {
+                return true;
+            }
+
+            current = match current {
+                None => return false,
+
+                Some(overlay) => self.overlays[overlay].parent,
+            };
+        }
+    }
+
+    pub fn parse(s: &str, name: &str) -> Result<Layout, String> {
+        let mut regions: Vec<Region> = Vec::new();
+        let mut overlays: Vec<Overlay> = Vec::new();
+        let mut code = None;
+        let mut data = None;
+
+        for (i, line) in s.lines().enumerate() {
+            let line = match line.split_once("//") {
+                Some((line, _)) => line,
+                None => line,
+            };
+
+            let mut args = line.split_whitespace();
+            let statement = match args.next() {
+                None => continue,
+
+                Some(statement) => statement,
+            };
+
+            match statement {
+                "REGION" => {
+                    let region = match args.next() {
+                        None => return Err(format!("Expected region name at line {} in {}", i + 1, name)),
+
+                        Some(region) => region,
+                    };
+                    let start = number(args.next(), "start", i, name)?;
+                    let size = number(args.next(), "size", i, name)?;
+
+                    if let Some(arg) = args.next() {
+                        return Err(format!("Unexpected argument at line {} in {}\n\t{}", i + 1, name, arg));
+                    }
+
+                    if size == 0 || start >= MEMORY_END || size > MEMORY_END - start {
+                        return Err(format!("Region {} outside of memory at line {} in {}", region, i + 1, name));
+                    }
+
+                    // Relocations only patch the low 16 bits, so a region has to stay in its page
+                    if start >> 16 != (start + size - 1) >> 16 {
+                        return Err(format!("Region {} crosses a page boundary at line {} in {}", region, i + 1, name));
+                    }
+
+                    if let Some(other) = regions.iter().find(|other| other.name == region) {
+                        return Err(format!("Found repeated region {} at line {} in {}", other.name, i + 1, name));
+                    }
+
+                    if let Some(other) = regions.iter().find(|other| start < other.start + other.size && other.start < start + size) {
+                        return Err(format!("Region {} overlaps {} at line {} in {}", region, other.name, i + 1, name));
+                    }
+
+                    regions.push(Region {
+                        name: region.to_owned(),
+                        start,
+                        size,
+                    });
+                }
+
+                "SECTION" => {
+                    let (section, region) = match (args.next(), args.next(), args.next()) {
+                        (Some(section), Some(region), None) => (section, region),
+                        _ => return Err(format!("Expected section and region after SECTION at line {} in {}", i + 1, name)),
+                    };
+
+                    let index = match regions.iter().position(|other| other.name == region) {
+                        None => return Err(format!("Unknown region {} at line {} in {}", region, i + 1, name)),
+
+                        Some(index) => index,
+                    };
+
+                    let (target, page) = match section {
+                        ".code" => (&mut code, CODE_PAGE),
+                        ".data" => (&mut data, DATA_PAGE),
+                        _ => return Err(format!("Unknown section {} at line {} in {}", section, i + 1, name)),
+                    };
+
+                    if target.is_some() {
+                        return Err(format!("Found repeated SECTION {} at line {} in {}", section, i + 1, name));
+                    }
+
+                    // PRINT and READ only reach data in its page, and the loader places each section in its own
+                    if regions[index].start >> 16 != page >> 16 {
+                        return Err(format!("Region {} for SECTION {} is outside page {} at line {} in {}", region, section, page >> 16, i + 1, name));
+                    }
+                    *target = Some(index);
+                }
+
+                "OVERLAY" => {
+                    let (overlay, region, parent) = match (args.next(), args.next(), args.next(), args.next()) {
+                        (Some(overlay), Some(region), parent, None) => (overlay, region, parent),
+                        _ => return Err(format!("Expected name, region and optional parent after OVERLAY at line {} in {}", i + 1, name)),
+                    };
+
+                    if overlays.iter().any(|other| other.name == overlay) {
+                        return Err(format!("Found repeated overlay {} at line {} in {}", overlay, i + 1, name));
+                    }
+
+                    let region = match regions.iter().position(|other| other.name == region) {
+                        None => return Err(format!("Unknown region {} at line {} in {}", region, i + 1, name)),
+
+                        Some(index) => index,
+                    };
+
+                    // Overlays only hold code, and the CPU fetches instructions from page 0
+                    if regions[region].start >> 16 != CODE_PAGE >> 16 {
+                        return Err(format!("Region {} for OVERLAY {} is outside page {} at line {} in {}", regions[region].name, overlay, CODE_PAGE >> 16, i + 1, name));
+                    }
+
+                    let parent = match parent.map(|parent| (parent, overlays.iter().position(|other| other.name == parent))) {
+                        None => None,
+
+                        Some((parent, None)) => return Err(format!("Unknown overlay {} at line {} in {}", parent, i + 1, name)),
+
+                        Some((_, Some(index))) => Some(index),
+                    };
+
+                    // Loading the overlay would wipe out the code that called it
+                    let mut ancestor = parent;
+                    while let Some(index) = ancestor {
+                        if overlays[index].region == region {
+                            return Err(format!(
+                                "Overlay {} shares region {} with {} above it at line {} in {}",
+                                overlay, regions[region].name, overlays[index].name, i + 1, name
+                            ));
+                        }
+                        ancestor = overlays[index].parent;
+                    }
+
+                    overlays.push(Overlay {
+                        name: overlay.to_owned(),
+                        region,
+                        parent,
+                        modules: Vec::new(),
+                    });
+                }
+
+                "MODULE" => {
+                    let (module, overlay) = match (args.next(), args.next(), args.next()) {
+                        (Some(module), Some(overlay), None) => (module, overlay),
+                        _ => return Err(format!("Expected file and overlay after MODULE at line {} in {}", i + 1, name)),
+                    };
+
+                    if let Some(other) = overlays.iter().find(|other| other.modules.iter().any(|file| file == module)) {
+                        return Err(format!("Module {} already in overlay {} at line {} in {}", module, other.name, i + 1, name));
+                    }
+
+                    match overlays.iter_mut().find(|other| other.name == overlay) {
+                        None => return Err(format!("Unknown overlay {} at line {} in {}", overlay, i + 1, name)),
+
+                        Some(overlay) => overlay.modules.push(module.to_owned()),
+                    }
+                }
+
+                _ => return Err(format!("Unknown statement {} at line {} in {}", statement, i + 1, name)),
+            }
+        }
+
+        if let Some(overlay) = overlays.iter().find(|overlay| Some(overlay.region) == code || Some(overlay.region) == data) {
+            return Err(format!("Overlay {} can't use region {} of a section in {}", overlay.name, regions[overlay.region].name, name));
+        }
+
+        match (code, data) {
+            (None, _) => Err(format!("Missing SECTION .code in {}", name)),
+
+            (_, None) => Err(format!("Missing SECTION .data in {}", name)),
+
+            (Some(code), Some(data)) if code == data => Err(format!("Sections .code and .data share region {} in {}", regions[code].name, name)),
+
+            (Some(code), Some(data)) => Ok(Layout { regions, overlays, code, data }),
+        }
+    }
+
+    pub fn read(path: &str) -> Result<Layout, String> {
+        match fs::read_to_string(path) {
+            Err(why) => Err(format!("Read error: {}", why)),
+
+            Ok(s) => Layout::parse(&s, path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::testing::error;
+
+    fn layout_error(s: &str) -> String {
+        error(Layout::parse(s, "test.ld"))
+    }
+
+    #[test]
+    fn sections_stay_in_the_pages_the_cpu_uses_for_them() {
+        assert_eq!(
+            layout_error("REGION code 0x100 0x100\nREGION data 0x20000 0x100\nSECTION .code code\nSECTION .data data\n"),
+            "Region data for SECTION .data is outside page 1 at line 4 in test.ld"
+        );
+        assert_eq!(
+            layout_error("REGION code 0x30000 0x100\nSECTION .code code\n"),
+            "Region code for SECTION .code is outside page 0 at line 2 in test.ld"
+        );
+        assert_eq!(
+            layout_error("REGION code 0 0x100\nREGION data 0x10000 0x100\nREGION swap 0x10100 0x100\nSECTION .code code\nSECTION .data data\nOVERLAY o swap\n"),
+            "Region swap for OVERLAY o is outside page 0 at line 6 in test.ld"
+        );
+    }
+
+    #[test]
+    fn layouts_name_regions_and_sections() {
+        let layout = Layout::parse(
+            "// low memory for code\nREGION low 0 0x400\nREGION vars 0x10000 0x1000 // all the data\nSECTION .code low\nSECTION .data vars\n",
+            "game.ld",
+        )
+        .unwrap();
+
+        assert_eq!((layout.code().name.as_str(), layout.code().start, layout.code().size), ("low", 0, 0x400));
+        assert_eq!((layout.data().name.as_str(), layout.data().start, layout.data().size), ("vars", 0x10000, 0x1000));
+        assert!(layout.data().contains(0x10FFF));
+        assert!(!layout.data().contains(0x11000));
+    }
+
+    #[test]
+    fn overlays_name_their_region_parent_and_modules() {
+        let layout = Layout::parse(
+            "// two overlays over the same region\nREGION low 0 0x400\nREGION swap 0x400 0x100 // loaded on demand\nREGION vars 0x10000 0x1000\nSECTION .code low\nSECTION .data vars\nOVERLAY menu swap\nOVERLAY game swap\nOVERLAY level low2\n",
+            "game.ld",
+        );
+        assert_eq!(error(layout), "Unknown region low2 at line 9 in game.ld");
+
+        let layout = Layout::parse(
+            "REGION low 0 0x400\nREGION swap 0x400 0x100\nREGION deep 0x500 0x100\nREGION vars 0x10000 0x1000\nSECTION .code low\nSECTION .data vars\nOVERLAY menu swap\nOVERLAY game swap\nOVERLAY level deep game\nMODULE menu.bdc menu\nMODULE game.bdc game\nMODULE lvl.bdc level\n",
+            "game.ld",
+        )
+        .unwrap();
+
+        assert_eq!(layout.overlay("build/menu.bdc"), Some(0));
+        assert_eq!(layout.overlay("lib.lib(lvl.bdc)"), Some(2));
+        assert_eq!(layout.overlay("main.bdc"), None);
+        assert!(layout.encloses(Some(1), Some(2)));
+        assert!(layout.encloses(None, Some(2)));
+        assert!(!layout.encloses(Some(0), Some(2)));
+        assert!(!layout.encloses(Some(2), None));
+    }
+
+    #[test]
+    fn malformed_layouts_are_reported() {
+        let sections = "SECTION .code code\nSECTION .data data\n";
+        let regions = "REGION code 0 0x100\nREGION data 0x10000 0x100\n";
+
+        assert_eq!(layout_error("REGION big 0x3FF00 0x200\n"), "Region big outside of memory at line 1 in test.ld");
+        assert_eq!(layout_error("REGION wide 0xFF00 0x200\n"), "Region wide crosses a page boundary at line 1 in test.ld");
+        assert_eq!(layout_error("REGION a 0 0x100\nREGION b 0x80 0x100\n"), "Region b overlaps a at line 2 in test.ld");
+        assert_eq!(layout_error("REGION a 0 0x100\nREGION a 0x200 0x100\n"), "Found repeated region a at line 2 in test.ld");
+        assert_eq!(layout_error("REGION a x 0x100\n"), "Expected number as region start at line 1 in test.ld\n\tfound x instead");
+        assert_eq!(layout_error(&format!("{}{}SECTION .code code\n", regions, sections)), "Found repeated SECTION .code at line 5 in test.ld");
+        assert_eq!(layout_error(&format!("{}SECTION .bss data\n", regions)), "Unknown section .bss at line 3 in test.ld");
+        assert_eq!(layout_error(&format!("{}SECTION .code low\n", regions)), "Unknown region low at line 3 in test.ld");
+        assert_eq!(layout_error(&format!("{}SECTION .code code\n", regions)), "Missing SECTION .data in test.ld");
+        assert_eq!(layout_error(&format!("{}{}OVERLAY o code\n", regions, sections)), "Overlay o can't use region code of a section in test.ld");
+        assert_eq!(
+            layout_error(&format!("{}REGION swap 0x100 0x100\n{}OVERLAY a swap\nOVERLAY b swap a\n", regions, sections)),
+            "Overlay b shares region swap with a above it at line 7 in test.ld"
+        );
+        assert_eq!(
+            layout_error(&format!("{}REGION swap 0x100 0x100\n{}OVERLAY a swap\nMODULE m.bdc a\nMODULE m.bdc a\n", regions, sections)),
+            "Module m.bdc already in overlay a at line 8 in test.ld"
+        );
+        assert_eq!(layout_error(&format!("{}{}STACK 0x20000\n", regions, sections)), "Unknown statement STACK at line 5 in test.ld");
+    }
+}
@@ -0,0 +1,198 @@
+use super::{
+    executable::{read_name, write_name},
+    object::ObjectModule,
+    objfile::{self, read_object, ObjectFile, Reader},
+};
+use pyo3::prelude::*;
+use std::{collections::HashMap, fs, path::Path};
+
+// Layout, all integers little endian:
+//   magic, version u16, member count u32
+//   members: name length u16, name, object size u32, binary object file
+pub const MAGIC: &[u8; 4] = b"SLIB";
+pub const VERSION: u16 = 1;
+
+pub struct Member {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+pub struct Library {
+    pub members: Vec<Member>,
+}
+
+impl Library {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        buf.extend(MAGIC);
+        buf.extend(VERSION.to_le_bytes());
+        buf.extend((self.members.len() as u32).to_le_bytes());
+
+        for member in &self.members {
+            write_name(&mut buf, &member.name);
+            buf.extend((member.bytes.len() as u32).to_le_bytes());
+            buf.extend(&member.bytes);
+        }
+
+        buf
+    }
+
+    pub fn read(bytes: &[u8], lib: &str) -> Result<Library, String> {
+        let mut reader = Reader { bytes, name: lib };
+
+        if reader.take(4)? != MAGIC {
+            return Err(format!("{} is not a library", lib));
+        }
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(format!("Unsupported library version {} in {}", version, lib));
+        }
+
+        let mut library = Library { members: Vec::new() };
+        for _ in 0..reader.u32()? {
+            let name = read_name(&mut reader)?;
+            let len = reader.u32()? as usize;
+            let bytes = reader.take(len)?.to_vec();
+
+            library.members.push(Member { name, bytes });
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(format!("Unexpected data after the last member of {}", lib));
+        }
+
+        Ok(library)
+    }
+
+    // Members are named after the library, like lib.lib(member.bdc)
+    pub fn modules(&self, lib: &str) -> Result<Vec<ObjectModule>, String> {
+        self.members
+            .iter()
+            .map(|member| read_object(&member.bytes, format!("{}({})", lib, member.name).as_str()))
+            .collect()
+    }
+}
+
+#[pyfunction]
+pub fn archive(breadcrumbs: Vec<&str>, out: &str) -> PyResult<(bool, String)> {
+    let mut library = Library { members: Vec::new() };
+    let mut exported: HashMap<String, String> = HashMap::new();
+
+    for bdc in breadcrumbs {
+        let bytes = match fs::read(bdc) {
+            Err(why) => return Ok((false, why.to_string())),
+
+            Ok(bytes) => bytes,
+        };
+
+        let module = match read_object(&bytes, bdc) {
+            Err(why) => return Ok((false, why)),
+
+            Ok(module) => module,
+        };
+
+        let name = Path::new(bdc).file_name().map_or(bdc.to_owned(), |name| name.to_string_lossy().into_owned());
+        if library.members.iter().any(|member| member.name == name) {
+            return Ok((false, format!("Found repeated member {} in {}", name, out)));
+        }
+
+        // The linker picks members by the labels they export, so each label has a single strong owner
+        for label in module.exports.iter().filter(|label| !module.is_weak(label)) {
+            if let Some(other) = exported.insert(label.clone(), name.clone()) {
+                return Ok((false, format!("Label {} exported by both {} and {}", label, other, name)));
+            }
+        }
+
+        // Members are always stored in the binary format
+        let bytes = match bytes.starts_with(objfile::MAGIC) {
+            true => bytes,
+            false => match ObjectFile::from_module(&module) {
+                Err(why) => return Ok((false, why)),
+
+                Ok(file) => file.write(),
+            },
+        };
+
+        library.members.push(Member { name, bytes });
+    }
+
+    match fs::write(out, library.to_bytes()) {
+        Err(why) => Ok((false, why.to_string())),
+        Ok(_) => Ok((true, format!("Archived {} modules into {}", library.members.len(), out))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{
+        assembler::assemble_module,
+        testing::{error, scratch},
+    };
+
+    fn object(s: &str) -> Vec<u8> {
+        ObjectFile::from_module(&assemble_module(s, HashMap::new()).unwrap()).unwrap().write()
+    }
+
+    fn read_error(bytes: &[u8]) -> String {
+        error(Library::read(bytes, "std.lib"))
+    }
+
+    fn library() -> Library {
+        Library {
+            members: vec![
+                Member {
+                    name: "put.bdc".to_owned(),
+                    bytes: object(".global put\nBEGIN\nput: RET 0\nEND\n"),
+                },
+                Member {
+                    name: "get.bdc".to_owned(),
+                    bytes: object(".global get\nBEGIN\nget: RET 0\nEND\n"),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn members_read_back_named_after_the_library() {
+        let bytes = library().to_bytes();
+        let read = Library::read(&bytes, "std.lib").unwrap();
+
+        assert_eq!(read.to_bytes(), bytes);
+        let modules = read.modules("std.lib").unwrap();
+        assert_eq!(modules.iter().map(|module| module.name.as_str()).collect::<Vec<&str>>(), ["std.lib(put.bdc)", "std.lib(get.bdc)"]);
+        assert_eq!(modules[1].exports, ["get"]);
+    }
+
+    #[test]
+    fn damaged_libraries_are_rejected() {
+        let bytes = library().to_bytes();
+
+        assert_eq!(read_error(b"SBDC\x01\x00"), "std.lib is not a library");
+        assert_eq!(read_error(&bytes[..bytes.len() - 1]), "Unexpected end of file std.lib");
+        assert_eq!(read_error(&[bytes.as_slice(), &[0]].concat()), "Unexpected data after the last member of std.lib");
+
+        let mut newer = bytes.clone();
+        newer[4] = VERSION as u8 + 1;
+        assert_eq!(read_error(&newer), format!("Unsupported library version {} in std.lib", VERSION + 1));
+    }
+
+    #[test]
+    fn archives_refuse_two_owners_of_a_label() {
+        let root = scratch("archive", &[]);
+        let path = |name: &str| root.join(name).to_string_lossy().into_owned();
+
+        fs::write(path("a.bdc"), object(".global put\nBEGIN\nput: RET 0\nEND\n")).unwrap();
+        fs::write(path("b.bdc"), object(".global put\nBEGIN\nput: HALT\nEND\n")).unwrap();
+        fs::write(path("c.bdc"), object(".weak put\nBEGIN\nput: HALT\nEND\n")).unwrap();
+
+        assert_eq!(archive(vec![&path("a.bdc"), &path("b.bdc")], &path("std.lib")).unwrap(), (false, "Label put exported by both a.bdc and b.bdc".to_owned()));
+        assert_eq!(archive(vec![&path("a.bdc"), &path("a.bdc")], &path("std.lib")).unwrap(), (false, format!("Found repeated member a.bdc in {}", path("std.lib"))));
+        assert_eq!(archive(vec![&path("a.bdc"), &path("c.bdc")], &path("std.lib")).unwrap(), (true, format!("Archived 2 modules into {}", path("std.lib"))));
+        assert_eq!(Library::read(&fs::read(path("std.lib")).unwrap(), "std.lib").unwrap().members.len(), 2);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,324 +1,1309 @@
-use super::assembler::{OpCodes, PseudoOps};
-use pyo3::prelude::*;
-use std::{collections::HashMap, fs, str::FromStr};
+use super::{
+    assembler::OpCodes,
+    deadcode::eliminate_dead_code,
+    executable::{self, Executable, Segment, RELOCATE_CODE, RELOCATE_DATA},
+    expression::Expr,
+    layout::{is_module, Layout, Region, CODE_PAGE, DATA_PAGE},
+    object::{text_words, DataValue, Instruction, ObjectModule, SymbolKind},
+    library::{self, Library},
+    objfile::read_object,
+    xref::{Definition, Reference},
+};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
 
-#[pyfunction]
-pub fn link(breadcrumbs: Vec<&str>, out: Option<&str>) -> PyResult<(bool, String)> {
-    let mut offset: u32 = 0;
+// A shared module given to the linker, the routines it exports are bound at load time
+pub struct Shared {
+    pub name: String,
+    pub exports: Vec<(String, u32)>,
+}
 
-    let mut labels = HashMap::new();
-    let mut extern_labels = Vec::new();
+// Where a module ended up in the code and data regions and every label it defines, exported or not
+struct Placement<'a> {
+    bdc: &'a str,
+    code: u32,
+    data: u32,
+    // Region the code went in, the code region or the one of its overlay
+    code_region: &'a Region,
+    overlay: Option<usize>,
+    labels: HashMap<&'a str, u32>,
+    ranges: Vec<(&'static str, u32, u32)>,
+}
 
-    let mut buf = Vec::new();
+struct Linkage<'a> {
+    modules: Vec<Placement<'a>>,
+    globals: HashMap<&'a str, (u32, usize)>,
+    // Weak labels replaced by another module's definition, as (module, label)
+    overridden: HashSet<(usize, &'a str)>,
+    // Labels in overlays reached through a stub in the root code, as (module, label)
+    stubs: Vec<(usize, &'a str)>,
+    stub_start: u32,
+    // Labels taken from shared modules, as (shared module, label), and their jump table
+    imports: Vec<(&'a str, &'a str)>,
+    import_start: u32,
+    code: &'a Region,
+    data: &'a Region,
+    layout: &'a Layout,
+}
 
-    match breadcrumbs.iter().try_for_each(|bdc| {
-        let s = match fs::read_to_string(bdc) {
-            Err(why) => return Err(why.to_string()),
+impl<'a> Linkage<'a> {
+    // Offsets in the regions of sections placed with .org, which fixed_ranges checked fall in them
+    fn data_origin(&self, origin: u32) -> u32 {
+        (DATA_PAGE | origin) - self.data.start
+    }
 
-            Ok(s) => s,
-        };
+    fn code_origin(&self, origin: u32) -> u32 {
+        (CODE_PAGE | origin) - self.code.start
+    }
+
+    // A module sees its own labels first, unless they are weak and were overridden, then
+    // whatever other modules exported.
+    // `.code` and `.data` stand for the start of the module's own sections.
+    fn lookup(&self, module: usize, name: &str) -> Option<u32> {
+        let placement = &self.modules[module];
+        match name {
+            ".code" => Some(placement.code_region.start + placement.code),
+            ".data" => Some(self.data.start + placement.data),
+            _ if self.stub(module, name).is_some() => self.stub(module, name),
+            _ => match placement.labels.get(name) {
+                Some(v) if !self.overridden.contains(&(module, name)) => Some(*v),
+                _ => self.globals.get(name).map(|(v, _)| *v).or_else(|| self.import(name)),
+            },
+        }
+    }
+
+    // Jump table slot of a label imported from a shared module
+    fn import(&self, name: &str) -> Option<u32> {
+        self.imports.iter().position(|(_, other)| *other == name).map(|index| self.import_start + index as u32)
+    }
+
+    // Stub `module` jumps to for a label in an overlay that isn't loaded along with it
+    fn stub(&self, module: usize, name: &str) -> Option<u32> {
+        let owner = self.definer(module, name)?;
+        let target = self.modules[owner].overlay?;
+
+        match self.layout.encloses(Some(target), self.modules[module].overlay) {
+            true => None,
+            false => self.stubs.iter().position(|stub| *stub == (owner, name)).map(|index| self.stub_start + index as u32),
+        }
+    }
+
+    // Module whose definition `name` resolves to when used in `module`
+    fn definer(&self, module: usize, name: &str) -> Option<usize> {
+        match self.modules[module].labels.contains_key(name) && !self.overridden.contains(&(module, name)) {
+            true => Some(module),
+            false => self.globals.get(name).map(|(_, owner)| *owner),
+        }
+    }
+
+    fn private_owner(&self, name: &str) -> Option<&str> {
+        self.modules.iter().find(|p| p.labels.contains_key(name)).map(|p| p.bdc)
+    }
+
+    fn resolve(&self, module: usize, expr: &Expr, line: usize) -> Result<u32, String> {
+        let bdc = self.modules[module].bdc;
+
+        if let Some(label) = expr.symbols().into_iter().find(|s| self.lookup(module, s).is_none()) {
+            return Err(match self.private_owner(label) {
+                Some(owner) => format!("Label {} used at line {} in {} is private to {}", label, line + 1, bdc, owner),
+                None => format!("Label {} used at line {} in {} not defined in object files", label, line + 1, bdc),
+            });
+        }
+
+        // Only the stub's or slot's address is known, not where the label really is
+        if !matches!(expr, Expr::Symbol(_)) {
+            if let Some(label) = expr.symbols().into_iter().find(|s| self.stub(module, s).is_some()) {
+                return Err(format!("Label {} used at line {} in {} is in an overlay and can only be used on its own", label, line + 1, bdc));
+            }
+
+            if let Some(label) = expr.symbols().into_iter().find(|s| self.import(s).is_some_and(|slot| self.lookup(module, s) == Some(slot))) {
+                return Err(format!("Label {} used at line {} in {} is in a shared module and can only be used on its own", label, line + 1, bdc));
+            }
+        }
+
+        match expr.evaluate(&|name| self.lookup(module, name)) {
+            Err(why) => Err(format!("{} at line {} in {}", why, line + 1, bdc)),
+
+            Ok(val) => Ok(val),
+        }
+    }
 
-        let mut lines = s.lines().enumerate();
+    // Whether a resolved value moves with the code or the data when the program is loaded
+    // somewhere else. Values mixing labels in any other way can't be patched by the loader.
+    fn relocation(&self, module: usize, expr: &Expr, value: u32, line: usize, warnings: &mut Vec<String>) -> Option<u8> {
+        const SHIFT: u32 = 0x1000;
 
-        let header_len = match lines.next() {
-            None => return Err(format!("{} is empty", bdc)),
+        let shifted = |code: u32, data: u32| {
+            expr.evaluate(&|name| {
+                self.lookup(module, name).map(|v| match self.data.contains(v) {
+                    true => v.wrapping_add(data),
+                    false => v.wrapping_add(code),
+                })
+            })
+            .map(|v| v.wrapping_sub(value))
+        };
 
-            Some((_, line)) => match line.trim().parse::<usize>() {
-                Err(_) => {
-                    return Err(format!(
-                        "Expected integer at first line in {}\n\tfound {} instead",
-                        bdc, line
-                    ));
+        match (shifted(SHIFT, 0), shifted(0, SHIFT)) {
+            (Ok(0), Ok(0)) => None,
+            (Ok(SHIFT), Ok(0)) => Some(RELOCATE_CODE),
+            (Ok(0), Ok(SHIFT)) => Some(RELOCATE_DATA),
+            _ => {
+                let warning = format!("Value at line {} in {} can't be relocated", line + 1, self.modules[module].bdc);
+                if !warnings.contains(&warning) {
+                    warnings.push(warning);
                 }
+                None
+            }
+        }
+    }
 
-                Ok(n) => n,
+    // Kind of label `name` resolves to in `module`, externs take the kind their definition has
+    fn kind(&self, modules: &[&ObjectModule], module: usize, name: &str) -> Option<SymbolKind> {
+        match name {
+            ".code" => Some(SymbolKind::Code),
+            ".data" => Some(SymbolKind::Data),
+            _ if self.stub(module, name).is_some() => Some(SymbolKind::Code),
+            _ => match self.definer(module, name) {
+                Some(owner) => modules[owner].symbol_kind(name),
+
+                // Routines of shared modules are reached through the jump table
+                None => self.import(name).map(|_| SymbolKind::Code),
             },
+        }
+    }
+
+    // Operands pointing at the wrong kind of label are reported along with where the label came from.
+    // Values the loader can't patch still point where their labels do when they all agree.
+    fn check_kind(&self, modules: &[&ObjectModule], module: usize, instr: &Instruction, relocation: Option<u8>) -> Result<(), String> {
+        let symbols = instr.operand.iter().flat_map(|expr| expr.symbols()).collect::<Vec<&str>>();
+        let kinds: Vec<Option<SymbolKind>> = symbols.iter().map(|name| self.kind(modules, module, name)).collect();
+
+        let found = match (relocation, kinds.first()) {
+            (Some(RELOCATE_CODE), _) => SymbolKind::Code,
+
+            (Some(_), _) => SymbolKind::Data,
+
+            (None, Some(Some(kind))) if kinds.iter().all(|other| other == &Some(*kind)) => *kind,
+
+            _ => return Ok(()),
         };
 
-        lines.by_ref().take(header_len).try_for_each(|(i, line)| {
-            if let Some((label, data)) = line.split_once(':') {
-                if let Some(text) = data.strip_suffix('\"') {
-                    if labels.keys().any(|k| k == label) {
-                        return Err(format!(
-                            "Found label redefinition in {} at line {}\n\t{}",
-                            bdc,
-                            i + 1,
-                            label
-                        ));
-                    }
+        match instr.expects() {
+            Some(expected) if expected != found => {
+                let mut why = format!(
+                    "{} at line {} in {} expects a {} label",
+                    instr.mnemonic(),
+                    instr.line + 1,
+                    modules[module].name,
+                    expected.name()
+                );
 
-                    if labels
-                        .insert(
-                            label.to_owned(),
-                            1 << 16
-                                | match u32::try_from(buf.len() >> 2) {
-                                    Err(_) => return Err("File too big!".to_owned()),
-
-                                    Ok(v) => {
-                                        if v.leading_zeros() < 16 {
-                                            return Err("File too big!".to_owned());
-                                        }
-
-                                        v
-                                    }
-                                },
-                        )
-                        .is_some()
-                    {
-                        return Err(format!(
-                            "Found label redefinition in {} at line {}\n\t{}",
-                            bdc,
-                            i + 1,
-                            label
-                        ));
+                // The label that gave the operand its kind
+                let label = symbols.iter().zip(&kinds).find(|(_, kind)| **kind == Some(found)).map(|(name, _)| *name);
+
+                if let Some(label) = label {
+                    let owner = self.definer(module, label);
+                    match (label, owner.and_then(|owner| modules[owner].definition_line(label))) {
+                        (".code" | ".data", _) => why.push_str(format!("\n\tfound {} section of {}", label, modules[module].name).as_str()),
+
+                        (_, Some(line)) => why.push_str(
+                            format!("\n\tfound {} label {} defined at line {} in {}", found.name(), label, line + 1, modules[owner.unwrap_or(module)].name).as_str(),
+                        ),
+
+                        (_, None) => why.push_str(format!("\n\tfound {} label {}", found.name(), label).as_str()),
                     }
+                }
+
+                Err(why)
+            }
+
+            _ => Ok(()),
+        }
+    }
+}
+
+// First address from `start` where `size` words fit between the fixed sections
+fn allocate(start: usize, size: usize, fixed: &[(usize, usize, usize)]) -> usize {
+    let mut start = start;
+    while let Some((_, end, _)) = fixed.iter().find(|(from, to, _)| start < *to && *from < start + size) {
+        start = *end;
+    }
+    start
+}
+
+// Ranges taken by .org sections, given as (module, origin, size), with the module that placed them.
+// Origins are addresses in the page, like the assembler gives the labels after them, so each
+// range counts from the start of the region.
+fn fixed_ranges(modules: &[&ObjectModule], kind: &str, region: &Region, page: u32, sections: Vec<(usize, u32, usize)>) -> Result<Vec<(usize, usize, usize)>, String> {
+    let mut fixed: Vec<(usize, usize, usize)> = Vec::new();
+
+    for (index, origin, size) in sections {
+        let address = page | origin;
+        if address < region.start {
+            return Err(format!(
+                "{} section at .org {} in {} is outside region {}: it starts at {}",
+                kind, origin, modules[index].name, region.name, region.start
+            ));
+        }
+
+        let from = (address - region.start) as usize;
+        let to = from + size;
+        if to > region.size as usize {
+            return Err(format!(
+                "{} section at .org {} in {} overflows region {}: ends at word {} of {}",
+                kind, origin, modules[index].name, region.name, to, region.size
+            ));
+        }
+
+        if let Some((_, _, other)) = fixed.iter().find(|(start, end, _)| from < *end && *start < to) {
+            return Err(format!("{} section at .org {} in {} overlaps another in {}", kind, origin, modules[index].name, modules[*other].name));
+        }
+
+        fixed.push((from, to, index));
+    }
+
+    Ok(fixed)
+}
+
+// Labels the modules use or declare EXTERN that none of them exports
+fn undefined(modules: &[ObjectModule]) -> HashSet<&str> {
+    let exported: HashSet<&str> = modules.iter().flat_map(|module| module.exports.iter().map(|name| name.as_str())).collect();
+
+    modules
+        .iter()
+        .flat_map(|module| {
+            module
+                .externs
+                .iter()
+                .map(|name| name.as_str())
+                .chain(module.references().into_iter().map(|(name, _)| name))
+                .chain(module.entry.as_deref())
+                .filter(move |name| !module.defines(name))
+        })
+        .filter(|name| !exported.contains(name))
+        .collect()
+}
+
+// Objects are always linked. Like a classic linkage editor, a library only adds the members
+// exporting a label still undefined when it is reached, and is searched again until it adds
+// nothing; libraries are never searched again for files after them.
+fn select_members(inputs: Vec<(Vec<ObjectModule>, bool)>) -> Vec<ObjectModule> {
+    let mut modules = Vec::new();
+
+    for (members, is_library) in inputs {
+        if !is_library {
+            modules.extend(members);
+            continue;
+        }
+
+        let mut members: Vec<Option<ObjectModule>> = members.into_iter().map(Some).collect();
+        loop {
+            let needed: HashSet<String> = undefined(&modules).into_iter().map(|name| name.to_owned()).collect();
+            let pulled: Vec<ObjectModule> = members
+                .iter_mut()
+                .filter(|member| member.as_ref().is_some_and(|module| module.exports.iter().any(|name| needed.contains(name))))
+                .filter_map(|member| member.take())
+                .collect();
+
+            if pulled.is_empty() {
+                break;
+            }
+            modules.extend(pulled);
+        }
+    }
 
-                    buf.extend(text.bytes());
-                    buf.push(0);
-                    while buf.len() & 3 != 0 {
-                        buf.push(0);
+    modules
+}
+
+// Object files and the library members they need, in link order
+pub fn read_inputs(breadcrumbs: &[&str]) -> Result<(Vec<ObjectModule>, Vec<Shared>), String> {
+    let mut inputs = Vec::new();
+    let mut shared = Vec::new();
+
+    for bdc in breadcrumbs {
+        let bytes = match fs::read(bdc) {
+            Err(why) => return Err(why.to_string()),
+
+            Ok(bytes) => bytes,
+        };
+
+        // Shared modules are found by their file name when loaded
+        if bytes.starts_with(executable::MAGIC) {
+            let executable = Executable::from_bytes(&bytes, bdc)?;
+            if executable.exports.is_empty() {
+                return Err(format!("{} is not a shared module, it exports nothing", bdc));
+            }
+
+            shared.push(Shared {
+                name: Path::new(bdc).file_name().map_or(bdc.to_string(), |name| name.to_string_lossy().into_owned()),
+                exports: executable.exports,
+            });
+            continue;
+        }
+
+        inputs.push(match bytes.starts_with(library::MAGIC) {
+            true => (Library::read(&bytes, bdc)?.modules(bdc)?, true),
+            false => (vec![read_object(&bytes, bdc)?], false),
+        });
+    }
+
+    Ok((select_members(inputs), shared))
+}
+
+pub fn link_objects(modules: &[&ObjectModule], layout: &Layout) -> Result<Executable, String> {
+    link_placed(modules, &[], layout, false).map(|(executable, _)| executable)
+}
+
+// Links the modules and keeps where each of them was placed, for the map file
+fn link_placed<'a>(modules: &[&'a ObjectModule], shared: &'a [Shared], layout: &'a Layout, export: bool) -> Result<(Executable, Linkage<'a>), String> {
+    let mut linkage = Linkage {
+        modules: Vec::new(),
+        globals: HashMap::new(),
+        overridden: HashSet::new(),
+        stubs: Vec::new(),
+        stub_start: 0,
+        imports: Vec::new(),
+        import_start: 0,
+        code: layout.code(),
+        data: layout.data(),
+        layout,
+    };
+
+    let overlay_of: Vec<Option<usize>> = modules.iter().map(|module| layout.overlay(&module.name)).collect();
+    for (module, overlay) in modules.iter().zip(&overlay_of) {
+        if let Some(overlay) = overlay {
+            if module.code.iter().any(|section| section.origin.is_some()) {
+                return Err(format!("Code placed with .org in {} can't go in overlay {}", module.name, layout.overlays[*overlay].name));
+            }
+        }
+    }
+
+    let fixed_data = fixed_ranges(
+        modules,
+        "Data",
+        linkage.data,
+        DATA_PAGE,
+        modules
+            .iter()
+            .enumerate()
+            .flat_map(|(index, module)| module.data.iter().filter_map(move |section| section.origin.map(|origin| (index, origin, section.size()))))
+            .collect(),
+    )?;
+    let fixed_code = fixed_ranges(
+        modules,
+        "Code",
+        linkage.code,
+        CODE_PAGE,
+        modules
+            .iter()
+            .enumerate()
+            .flat_map(|(index, module)| module.code.iter().filter_map(move |section| section.origin.map(|origin| (index, origin, section.instructions.len()))))
+            .collect(),
+    )?;
+
+    let mut data_offset = 0;
+    let mut code_offset = 0;
+    let mut segment_sizes = vec![0; layout.overlays.len()];
+
+    // A module's relocatable sections of each kind are kept together, so `.data` and
+    // `.code` plus an offset still point into the right place
+    for (index, module) in modules.iter().enumerate() {
+        let data_size = module.data.iter().filter(|section| section.origin.is_none()).map(|section| section.size()).sum();
+        let code_size = module.code.iter().filter(|section| section.origin.is_none()).map(|section| section.instructions.len()).sum();
+
+        // Every overlay starts at the beginning of its region
+        let overlay = overlay_of[index];
+        data_offset = allocate(data_offset, data_size, &fixed_data);
+        let (code_region, code_start) = match overlay {
+            None => {
+                code_offset = allocate(code_offset, code_size, &fixed_code);
+                (linkage.code, code_offset)
+            }
+
+            Some(overlay) => (&layout.regions[layout.overlays[overlay].region], segment_sizes[overlay]),
+        };
+
+        for (kind, region, end) in [("Data", linkage.data, data_offset + data_size), ("Code", code_region, code_start + code_size)] {
+            if end > region.size as usize {
+                return Err(format!("{} of {} overflows region {}: ends at word {} of {}", kind, module.name, region.name, end, region.size));
+            }
+        }
+
+        let mut placement = Placement {
+            bdc: module.name.as_str(),
+            code: code_start as u32,
+            data: data_offset as u32,
+            code_region,
+            overlay,
+            labels: HashMap::new(),
+            ranges: Vec::new(),
+        };
+
+        let mut relocated = placement.data;
+        for section in &module.data {
+            let base = match section.origin {
+                None => relocated,
+                Some(origin) => linkage.data_origin(origin),
+            };
+            let mut offset = base;
+
+            for entry in &section.entries {
+                if !entry.label.is_empty() {
+                    placement.labels.insert(entry.label.as_str(), linkage.data.start + offset);
+                }
+
+                offset += entry.size() as u32;
+            }
+
+            if section.size() != 0 {
+                placement.ranges.push(("data", linkage.data.start + base, section.size() as u32));
+            }
+
+            if section.origin.is_none() {
+                relocated = offset;
+            }
+        }
+
+        let mut relocated = placement.code;
+        for section in &module.code {
+            let base = match section.origin {
+                None => relocated,
+                Some(origin) => linkage.code_origin(origin),
+            };
+
+            for (label, value) in &section.labels {
+                placement.labels.insert(label.as_str(), code_region.start + base + value);
+            }
+
+            if !section.instructions.is_empty() {
+                placement.ranges.push(("code", code_region.start + base, section.instructions.len() as u32));
+            }
+
+            if section.origin.is_none() {
+                relocated += section.instructions.len() as u32;
+            }
+        }
+
+        // A strong definition replaces a weak one, and the first weak one wins over later ones
+        for label in &module.exports {
+            let label = label.as_str();
+            let value = match placement.labels.get(label) {
+                None => return Err(format!("Exported label {} is not defined in {}", label, module.name)),
+
+                Some(value) => *value,
+            };
+
+            match linkage.globals.get(label).map(|(_, other)| *other) {
+                None => {
+                    linkage.globals.insert(label, (value, index));
+                }
+
+                Some(other) => match (modules[other].is_weak(label), module.is_weak(label)) {
+                    (true, false) => {
+                        linkage.overridden.insert((other, label));
+                        linkage.globals.insert(label, (value, index));
                     }
-                } else {
-                    if labels.keys().any(|k| k == label) {
-                        return Err(format!(
-                            "Found label redefinition in {} at line {}\n\t{}",
-                            bdc,
-                            i + 1,
-                            label
-                        ));
+
+                    (_, true) => {
+                        linkage.overridden.insert((index, label));
                     }
 
-                    if labels
-                        .insert(
-                            label.to_owned(),
-                            1 << 16
-                                | match u32::try_from(buf.len() >> 2) {
-                                    Err(_) => return Err("File too big!".to_owned()),
-
-                                    Ok(v) => {
-                                        if v.leading_zeros() < 16 {
-                                            return Err("File too big!".to_owned());
-                                        }
-
-                                        v
-                                    }
-                                },
-                        )
-                        .is_some()
-                    {
+                    (false, false) => {
                         return Err(format!(
-                            "Found label redefinition in {} at line {}\n\t{}",
-                            bdc,
-                            i + 1,
-                            label
+                            "Found label redefinition in {}\n\t{} already exported by {}",
+                            module.name, label, modules[other].name
                         ));
                     }
+                },
+            }
+        }
 
-                    data.split(',').try_for_each(|word| {
-                        match word.parse::<u32>() {
-                            Err(_) => {
-                                return Err(format!(
-                                    "Expected integer as argument at line {}\n\tfound {} instead",
-                                    i + 1,
-                                    word
-                                ))
-                            }
-
-                            Ok(val) => buf.extend(val.to_le_bytes().into_iter()),
-                        }
-                        Ok(())
-                    })?;
-                }
-                Ok(())
-            } else if let Some((label, line_number)) = line.split_once(' ') {
-                if labels.keys().any(|k| k == label) {
-                    return Err(format!(
-                        "Found label redefinition in {} at line {}\n\t{}",
-                        bdc,
-                        i + 1,
-                        label
-                    ));
-                }
+        linkage.modules.push(placement);
+        data_offset += data_size;
+        match overlay {
+            None => code_offset += code_size,
+            Some(overlay) => segment_sizes[overlay] += code_size,
+        }
+    }
 
-                match line_number.parse::<u32>() {
-                    Err(_) => {
-                        return Err(format!(
-                            "Expected integer at line {} in {}, found {} instead",
-                            i + 1,
-                            bdc,
-                            line_number
-                        ))
+    // Labels no module defines are taken from the first shared module exporting them
+    for (index, module) in modules.iter().enumerate() {
+        let names = module.externs.iter().map(|name| name.as_str()).chain(module.references().into_iter().map(|(name, _)| name));
+
+        for name in names {
+            if linkage.lookup(index, name).is_some() {
+                continue;
+            }
+
+            if let Some(library) = shared.iter().find(|library| library.exports.iter().any(|(export, _)| export == name)) {
+                linkage.imports.push((library.name.as_str(), name));
+            }
+        }
+    }
+
+    for module in modules {
+        for ext in &module.externs {
+            if !linkage.globals.contains_key(ext.as_str()) && linkage.import(ext).is_none() {
+                return Err(match linkage.private_owner(ext) {
+                    Some(owner) => format!("EXTERN label {} in {} resolves to private symbol in {}", ext, module.name, owner),
+                    None => format!("EXTERN label {} not defined in object files", ext),
+                });
+            }
+        }
+    }
+
+    for overlay in &layout.overlays {
+        for file in &overlay.modules {
+            if !modules.iter().any(|module| is_module(&module.name, file)) {
+                return Err(format!("Module {} of overlay {} not among the linked files", file, overlay.name));
+            }
+        }
+    }
+
+    // A label in an overlay is reached through a stub in the root code that loads the overlay
+    // first, unless the module using it runs with that overlay already loaded
+    for (index, module) in modules.iter().enumerate() {
+        let user = overlay_of[index];
+
+        for (name, line) in module.references() {
+            let owner = match linkage.definer(index, name) {
+                None => continue,
+
+                Some(owner) => owner,
+            };
+            let target = match overlay_of[owner] {
+                Some(target) if !layout.encloses(Some(target), user) => target,
+                _ => continue,
+            };
+
+            if !layout.encloses(user, Some(target)) {
+                return Err(format!(
+                    "Label {} used at line {} in {} is in overlay {}, which can't be loaded from {}",
+                    name,
+                    line + 1,
+                    module.name,
+                    layout.overlays[target].name,
+                    user.map_or("the root", |user| layout.overlays[user].name.as_str())
+                ));
+            }
+
+            if !linkage.stubs.contains(&(owner, name)) {
+                linkage.stubs.push((owner, name));
+            }
+        }
+    }
+
+    let stub_offset = allocate(code_offset, linkage.stubs.len(), &fixed_code);
+    if stub_offset + linkage.stubs.len() > linkage.code.size as usize {
+        return Err(format!(
+            "Overlay stubs overflow region {}: end at word {} of {}",
+            linkage.code.name,
+            stub_offset + linkage.stubs.len(),
+            linkage.code.size
+        ));
+    }
+    linkage.stub_start = linkage.code.start + stub_offset as u32;
+    if !linkage.stubs.is_empty() {
+        code_offset = stub_offset + linkage.stubs.len();
+    }
+
+    let import_offset = allocate(code_offset, linkage.imports.len(), &fixed_code);
+    if import_offset + linkage.imports.len() > linkage.code.size as usize {
+        return Err(format!(
+            "Import table overflows region {}: ends at word {} of {}",
+            linkage.code.name,
+            import_offset + linkage.imports.len(),
+            linkage.code.size
+        ));
+    }
+    linkage.import_start = linkage.code.start + import_offset as u32;
+    if !linkage.imports.is_empty() {
+        code_offset = import_offset + linkage.imports.len();
+    }
+
+    // A shared module's exports are there for programs linked later
+    let mut warnings = Vec::new();
+    for (index, module) in modules.iter().enumerate().filter(|_| !export) {
+        for label in module.exports.iter().filter(|label| !module.is_weak(label)) {
+            let used = modules.iter().enumerate().any(|(other, m)| {
+                other != index
+                    && (m.externs.contains(label) || m.references().iter().any(|(name, _)| name == label))
+                    && linkage.definer(other, label) == Some(index)
+            });
+
+            if !used {
+                warnings.push(format!("Exported label {} in {} is never used by other modules", label, module.name));
+            }
+        }
+    }
+
+    // Gaps left between sections are filled with zeros
+    let data_end = fixed_data.iter().map(|(_, end, _)| *end).fold(data_offset, usize::max);
+    let code_end = fixed_code.iter().map(|(_, end, _)| *end).fold(code_offset, usize::max);
+    let mut data = vec![0; data_end];
+    let mut code = vec![0; code_end];
+    let mut segments: Vec<Vec<u32>> = segment_sizes.iter().map(|size| vec![0; *size]).collect();
+    let mut lines = Vec::new();
+    let mut relocations = Vec::new();
+
+    for (index, module) in modules.iter().enumerate() {
+        let mut relocated = linkage.modules[index].data as usize;
+        for section in &module.data {
+            let mut offset = match section.origin {
+                None => relocated,
+                Some(origin) => linkage.data_origin(origin) as usize,
+            };
+
+            for entry in &section.entries {
+                let exprs = match &entry.value {
+                    DataValue::Words(words) => words.iter().collect(),
+                    DataValue::Fill(count, value) => vec![value; *count as usize],
+                    DataValue::Text(text) => {
+                        let words = text_words(text);
+                        data[offset..offset + words.len()].copy_from_slice(&words);
+                        offset += words.len();
+                        continue;
                     }
+                };
 
-                    Ok(val) => {
-                        if labels.insert(label.to_owned(), val + offset).is_some() {
-                            return Err(format!(
-                                "Found label redefinition in {} at line {}\n\t{}",
-                                bdc,
-                                i + 1,
-                                label
-                            ));
-                        }
+                for expr in exprs {
+                    data[offset] = linkage.resolve(index, expr, entry.line)?;
+                    if let Some(kind) = linkage.relocation(index, expr, data[offset], entry.line, &mut warnings) {
+                        relocations.push((linkage.data.start + offset as u32, kind));
                     }
+                    offset += 1;
                 }
-                Ok(())
-            } else {
-                if !extern_labels.iter().any(|e| e == line) {
-                    extern_labels.push(line.to_owned());
-                }
-                Ok(())
             }
-        })?;
 
-        offset += match u32::try_from(lines.count()) {
-            Err(_) => return Err("File too big!".to_owned()),
+            if section.origin.is_none() {
+                relocated = offset;
+            }
+        }
+
+        let placement = &linkage.modules[index];
+        let words = match placement.overlay {
+            None => &mut code,
+            Some(overlay) => &mut segments[overlay],
+        };
+
+        let mut relocated = placement.code as usize;
+        for section in &module.code {
+            let mut offset = match section.origin {
+                None => relocated,
+                Some(origin) => linkage.code_origin(origin) as usize,
+            };
+
+            for instr in &section.instructions {
+                let value = match &instr.operand {
+                    None => 0,
+
+                    Some(operand) => {
+                        let value = linkage.resolve(index, operand, instr.line)?;
+                        let relocation = linkage.relocation(index, operand, value, instr.line, &mut warnings);
+                        linkage.check_kind(modules, index, instr, relocation)?;
+
+                        if let Some(kind) = relocation {
+                            relocations.push((placement.code_region.start + offset as u32, kind));
+                        }
+                        value
+                    }
+                };
+
+                match instr.encode(value) {
+                    Err(why) => return Err(format!("{} in {}", why, module.name)),
 
-            Ok(v) => {
-                if v.leading_zeros() < 16 {
-                    return Err("File too big!".to_owned());
+                    Ok(word) => words[offset] = word,
                 }
+                lines.push((placement.code_region.start + offset as u32, index as u16, instr.line as u32));
+                offset += 1;
+            }
 
-                v
+            if section.origin.is_none() {
+                relocated = offset;
             }
+        }
+    }
+
+    for (index, (owner, name)) in linkage.stubs.iter().enumerate() {
+        code[stub_offset + index] = (OpCodes::JMP as u32) << 18 | linkage.modules[*owner].labels[name];
+    }
+
+    // The loader points every slot at the shared module's routine
+    for index in 0..linkage.imports.len() {
+        code[import_offset + index] = (OpCodes::JMP as u32) << 18;
+    }
+
+    // Only one module may say where the program starts
+    let mut entry = linkage.code.start;
+    let mut declared: Option<&str> = None;
+    for (index, module) in modules.iter().enumerate() {
+        let name = match &module.entry {
+            None => continue,
+
+            Some(name) => name,
         };
 
-        Ok(())
-    }) {
-        Ok(()) => (),
-        Err(why) => return Ok((false, why)),
+        if let Some(first) = declared {
+            return Err(format!("Entry point declared in both {} and {}", first, module.name));
+        }
+        declared = Some(module.name.as_str());
+
+        if let Some(overlay) = linkage.definer(index, name).and_then(|owner| overlay_of[owner]) {
+            return Err(format!("Entry label {} in {} is in overlay {}", name, module.name, layout.overlays[overlay].name));
+        }
+
+        entry = match linkage.lookup(index, name) {
+            None => return Err(format!("Entry label {} in {} not defined in object files", name, module.name)),
+
+            Some(value) if !linkage.code.contains(value) => return Err(format!("Entry label {} in {} is not a code label", name, module.name)),
+
+            Some(value) => value,
+        };
     }
 
-    match extern_labels.into_iter().try_for_each(|ext| {
-        if !labels.contains_key(ext.as_str()) {
-            return Err(format!("EXTERN label {} not defined in object files", ext));
+    let mut symbols: Vec<(String, u32)> = linkage
+        .modules
+        .iter()
+        .enumerate()
+        .flat_map(|(index, placement)| placement.labels.iter().map(move |(name, value)| (index, *name, *value)))
+        .filter(|(index, name, _)| !linkage.overridden.contains(&(*index, *name)))
+        .map(|(_, name, value)| (name.to_owned(), value))
+        .collect();
+    symbols.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+
+    let mut executable = Executable {
+        entry,
+        data_address: linkage.data.start,
+        data,
+        code_address: linkage.code.start,
+        code,
+        symbols,
+        files: modules.iter().map(|module| module.name.clone()).collect(),
+        lines,
+        // Overlays only run where they were linked, so the program can't be moved
+        relocations: match overlay_of.iter().any(Option::is_some) {
+            true => None,
+            false => Some(relocations),
+        },
+        overlays: match overlay_of.iter().any(Option::is_some) {
+            true => layout
+                .overlays
+                .iter()
+                .zip(segments)
+                .enumerate()
+                .map(|(index, (overlay, code))| Segment {
+                    name: overlay.name.clone(),
+                    parent: overlay.parent,
+                    address: layout.regions[overlay.region].start,
+                    stubs: (linkage.stub_start..).zip(&linkage.stubs).filter(|(_, (owner, _))| overlay_of[*owner] == Some(index)).map(|(stub, _)| stub).collect(),
+                    code,
+                })
+                .collect(),
+            false => Vec::new(),
+        },
+        exports: Vec::new(),
+        imports: linkage
+            .imports
+            .iter()
+            .zip(linkage.import_start..)
+            .map(|((shared, name), slot)| (shared.to_string(), name.to_string(), slot))
+            .collect(),
+        warnings,
+    };
+
+    // Programs call a shared module's routines through their jump table, its data can't be reached
+    if export {
+        if executable.relocations.is_none() {
+            return Err("A shared module can't use overlays".to_owned());
+        }
+
+        for (name, (value, owner)) in &linkage.globals {
+            match linkage.code.contains(*value) {
+                true => executable.exports.push((name.to_string(), *value)),
+                false => executable.warnings.push(format!("Data label {} in {} can't be shared", name, modules[*owner].name)),
+            }
+        }
+        executable.exports.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+
+        if executable.exports.is_empty() {
+            return Err("Shared module exports no routines".to_owned());
         }
-        Ok(())
-    }) {
-        Ok(()) => (),
-        Err(why) => return Ok((false, why)),
     }
 
-    for byte in match u32::try_from(buf.len() >> 2) {
-        Err(_) => return Ok((false, "File too big!".to_owned())),
+    Ok((executable, linkage))
+}
+
+// Every label the program ends up with, where it was defined and the words that use it
+pub fn cross_reference(modules: &[&ObjectModule], shared: &[Shared], layout: &Layout) -> Result<Vec<Definition>, String> {
+    let (_, linkage) = link_placed(modules, shared, layout, false)?;
+
+    // Paired with the module defining them, as modules from different paths may share a name
+    let mut definitions: Vec<(usize, Definition)> = linkage
+        .modules
+        .iter()
+        .enumerate()
+        .flat_map(|(index, placement)| placement.labels.iter().map(move |(name, value)| (index, *name, *value)))
+        .filter(|(index, name, _)| !linkage.overridden.contains(&(*index, *name)))
+        .map(|(index, name, address)| {
+            let definition = Definition {
+                name: name.to_owned(),
+                kind: match linkage.data.contains(address) {
+                    true => SymbolKind::Data,
+                    false => SymbolKind::Code,
+                },
+                address,
+                module: Some(modules[index].name.clone()),
+                line: modules[index].definition_line(name),
+                references: Vec::new(),
+            };
+            (index, definition)
+        })
+        .collect();
+    definitions.sort_by(|(_, a), (_, b)| (a.address, &a.name).cmp(&(b.address, &b.name)));
 
-        Ok(v) => {
-            if v.leading_zeros() < 16 {
-                return Ok((false, "File too big!".to_owned()));
+    for (index, module) in modules.iter().enumerate() {
+        // Words are walked in the same order link_placed lays them out
+        let mut uses: Vec<(&Expr, usize, u32)> = Vec::new();
+
+        let mut relocated = linkage.modules[index].data;
+        for section in &module.data {
+            let mut offset = section.origin.map_or(relocated, |origin| linkage.data_origin(origin));
+
+            for entry in &section.entries {
+                let address = linkage.data.start + offset;
+                match &entry.value {
+                    DataValue::Words(words) => uses.extend(words.iter().zip(address..).map(|(expr, address)| (expr, entry.line, address))),
+                    DataValue::Fill(_, value) => uses.push((value, entry.line, address)),
+                    DataValue::Text(_) => (),
+                }
+                offset += entry.size() as u32;
             }
 
-            v
+            if section.origin.is_none() {
+                relocated = offset;
+            }
+        }
+
+        let mut relocated = linkage.modules[index].code;
+        for section in &module.code {
+            let offset = section.origin.map_or(relocated, |origin| linkage.code_origin(origin));
+
+            for (instr, address) in section.instructions.iter().zip(linkage.modules[index].code_region.start + offset..) {
+                uses.extend(instr.operand.iter().map(|operand| (operand, instr.line, address)));
+            }
+
+            if section.origin.is_none() {
+                relocated = offset + section.instructions.len() as u32;
+            }
+        }
+
+        for (expr, line, address) in uses {
+            for name in expr.symbols() {
+                let owner = match linkage.definer(index, name) {
+                    None => continue,
+
+                    Some(owner) => owner,
+                };
+
+                let reference = Reference {
+                    module: Some(module.name.clone()),
+                    line: Some(line),
+                    address,
+                };
+                let definition = definitions.iter_mut().find(|(index, definition)| *index == owner && definition.name == name);
+                if let Some((_, definition)) = definition.filter(|(_, definition)| !definition.references.contains(&reference)) {
+                    definition.references.push(reference);
+                }
+            }
         }
     }
-    .to_be_bytes()
-    {
-        buf.insert(0, byte);
+
+    Ok(definitions.into_iter().map(|(_, definition)| definition).collect())
+}
+
+// Text report of where every module and symbol ended up and how much of each region is used
+fn link_map(modules: &[&ObjectModule], linkage: &Linkage, layout: &Layout, executable: &Executable, out: &str, removed: &[String]) -> String {
+    let mut buf = String::new();
+
+    buf.push_str(format!("Link map for {}\n", out).as_str());
+    buf.push_str(format!("entry point {:05X}\n", executable.entry).as_str());
+
+    buf.push_str("\nMODULES\n");
+    buf.push_str(format!(" {:24}  {:4}  {:5}  {:5}  {}\n", "NAME", "KIND", "START", "END", "SIZE").as_str());
+    for placement in &linkage.modules {
+        for (kind, start, size) in &placement.ranges {
+            buf.push_str(format!(" {:24}  {}  {:05X}  {:05X}  {:04X}\n", placement.bdc, kind, start, start + size - 1, size).as_str());
+        }
     }
 
-    match breadcrumbs.into_iter().try_for_each(|bdc| {
-        let s = match fs::read_to_string(bdc) {
-            Err(why) => return Err(why.to_string()),
+    let mut symbols: Vec<(&str, u32, usize)> = linkage
+        .modules
+        .iter()
+        .enumerate()
+        .flat_map(|(index, placement)| placement.labels.iter().map(move |(name, value)| (*name, *value, index)))
+        .collect();
+    symbols.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)));
 
-            Ok(s) => s,
+    buf.push_str("\nSYMBOLS\n");
+    buf.push_str(format!(" {:24}  {:7}  {:4}  {:10}  {:24}  {}\n", "NAME", "ADDRESS", "KIND", "BIND", "DEFINED IN", "REFERENCED BY").as_str());
+    for (name, value, index) in symbols {
+        let kind = match linkage.data.contains(value) {
+            true => "data",
+            false => "code",
+        };
+        let bind = match (linkage.overridden.contains(&(index, name)), modules[index].is_weak(name), modules[index].is_exported(name)) {
+            (true, _, _) => "overridden",
+            (false, true, _) => "weak",
+            (false, false, true) => "global",
+            (false, false, false) => "local",
         };
 
-        let mut lines = s.lines();
+        // Whoever resolves the name to this definition uses it
+        let users: Vec<&str> = modules
+            .iter()
+            .enumerate()
+            .filter(|(other, module)| module.references().iter().any(|(reference, _)| *reference == name) && linkage.definer(*other, name) == Some(index))
+            .map(|(_, module)| module.name.as_str())
+            .collect();
 
-        let header_len = match lines.next() {
-            None => return Err(format!("{} is empty", bdc)),
+        let line = format!(" {:24}  {:05X}    {}  {:10}  {:24}  {}", name, value, kind, bind, linkage.modules[index].bdc, users.join(", "));
+        buf.push_str(line.trim_end());
+        buf.push('\n');
+    }
 
-            Some(line) => match line.trim().parse::<usize>() {
-                Err(_) => {
-                    return Err(format!(
-                        "Expected integer at first line in {}\n\tfound {} instead",
-                        bdc, line
-                    ));
-                }
+    // Only code goes in overlays, data always stays loaded
+    let size = |overlay: Option<usize>, region: &Region| -> u32 {
+        linkage
+            .modules
+            .iter()
+            .flat_map(|placement| placement.ranges.iter().map(move |range| (placement.overlay.filter(|_| range.0 == "code"), range)))
+            .filter(|(owner, (_, start, _))| *owner == overlay && region.contains(*start))
+            .map(|(_, (_, _, size))| size)
+            .sum()
+    };
 
-                Ok(n) => n,
-            },
+    if !executable.overlays.is_empty() {
+        buf.push_str("\nOVERLAYS\n");
+        buf.push_str(format!(" {:16}  {:8}  {:16}  {:5}  {:4}  {}\n", "NAME", "REGION", "PARENT", "START", "SIZE", "STUBS").as_str());
+        for (overlay, segment) in layout.overlays.iter().zip(&executable.overlays) {
+            let parent = overlay.parent.map_or("root", |parent| layout.overlays[parent].name.as_str());
+            let stubs: Vec<String> = segment.stubs.iter().map(|stub| format!("{:05X}", stub)).collect();
+            let line = format!(
+                " {:16}  {:8}  {:16}  {:05X}  {:04X}  {}",
+                overlay.name,
+                layout.regions[overlay.region].name,
+                parent,
+                segment.address,
+                segment.code.len(),
+                stubs.join(", ")
+            );
+            buf.push_str(line.trim_end());
+            buf.push('\n');
+        }
+    }
+
+    if !executable.imports.is_empty() {
+        buf.push_str("\nIMPORTS\n");
+        buf.push_str(format!(" {:24}  {:24}  {}\n", "NAME", "SHARED MODULE", "SLOT").as_str());
+        for (shared, name, slot) in &executable.imports {
+            buf.push_str(format!(" {:24}  {:24}  {:05X}\n", name, shared, slot).as_str());
+        }
+    }
+
+    if !executable.exports.is_empty() {
+        buf.push_str("\nEXPORTS\n");
+        buf.push_str(format!(" {:24}  {}\n", "NAME", "ADDRESS").as_str());
+        for (name, address) in &executable.exports {
+            buf.push_str(format!(" {:24}  {:05X}\n", name, address).as_str());
+        }
+    }
+
+    // Overlays sharing a region only need room for the largest of them
+    buf.push_str("\nMEMORY USAGE\n");
+    buf.push_str(format!(" {:8}  {:5}  {:5}  {:5}  {}\n", "REGION", "START", "SIZE", "USED", "FREE").as_str());
+    for region in &layout.regions {
+        let overlaid = (0..layout.overlays.len()).map(|overlay| size(Some(overlay), region)).max().unwrap_or(0);
+        let stubs = match region.contains(linkage.stub_start) {
+            true => (linkage.stubs.len() + linkage.imports.len()) as u32,
+            false => 0,
         };
+        let used = size(None, region) + overlaid + stubs;
+        buf.push_str(format!(" {:8}  {:05X}  {:05X}  {:05X}  {:05X}\n", region.name, region.start, region.size, used, region.size.saturating_sub(used)).as_str());
+    }
 
-        lines.skip(header_len)
-            .enumerate()
-            .try_for_each(|(i, line)| {
-                let mut tokens = line.split_whitespace();
-                if let Some(token) = tokens.next() {
-                    if let Ok(_) = PseudoOps::from_str(token) {
-                        return Err(format!("Found non-parsed pseudoinstruction during linking at line {} in {}", i + 1, bdc));
-                    } else if let Ok(op) = OpCodes::from_str(token) {
-                        match tokens.next() {
-                            None => return Err(format!("Expected argument at line {} in {}", i + 1, bdc)),
-
-                            Some(arg) => match op {
-                                OpCodes::IRQ => match arg.parse::<u8>() {
-                                    Err(_) => return Err(format!("Expected integer at line {}\n\tfound {} instead", i + 1, arg)),
-
-                                    Ok(irq_type) => match tokens.next() {
-                                        Some(label) => match irq_type {
-                                            1..=2 => match labels.get(label) {
-                                                None => return Err(format!("Missing declaration for label {} used at line {} in {}", label, i + 1, bdc)),
-
-                                                Some(field) => {
-                                                    match field.leading_zeros() {
-                                                        0..=14 => return Err("File too big!".to_owned()),
-                                                        15..=16 => (),
-                                                        _ => return Err("Only data labels allowed for PRINT and READ instructions".to_owned()),
-                                                    }
-
-                                                    buf.extend((u32::from(irq_type) << 16 | (field & 0xFFFF)).to_le_bytes().into_iter());
-                                                }
-                                            }
-                                            3 => match u32::from_str_radix(label, 2) {
-                                                Err(_) => return Err(format!("Expected binary number as argument at line {}\n\tfound {} instead", i + 1, token)),
-
-                                                Ok(field) => {
-                                                    if field.leading_zeros() < 14 {
-                                                        return Err("File too big!".to_owned());
-                                                    }
-
-                                                    buf.extend((3 << 16 | field).to_le_bytes().into_iter());
-                                                }
-                                            }
-                                            0 | 4 => return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, label)),
-                                            _ => return Err(format!("Unknown IRQ type at line {}\n\t{}", i + 1, arg)),
-                                        }
-                                        None => match irq_type {
-                                            0 | 4 => buf.extend((u32::from(irq_type >> 2)).to_le_bytes().into_iter()),
-                                            1..=3 => return Err(format!("Expected label at line {}", i + 1)),
-                                            _ => return Err(format!("Unknown IRQ type at line {}\n\t{}", i + 1, irq_type)),
-                                        }
-                                    }
-                                }
-                                _ => match labels.get(arg) {
-                                    None => return Err(format!("Label {} used at line {} in {} not defined in object files", arg, i + 1, bdc)),
-
-                                    Some(field) => {
-                                        if field.leading_zeros() < 14 {
-                                            return Err("File too big!".to_owned());
-                                        }
-
-                                        buf.extend((u32::from(op as u8) << 18 | field).to_le_bytes().into_iter());
-                                    }
-                                }
-                            }
-                        }
-                        if let Some(token) = tokens.next() {
-                            return Err(format!("Unexpected argument at line {}\n\t{}", i + 1, token))
-                        }
-                    } else {
-                        return Err(format!("Expected instruction at line {} in {}\n\tfound {} instead", i + 1, bdc, token))
-                    }
-                }
-            Ok(())
-        })?;
-        Ok(())
-    }) {
-        Ok(()) => (),
+    if !removed.is_empty() {
+        buf.push_str("\nREMOVED\n");
+        for line in removed {
+            buf.push_str(format!(" {}\n", line).as_str());
+        }
+    }
+
+    buf
+}
+
+#[pyfunction]
+pub fn link_modules(modules: Vec<PyRef<ObjectModule>>, layout: Option<&str>) -> PyResult<Executable> {
+    let layout = match layout.map_or(Ok(Layout::default()), Layout::read) {
+        Err(why) => return Err(PyValueError::new_err(why)),
+
+        Ok(layout) => layout,
+    };
+
+    match link_objects(&modules.iter().map(|module| &**module).collect::<Vec<_>>(), &layout) {
+        Err(why) => Err(PyValueError::new_err(why)),
+        Ok(executable) => Ok(executable),
+    }
+}
+
+#[pyfunction]
+pub fn link(
+    breadcrumbs: Vec<&str>,
+    out: Option<&str>,
+    map: Option<&str>,
+    gc: Option<bool>,
+    layout: Option<&str>,
+    shared: Option<bool>,
+) -> PyResult<(bool, String)> {
+    let layout = match layout.map_or(Ok(Layout::default()), Layout::read) {
         Err(why) => return Ok((false, why)),
+
+        Ok(layout) => layout,
+    };
+
+    let (modules, libraries) = match read_inputs(&breadcrumbs) {
+        Err(why) => return Ok((false, why)),
+
+        Ok(inputs) => inputs,
+    };
+
+    let (modules, removed) = match gc.unwrap_or(false) {
+        true => eliminate_dead_code(modules),
+        false => (modules, Vec::new()),
+    };
+    let modules: Vec<&ObjectModule> = modules.iter().collect();
+    let (executable, linkage) = match link_placed(&modules, &libraries, &layout, shared.unwrap_or(false)) {
+        Err(why) => return Ok((false, why)),
+
+        Ok(linked) => linked,
+    };
+    let out = out.unwrap_or("a.fita");
+
+    if let Some(map) = map {
+        if let Err(why) = fs::write(map, link_map(&modules, &linkage, &layout, &executable, out, &removed)) {
+            return Ok((false, why.to_string()));
+        }
     }
 
-    match fs::write(out.unwrap_or("a.fita"), buf) {
-        Ok(_) => Ok((true, "Linking successful".to_owned())),
+    let mut message = "Linking successful".to_owned();
+    for line in &removed {
+        message.push('\n');
+        message.push_str(line.as_str());
+    }
+    for warning in &executable.warnings {
+        message.push_str("\nWarning: ");
+        message.push_str(warning.as_str());
+    }
+
+    match fs::write(out, executable.to_bytes()) {
+        Ok(_) => Ok((true, message)),
         Err(why) => Ok((false, why.to_string())),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::testing::{error, module};
+
+    #[test]
+    fn private_labels_stay_in_their_module() {
+        let a = module("a.bdc", "n: .word 1\n.global get\nBEGIN\nget: LDA n\n RET 0\nEND\n");
+        let b = module("b.bdc", "n: .word 2\nEXTERN get\nBEGIN\nmain: LDA n\n JAL get\nEND\n");
+        let executable = link_objects(&[&a, &b], &Layout::default()).unwrap();
+
+        assert_eq!(executable.data, [1, 2]);
+        assert_eq!(executable.code, [1 << 18 | 0x10000, 31 << 18, 1 << 18 | 0x10001, 18 << 18]);
+        assert!(executable.warnings.is_empty());
+    }
+
+    #[test]
+    fn externs_only_resolve_to_global_labels() {
+        let a = module("a.bdc", "n: .word 1\nBEGIN\nget: LDA n\nEND\n");
+        let b = module("b.bdc", "EXTERN n\nBEGIN\nmain: LDA n\nEND\n");
+        assert_eq!(error(link_objects(&[&a, &b], &Layout::default())), "EXTERN label n in b.bdc resolves to private symbol in a.bdc");
+
+        let a = module("a.bdc", "n: .word 1\n.global n, m\nm: .word 2\nBEGIN\nget: LDA n\nEND\n");
+        assert_eq!(link_objects(&[&a, &b], &Layout::default()).unwrap().warnings, ["Exported label m in a.bdc is never used by other modules"]);
+    }
+
+    #[test]
+    fn the_entry_point_is_a_code_label_one_module_declares() {
+        let a = module("a.bdc", "n: .word 1\n.global n, get\nBEGIN\nget: LDA n\n RET 0\nEND\n");
+        let b = module("b.bdc", "EXTERN get\nBEGIN main\n HALT\nmain: JAL get\n HALT\nEND\n");
+        assert_eq!(link_objects(&[&a, &b], &Layout::default()).unwrap().entry, 3);
+
+        let undefined = module("c.bdc", ".entry start\nBEGIN\nmain: HALT\nEND\n");
+        assert_eq!(error(link_objects(&[&a, &undefined], &Layout::default())), "Entry label start in c.bdc not defined in object files");
+
+        let data = module("c.bdc", "EXTERN n\n.entry n\nBEGIN\nmain: HALT\nEND\n");
+        assert_eq!(error(link_objects(&[&a, &data], &Layout::default())), "Entry label n in c.bdc is not a code label");
+
+        let other = module("c.bdc", "EXTERN get\n.entry get\nBEGIN\nmain: HALT\nEND\n");
+        assert_eq!(error(link_objects(&[&b, &other, &a], &Layout::default())), "Entry point declared in both b.bdc and c.bdc");
+    }
+
+    #[test]
+    fn libraries_only_add_the_members_still_needed() {
+        let names = |modules: &[ObjectModule]| modules.iter().map(|module| module.name.clone()).collect::<Vec<String>>();
+        let library = || {
+            vec![
+                module("std(fmt)", ".global fmt\nBEGIN\nfmt: RET 0\nEND\n"),
+                module("std(put)", "EXTERN fmt\n.global put\nBEGIN\nput: JAL fmt\n RET 0\nEND\n"),
+                module("std(get)", ".global get\nBEGIN\nget: RET 0\nEND\n"),
+            ]
+        };
+        let main = || module("main", "EXTERN put\nBEGIN main\nmain: JAL put\n HALT\nEND\n");
+        let late = || module("late", "EXTERN get\nBEGIN\nlate: JAL get\n RET 0\nEND\n");
+
+        let modules = select_members(vec![(vec![main()], false), (library(), true), (vec![late()], false)]);
+        assert_eq!(names(&modules), ["main", "std(put)", "std(fmt)", "late"]);
+
+        let modules = select_members(vec![(vec![main(), late()], false), (library(), true)]);
+        assert_eq!(names(&modules), ["main", "late", "std(put)", "std(get)", "std(fmt)"]);
+    }
+
+    #[test]
+    fn the_map_lists_where_everything_went() {
+        let layout = Layout::parse("REGION code 0x100 0x10\nREGION data 0x10100 0x10\nSECTION .code code\nSECTION .data data\n", "test.ld").unwrap();
+        let modules = vec![
+            module("lib.bdc", "n: .word 1\n.global get, unused\nBEGIN\nget: LDA n\n RET 0\nunused: LDA n\n RET 0\nEND\n"),
+            module("main.bdc", "EXTERN get\nBEGIN main\nmain: JAL get\n HALT\nEND\n"),
+        ];
+        let (modules, removed) = eliminate_dead_code(modules);
+        let modules: Vec<&ObjectModule> = modules.iter().collect();
+        let (executable, linkage) = link_placed(&modules, &[], &layout, false).unwrap();
+        let map = link_map(&modules, &linkage, &layout, &executable, "a.fita", &removed);
+
+        assert_eq!(
+            map,
+            concat!(
+                "Link map for a.fita\n",
+                "entry point 00102\n",
+                "\n",
+                "MODULES\n",
+                " NAME                      KIND  START  END    SIZE\n",
+                " lib.bdc                   data  10100  10100  0001\n",
+                " lib.bdc                   code  00100  00101  0002\n",
+                " main.bdc                  code  00102  00103  0002\n",
+                "\n",
+                "SYMBOLS\n",
+                " NAME                      ADDRESS  KIND  BIND        DEFINED IN                REFERENCED BY\n",
+                " get                       00100    code  global      lib.bdc                   main.bdc\n",
+                " main                      00102    code  local       main.bdc\n",
+                " n                         10100    data  local       lib.bdc                   lib.bdc\n",
+                "\n",
+                "MEMORY USAGE\n",
+                " REGION    START  SIZE   USED   FREE\n",
+                " code      00100  00010  00004  0000C\n",
+                " data      10100  00010  00001  0000F\n",
+                "\n",
+                "REMOVED\n",
+                " Removed routine unused (2 words) from lib.bdc\n",
+            )
+        );
+    }
+
+    #[test]
+    fn modules_that_do_not_fit_their_region_are_errors() {
+        let layout = Layout::parse("REGION code 0x100 2\nREGION data 0x10100 1\nSECTION .code code\nSECTION .data data\n", "test.ld").unwrap();
+
+        let main = module("main", "BEGIN\n HALT\n HALT\n HALT\nEND\n");
+        assert_eq!(error(link_objects(&[&main], &layout)), "Code of main overflows region code: ends at word 3 of 2");
+
+        let main = module("main", ".org 0x100\nn: .word 1, 2\nBEGIN\n HALT\nEND\n");
+        assert_eq!(error(link_objects(&[&main], &layout)), "Data section at .org 256 in main overflows region data: ends at word 2 of 1");
+    }
+
+    #[test]
+    fn strong_definitions_override_weak_ones_wherever_they_are_used() {
+        let lib = module("lib", ".weak handler\nBEGIN\nhandler: RET 0\nfire: JAL handler\n RET 0\nEND\n");
+        let main = module("main", ".global handler\nBEGIN main\nmain: JAL handler\n HALT\nhandler: RET 0\nEND\n");
+
+        let executable = link_objects(&[&lib, &main], &Layout::default()).unwrap();
+        assert_eq!(executable.code[1] & 0x3FFFF, 5);
+        assert_eq!(executable.code[3] & 0x3FFFF, 5);
+        assert_eq!(executable.symbols.iter().filter(|(name, _)| name == "handler").map(|(_, address)| *address).collect::<Vec<u32>>(), [5]);
+
+        let executable = link_objects(&[&main, &lib], &Layout::default()).unwrap();
+        assert_eq!(executable.code[0] & 0x3FFFF, 2);
+        assert_eq!(executable.code[4] & 0x3FFFF, 2);
+    }
+
+    #[test]
+    fn the_first_weak_definition_wins_and_two_strong_ones_clash() {
+        let first = module("first", ".weak handler\nBEGIN\nhandler: RET 0\nEND\n");
+        let second = module("second", ".weak handler\nBEGIN\n HALT\nhandler: RET 0\nEND\n");
+        let main = module("main", "EXTERN handler\nBEGIN main\nmain: JMP handler\nEND\n");
+
+        let executable = link_objects(&[&main, &first, &second], &Layout::default()).unwrap();
+        assert_eq!(executable.code[0] & 0x3FFFF, 1);
+
+        let strong = module("strong", ".global handler\nBEGIN\nhandler: HALT\nEND\n");
+        let again = module("again", ".global handler\nBEGIN\nhandler: HALT\nEND\n");
+        assert_eq!(error(link_objects(&[&strong, &again], &Layout::default())), "Found label redefinition in again\n\thandler already exported by strong");
+    }
+
+    #[test]
+    fn exporting_an_undefined_label_is_an_error() {
+        let mut main = module("main", "BEGIN\n HALT\nEND\n");
+        main.exports.push("ghost".to_owned());
+
+        assert_eq!(error(link_objects(&[&main], &Layout::default())), "Exported label ghost is not defined in main");
+    }
+
+    #[test]
+    fn org_places_sections_at_the_same_addresses_under_any_layout() {
+        let layout = Layout::parse("REGION code 0x100 0x100\nREGION data 0x10100 0x100\nSECTION .code code\nSECTION .data data\n", "test.ld").unwrap();
+        let main = module(
+            "main",
+            "first: .word 1\n.org 0x120\nvars: .word 0\n.tab: .word 7\n.ptr: .word .tab\nBEGIN main\nmain: JMP far\n.org 0x141\nfar: JMP .l\n.l: JMP .l\nEND\n",
+        );
+        let executable = link_objects(&[&main], &layout).unwrap();
+        let symbol = |name: &str| executable.symbols.iter().find(|(label, _)| label == name).map(|(_, address)| *address);
+
+        assert_eq!(executable.data_address, 0x10100);
+        assert_eq!(executable.data[0x21..], [7, 0x10121]);
+        assert_eq!(symbol("vars"), Some(0x10120));
+        assert_eq!(executable.code_address, 0x100);
+        assert_eq!(executable.code[0x41..], [(OpCodes::JMP as u32) << 18 | 0x142, (OpCodes::JMP as u32) << 18 | 0x142]);
+        assert_eq!(symbol("far"), Some(0x141));
+    }
+
+    #[test]
+    fn org_below_the_region_is_an_error() {
+        let layout = Layout::parse("REGION code 0x100 0x100\nREGION data 0x10100 0x100\nSECTION .code code\nSECTION .data data\n", "test.ld").unwrap();
+        let main = module("main", ".org 0x20\nvars: .word 0\nBEGIN\n HALT\nEND\n");
+
+        assert_eq!(error(link_objects(&[&main], &layout)), "Data section at .org 32 in main is outside region data: it starts at 65792");
+    }
+
+    #[test]
+    fn externs_take_the_kind_of_their_definition() {
+        let a = module("a", "count: .word 1\n.export count\nBEGIN\n HALT\nEND\n");
+        let b = module("b", "EXTERN count\nBEGIN\nmain: JMP count\n JMP count * 2\nEND\n");
+        assert_eq!(
+            error(link_objects(&[&b, &a], &Layout::default())),
+            "JMP at line 3 in b expects a code label\n\tfound data label count defined at line 1 in a"
+        );
+
+        let b = module("b", "EXTERN count\nBEGIN\nmain: JMP count * 2\nEND\n");
+        assert_eq!(
+            error(link_objects(&[&b, &a], &Layout::default())),
+            "JMP at line 3 in b expects a code label\n\tfound data label count defined at line 1 in a"
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use super::object::{text_words, DataValue, ObjectModule};
+use std::collections::HashMap;
+
+const PENDING: &str = "????????";
+
+pub fn listing(source: &str, module: &ObjectModule) -> String {
+    // Location and encoded word of everything each source line produced
+    let mut rows: HashMap<usize, Vec<(String, String)>> = HashMap::new();
+
+    // .org sections list their absolute offset, the rest count from the module's start
+    let mut relocated = 0;
+    for section in &module.data {
+        let mut offset = section.origin.map_or(relocated, |origin| origin as usize);
+
+        for entry in &section.entries {
+            let row = rows.entry(entry.line).or_default();
+
+            let words = match &entry.value {
+                DataValue::Words(words) => words
+                    .iter()
+                    .map(|word| match word.value() {
+                        Some(v) => format!("{:08X}", v),
+                        None => PENDING.to_owned(),
+                    })
+                    .collect(),
+                DataValue::Text(text) => text_words(text).into_iter().map(|word| format!("{:08X}", word)).collect(),
+                DataValue::Fill(count, value) => {
+                    let word = match value.value() {
+                        Some(v) => format!("{:08X}", v),
+                        None => PENDING.to_owned(),
+                    };
+
+                    // Long fills only show their first and last word
+                    let count = *count as usize;
+                    if count > 2 {
+                        row.push((format!("D {:04X}", offset), word.clone()));
+                        row.push(("   ...".to_owned(), String::new()));
+                        row.push((format!("D {:04X}", offset + count - 1), word));
+                        offset += count;
+                        continue;
+                    }
+
+                    vec![word; count]
+                }
+            };
+
+            for (i, word) in words.into_iter().enumerate() {
+                row.push((format!("D {:04X}", offset + i), word));
+            }
+
+            offset += entry.size();
+        }
+
+        if section.origin.is_none() {
+            relocated = offset;
+        }
+    }
+
+    let mut relocated = 0;
+    for section in &module.code {
+        let base = section.origin.map_or(relocated, |origin| origin as usize);
+
+        for (location, instr) in section.instructions.iter().enumerate() {
+            let word = match instr.operand.as_ref().map(|operand| operand.value()) {
+                Some(None) => PENDING.to_owned(),
+
+                value => match instr.encode(value.flatten().unwrap_or(0)) {
+                    Ok(word) => format!("{:08X}", word),
+                    Err(_) => PENDING.to_owned(),
+                },
+            };
+
+            rows.entry(instr.line).or_default().push((format!("C {:04X}", base + location), word));
+        }
+
+        if section.origin.is_none() {
+            relocated += section.instructions.len();
+        }
+    }
+
+    let mut buf = String::new();
+    buf.push_str(" LINE  LOC     WORD      SOURCE\n");
+
+    for (i, line) in source.lines().enumerate() {
+        match rows.get(&i).map(|row| row.as_slice()) {
+            None | Some([]) => buf.push_str(format!("{:5}                   {}\n", i + 1, line).as_str()),
+
+            Some([(location, word), rest @ ..]) => {
+                buf.push_str(format!("{:5}  {}  {:8}  {}\n", i + 1, location, word, line).as_str());
+                for (location, word) in rest {
+                    buf.push_str(format!("       {}  {}", location, word).trim_end());
+                    buf.push('\n');
+                }
+            }
+        }
+    }
+
+    buf.push_str("\nSYMBOL TABLE\n");
+    buf.push_str(format!("{:24}  {:6}  {:6}  {}\n", "NAME", "KIND", "VALUE", "BIND").as_str());
+
+    let binding = |label: &str| match module.is_exported(label) {
+        true => "global",
+        false => "local",
+    };
+
+    let mut relocated = 0;
+    for section in &module.data {
+        let mut offset = section.origin.map_or(relocated, |origin| origin as usize);
+
+        for entry in &section.entries {
+            if !entry.label.is_empty() {
+                buf.push_str(format!("{:24}  {:6}  D {:04X}  {}\n", entry.label, "data", offset, binding(&entry.label)).as_str());
+            }
+            offset += entry.size();
+        }
+
+        if section.origin.is_none() {
+            relocated = offset;
+        }
+    }
+
+    let mut relocated = 0;
+    for section in &module.code {
+        let base = section.origin.map_or(relocated, |origin| origin as usize);
+
+        for (label, value) in &section.labels {
+            buf.push_str(format!("{:24}  {:6}  C {:04X}  {}\n", label, "code", base + *value as usize, binding(label)).as_str());
+        }
+
+        if section.origin.is_none() {
+            relocated += section.instructions.len();
+        }
+    }
+
+    for label in &module.externs {
+        buf.push_str(format!("{:24}  {:6}\n", label, "extern").as_str());
+    }
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::assembler::assemble_module;
+
+    #[test]
+    fn listing_shows_locations_words_and_symbols() {
+        let source = "EXTERN put\nn: .word 5, put\nbuf: .fill 4, 0\nmsg: .text \"hi\"\n.global main\nBEGIN\nmain: LDA n\n JAL put\n.l: JMP 1f\n1: HALT\nEND\n";
+        let module = assemble_module(source, HashMap::new()).unwrap();
+
+        assert_eq!(
+            listing(source, &module),
+            " LINE  LOC     WORD      SOURCE
+    1                   EXTERN put
+    2  D 0000  00000005  n: .word 5, put
+       D 0001  ????????
+    3  D 0002  00000000  buf: .fill 4, 0
+          ...
+       D 0005  00000000
+    4  D 0006  00006968  msg: .text \"hi\"
+    5                   .global main
+    6                   BEGIN
+    7  C 0000  ????????  main: LDA n
+    8  C 0001  ????????   JAL put
+    9  C 0002  ????????  .l: JMP 1f
+   10  C 0003  00000000  1: HALT
+   11                   END
+
+SYMBOL TABLE
+NAME                      KIND    VALUE   BIND
+n                         data    D 0000  local
+buf                       data    D 0002  local
+msg                       data    D 0006  local
+main                      code    C 0000  global
+put                       extern
+"
+        );
+    }
+
+    #[test]
+    fn text_ends_with_a_zero_byte_padded_to_a_word() {
+        assert_eq!(text_words(b"abc"), [0x00636261]);
+        assert_eq!(text_words(b"abcd"), [0x64636261, 0]);
+        assert_eq!(text_words(b""), [0]);
+    }
+}
@@ -0,0 +1,491 @@
+// pyo3 expands #[new] into an impl nested in a constant
+#![allow(non_local_definitions)]
+
+use super::{
+    assembler::OpCodes,
+    cpu::{cycle, execute, get_acc, write_many, CPUState, STATE},
+    executable::{Executable, MAGIC, RELOCATE_CODE},
+    overlay, tape,
+};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::{fs, path::Path};
+
+const CODE_PAGE: (u32, u32) = (0, 1 << 16);
+const DATA_PAGE: (u32, u32) = (1 << 16, 2 << 16);
+
+// Name the bootstrap loader has to be loaded under for boot to find it
+const BOOT_LOADER: &str = "loader";
+
+struct Program {
+    name: String,
+    data: (u32, u32),
+    code: (u32, u32),
+    // Where overlays are swapped in, in the code page
+    overlays: Vec<(u32, u32)>,
+    entry: u32,
+    // Routines other programs may import and the shared modules this one imports from
+    exports: Vec<(String, u32)>,
+    uses: Vec<String>,
+}
+
+#[pyclass]
+#[derive(Default)]
+pub struct Loader {
+    programs: Vec<Program>,
+}
+
+impl Program {
+    // Start and size of what the program holds in the code or the data page
+    fn ranges(&self, code: bool) -> Vec<(u32, u32)> {
+        match code {
+            true => [self.code].into_iter().chain(self.overlays.iter().copied()).collect(),
+            false => vec![self.data],
+        }
+    }
+}
+
+impl Loader {
+    // Program already holding part of `size` words from `start`, and where the words it holds there end
+    fn owner(&self, start: u32, size: u32, code: bool) -> Option<(&Program, u32)> {
+        self.programs.iter().find_map(|program| {
+            program
+                .ranges(code)
+                .into_iter()
+                .find(|(from, len)| start < from + len && *from < start + size)
+                .map(|(from, len)| (program, from + len))
+        })
+    }
+
+    // Shared modules are imported by file name, whatever name they were loaded under
+    fn shared(&self, shared: &str) -> Option<&Program> {
+        self.programs
+            .iter()
+            .find(|program| program.name == shared || Path::new(&program.name).file_name().is_some_and(|name| name == shared))
+    }
+
+    // First place in the page where `size` words fit
+    fn allocate(&self, size: u32, page: (u32, u32), code: bool) -> Option<u32> {
+        let mut start = page.0;
+        while let Some((_, end)) = self.owner(start, size, code) {
+            start = end;
+        }
+
+        match start + size <= page.1 {
+            true => Some(start),
+            false => None,
+        }
+    }
+
+    // Picks where the program goes and patches every relocated word for that place
+    fn place(&self, name: &str, executable: &Executable) -> Result<(Program, Vec<u32>, Vec<u32>), String> {
+        if self.programs.iter().any(|program| program.name == name) {
+            return Err(format!("{} is already loaded", name));
+        }
+
+        let data_size = executable.data.len() as u32;
+        let code_size = executable.code.len() as u32;
+
+        let (data_start, code_start) = match &executable.relocations {
+            // Without relocations the program only runs where it was linked
+            None => {
+                let overlays = executable.overlays.iter().map(|segment| (segment.address, segment.code.len() as u32, true));
+                for (start, size, code) in [(executable.data_address, data_size, false), (executable.code_address, code_size, true)].into_iter().chain(overlays) {
+                    if let Some((owner, _)) = self.owner(start, size, code) {
+                        return Err(format!("{} can't be relocated and memory at {:05X} is taken by {}", name, start, owner.name));
+                    }
+                }
+
+                (executable.data_address, executable.code_address)
+            }
+
+            Some(_) => match (self.allocate(data_size, DATA_PAGE, false), self.allocate(code_size, CODE_PAGE, true)) {
+                (Some(data_start), Some(code_start)) => (data_start, code_start),
+                _ => return Err(format!("Not enough memory to load {}", name)),
+            },
+        };
+
+        let mut data = executable.data.clone();
+        let mut code = executable.code.clone();
+
+        let data_delta = i64::from(data_start) - i64::from(executable.data_address);
+        let code_delta = i64::from(code_start) - i64::from(executable.code_address);
+
+        for (address, kind) in executable.relocations.iter().flatten() {
+            let word = if (executable.data_address..executable.data_address + data_size).contains(address) {
+                &mut data[(address - executable.data_address) as usize]
+            } else if (executable.code_address..executable.code_address + code_size).contains(address) {
+                &mut code[(address - executable.code_address) as usize]
+            } else {
+                return Err(format!("Relocation at {:05X} outside of {}", address, name));
+            };
+
+            let field = i64::from(*word & 0xFFFF)
+                + match *kind {
+                    RELOCATE_CODE => code_delta,
+                    _ => data_delta,
+                };
+
+            if !(0..=0xFFFF).contains(&field) {
+                return Err(format!("Relocated address at {:05X} out of range in {}", address, name));
+            }
+
+            *word = *word & !0xFFFF | field as u32;
+        }
+
+        // Only the jump table changes, the program's own code is the same whoever it imports from
+        let mut uses: Vec<String> = Vec::new();
+        for (shared, label, slot) in &executable.imports {
+            let program = match self.shared(shared) {
+                None => return Err(format!("{} needs shared module {}, which is not loaded", name, shared)),
+
+                Some(program) => program,
+            };
+
+            let address = match program.exports.iter().find(|(export, _)| export == label) {
+                None => return Err(format!("Shared module {} doesn't export {} needed by {}", program.name, label, name)),
+
+                Some((_, address)) => *address,
+            };
+
+            match slot.checked_sub(executable.code_address).map(|offset| offset as usize).filter(|offset| *offset < code.len()) {
+                None => return Err(format!("Import slot at {:05X} outside of {}", slot, name)),
+
+                Some(offset) => code[offset] = (OpCodes::JMP as u32) << 18 | address,
+            }
+
+            if !uses.contains(&program.name) {
+                uses.push(program.name.clone());
+            }
+        }
+
+        let program = Program {
+            name: name.to_owned(),
+            data: (data_start, data_size),
+            code: (code_start, code_size),
+            overlays: executable.overlays.iter().map(|segment| (segment.address, segment.code.len() as u32)).collect(),
+            entry: (i64::from(executable.entry) + code_delta) as u32,
+            exports: executable.exports.iter().map(|(label, address)| (label.clone(), (i64::from(*address) + code_delta) as u32)).collect(),
+            uses,
+        };
+
+        Ok((program, data, code))
+    }
+
+    // Loads the program into memory and returns its entry address
+    fn write(&mut self, name: &str, executable: &Executable) -> PyResult<u32> {
+        let (program, data, code) = match self.place(name, executable) {
+            Err(why) => return Err(PyValueError::new_err(why)),
+
+            Ok(placed) => placed,
+        };
+
+        unsafe {
+            write_many(program.data.0, data)?;
+            write_many(program.code.0, code)?;
+        }
+        overlay::install(name, &executable.overlays);
+
+        let entry = program.entry;
+        self.programs.push(program);
+        Ok(entry)
+    }
+}
+
+#[pymethods]
+impl Loader {
+    #[new]
+    pub fn new() -> Self {
+        Loader::default()
+    }
+
+    // Name, data start and size, code start and size of every loaded program
+    #[getter]
+    pub fn programs(&self) -> Vec<(String, u32, u32, u32, u32)> {
+        self.programs
+            .iter()
+            .map(|program| (program.name.clone(), program.data.0, program.data.1, program.code.0, program.code.1))
+            .collect()
+    }
+
+    pub fn load_executable(&mut self, name: &str, executable: PyRef<Executable>) -> PyResult<u32> {
+        self.write(name, &executable)
+    }
+
+    // An absolute program goes where it was linked even when it could be relocated
+    pub fn load(&mut self, name: &str, fita: &str, absolute: Option<bool>) -> PyResult<u32> {
+        match fs::read(fita).map_err(|why| why.to_string()).and_then(|bytes| Executable::from_bytes(&bytes, fita)) {
+            Err(why) => Err(PyValueError::new_err(why)),
+
+            Ok(mut executable) => {
+                if absolute.unwrap_or(false) {
+                    executable.relocations = None;
+                }
+                self.write(name, &executable)
+            }
+        }
+    }
+
+    // Mounts the program as a tape and runs the bootstrap loader on the CPU, which reads it
+    // into the addresses it was linked for and halts with its entry in ACC
+    pub fn boot(&mut self, name: &str, fita: &str) -> PyResult<u32> {
+        let bytes = match fs::read(fita) {
+            Err(why) => return Err(PyValueError::new_err(format!("Read error: {}", why))),
+
+            Ok(bytes) => bytes,
+        };
+
+        let mut executable = match Executable::from_bytes(&bytes, fita) {
+            Err(why) => return Err(PyValueError::new_err(why)),
+
+            Ok(executable) => executable,
+        };
+
+        let loader = match self.programs.iter().find(|program| program.name == BOOT_LOADER) {
+            None => return Err(PyValueError::new_err(format!("Can't boot {} without the {} in memory", name, BOOT_LOADER))),
+
+            Some(loader) => loader.entry,
+        };
+
+        if !bytes.starts_with(MAGIC) {
+            return Err(PyValueError::new_err(format!("{} is in the legacy layout, the {} only reads FITA tapes", fita, BOOT_LOADER)));
+        }
+
+        // The bootstrap loader only copies words, it can't patch jump tables or install overlays
+        if !executable.imports.is_empty() || !executable.overlays.is_empty() {
+            return Err(PyValueError::new_err(format!("{} uses shared modules or overlays and has to be loaded with LOAD", fita)));
+        }
+
+        executable.relocations = None;
+        let program = match self.place(name, &executable) {
+            Err(why) => return Err(PyValueError::new_err(why)),
+
+            Ok((program, _, _)) => program,
+        };
+
+        tape::mount(fita, &bytes);
+        // A loader stuck waiting for input is stopped, so the CPU is left idle either way
+        let booted = unsafe {
+            let run = execute(loader, false).and_then(|_| cycle());
+            let halted = STATE == CPUState::IDLE;
+            STATE = CPUState::IDLE;
+            run.map(|_| halted)
+        };
+        tape::unmount();
+
+        match booted? {
+            false => Err(PyValueError::new_err(format!("The {} stopped before {} was loaded", BOOT_LOADER, fita))),
+
+            true => match unsafe { get_acc()? } == program.entry {
+                false => Err(PyValueError::new_err(format!("The {} read a different entry than {} has", BOOT_LOADER, fita))),
+
+                true => {
+                    let entry = program.entry;
+                    self.programs.push(program);
+                    Ok(entry)
+                }
+            },
+        }
+    }
+
+    pub fn unload(&mut self, name: &str) -> PyResult<()> {
+        if let Some(user) = self.programs.iter().find(|program| program.uses.iter().any(|shared| shared == name)) {
+            return Err(PyValueError::new_err(format!("{} is still used by {}", name, user.name)));
+        }
+
+        match self.programs.iter().position(|program| program.name == name) {
+            None => Err(PyValueError::new_err(format!("{} is not loaded", name))),
+
+            Some(index) => {
+                self.programs.remove(index);
+                overlay::remove(name);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn entry(&self, name: &str) -> PyResult<u32> {
+        match self.programs.iter().find(|program| program.name == name) {
+            None => Err(PyValueError::new_err(format!("{} is not loaded", name))),
+
+            Some(program) => Ok(program.entry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{
+        assembler::{assemble, OpCodes},
+        cpu::{cycle, execute, get_acc, read_memory, CPUState, STATE, TEST_MACHINE},
+        layout::Layout,
+        linker::{link, link_objects},
+        testing::{error, module, scratch},
+    };
+    use std::{path::PathBuf, sync::MutexGuard};
+
+    fn machine() -> MutexGuard<'static, ()> {
+        TEST_MACHINE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn program(s: &str) -> Executable {
+        link_objects(&[&module("a.bdc", s)], &Layout::default()).unwrap()
+    }
+
+    // Runs from `entry` until the program halts and returns ACC
+    fn run(entry: u32) -> u32 {
+        unsafe {
+            execute(entry, false).unwrap();
+            cycle().unwrap();
+            assert!(STATE == CPUState::IDLE);
+            get_acc().unwrap()
+        }
+    }
+
+    fn boot_error(loader: &mut Loader, name: &str, fita: &str) -> String {
+        pyo3::prepare_freethreaded_python();
+        error(loader.boot(name, fita).map_err(|why| why.to_string()))
+    }
+
+    // Assembles and links each source in a fresh directory named after the test, as `name`.fita.
+    // Files ending in .fita are linked along, `shared` ones export their routines.
+    fn build(test: &str, programs: &[(&str, &str, &[&str], bool)]) -> PathBuf {
+        let root = scratch(test, &[]);
+        let path = |file: &str| root.join(file).to_string_lossy().into_owned();
+
+        for (name, source, with, shared) in programs {
+            fs::write(path(&format!("{}.qck", name)), source).unwrap();
+            let (assembled, why) = assemble(&path(&format!("{}.qck", name)), Some(&path(&format!("{}.bdc", name))), None, None).unwrap();
+            assert!(assembled, "{}", why);
+
+            let inputs: Vec<String> = [path(&format!("{}.bdc", name))].into_iter().chain(with.iter().map(|file| path(file))).collect();
+            let (linked, why) = link(inputs.iter().map(|input| input.as_str()).collect(), Some(&path(&format!("{}.fita", name))), None, None, None, Some(*shared)).unwrap();
+            assert!(linked, "{}", why);
+        }
+        root
+    }
+
+    const COUNTER: &str = "n: .word 7\nBEGIN\nmain: LDA n\n HALT\nEND\n";
+
+    #[test]
+    fn relocatable_programs_go_after_the_ones_loaded() {
+        let _machine = machine();
+        let executable = program(COUNTER);
+        let mut loader = Loader::new();
+
+        assert_eq!(loader.write("a", &executable).unwrap(), 0);
+        assert_eq!(loader.write("b", &executable).unwrap(), 2);
+        assert_eq!(loader.programs(), [("a".to_owned(), 0x10000, 1, 0, 2), ("b".to_owned(), 0x10001, 1, 2, 2)]);
+        assert_eq!(unsafe { read_memory(2).unwrap() }, (OpCodes::LDA as u32) << 18 | 0x10001);
+        assert_eq!(run(2), 7);
+
+        loader.unload("a").unwrap();
+        assert_eq!(loader.write("c", &executable).unwrap(), 0);
+        assert!(loader.unload("a").is_err());
+        assert_eq!(loader.entry("b").unwrap(), 2);
+    }
+
+    #[test]
+    fn programs_that_do_not_fit_are_refused() {
+        let _machine = machine();
+        let mut loader = Loader::new();
+        loader.write("a", &program(COUNTER)).unwrap();
+
+        let place_error = |name: &str, executable: &Executable| error(loader.place(name, executable).map(|_| ()));
+        assert_eq!(place_error("a", &program(COUNTER)), "a is already loaded");
+
+        let mut absolute = program(COUNTER);
+        absolute.relocations = None;
+        assert_eq!(place_error("abs", &absolute), "abs can't be relocated and memory at 10000 is taken by a");
+
+        let mut big = program(COUNTER);
+        big.code = vec![0; 1 << 16];
+        assert_eq!(place_error("big", &big), "Not enough memory to load big");
+    }
+
+    #[test]
+    fn overlays_keep_their_region_from_programs_loaded_later() {
+        let _machine = machine();
+        let layout = Layout::parse(
+            "REGION swap 0 0x10\nREGION code 0x10 0x100\nREGION data 0x10000 0x100\nSECTION .code code\nSECTION .data data\nOVERLAY f swap\nMODULE f.bdc f\n",
+            "swap.ld",
+        )
+        .unwrap();
+        let main = module("main.bdc", "EXTERN f\nBEGIN main\nmain: JAL f\n HALT\nEND\n");
+        let f = module("f.bdc", ".global f\nBEGIN\nf: RET 0\nEND\n");
+        let mut loader = Loader::new();
+        loader.write("main", &link_objects(&[&main, &f], &layout).unwrap()).unwrap();
+
+        assert_eq!(loader.write("b", &program(COUNTER)).unwrap(), 1);
+        loader.unload("b").unwrap();
+
+        let mut absolute = program(COUNTER);
+        absolute.relocations = None;
+        assert_eq!(error(loader.place("abs", &absolute).map(|_| ())), "abs can't be relocated and memory at 00000 is taken by main");
+
+        loader.unload("main").unwrap();
+    }
+
+    #[test]
+    fn imports_are_bound_to_the_shared_module_loaded() {
+        let _machine = machine();
+        let root = build(
+            "shared",
+            &[
+                ("io", "v: .word 42\n.global get\nBEGIN\n HALT\nget: LDA v\n RET 0\nEND\n", &[], true),
+                ("main", "EXTERN get\nBEGIN main\nmain: JAL get\n HALT\nEND\n", &["io.fita"], false),
+            ],
+        );
+        let path = |file: &str| root.join(file).to_string_lossy().into_owned();
+        let mut loader = Loader::new();
+
+        assert!(loader.load("main", &path("main.fita"), None).is_err());
+        let main = Executable::from_bytes(&fs::read(path("main.fita")).unwrap(), "main.fita").unwrap();
+        assert_eq!(error(loader.place("main", &main).map(|_| ())), "main needs shared module io.fita, which is not loaded");
+
+        loader.load(&path("io.fita"), &path("io.fita"), None).unwrap();
+        let entry = loader.load("main", &path("main.fita"), None).unwrap();
+        assert_eq!(run(entry), 42);
+
+        assert!(loader.unload(&path("io.fita")).is_err());
+        loader.unload("main").unwrap();
+        loader.unload(&path("io.fita")).unwrap();
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn the_bootstrap_loader_reads_programs_from_the_tape() {
+        let _machine = machine();
+        let root = build(
+            "boot",
+            &[
+                ("io", ".global get\nBEGIN\nget: RET 0\nEND\n", &[], true),
+                ("user", "EXTERN get\nBEGIN main\nmain: JAL get\n HALT\nEND\n", &["io.fita"], false),
+                ("main", "v: .word 7\nw: .word 8\nBEGIN main\n JMP main\nmain: LDA v\n ADD w\n HALT\nEND\n", &[], false),
+            ],
+        );
+        let path = |file: &str| root.join(file).to_string_lossy().into_owned();
+        let mut loader = Loader::new();
+
+        assert_eq!(
+            boot_error(&mut loader, "main", &path("main.fita")),
+            "ValueError: Can't boot main without the loader in memory"
+        );
+
+        loader.load("loader", "loader.fita", Some(true)).unwrap();
+        let entry = loader.boot("main", &path("main.fita")).unwrap();
+        assert_eq!(entry, 1);
+        assert_eq!(loader.programs()[1], ("main".to_owned(), 0x10000, 2, 0, 4));
+        assert_eq!(run(entry), 15);
+
+        assert_eq!(boot_error(&mut loader, "main", &path("main.fita")), "ValueError: main is already loaded");
+        assert_eq!(
+            boot_error(&mut loader, "user", &path("user.fita")),
+            format!("ValueError: {} uses shared modules or overlays and has to be loaded with LOAD", path("user.fita"))
+        );
+        assert!(boot_error(&mut loader, "none", &path("none.fita")).starts_with("ValueError: Read error: "));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
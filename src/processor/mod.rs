@@ -1,6 +1,22 @@
 pub mod assembler;
 pub mod cpu;
+pub mod deadcode;
+pub mod executable;
+pub mod expression;
+pub mod layout;
+pub mod library;
+pub mod listing;
+pub mod loader;
 pub mod memory;
+pub mod object;
+pub mod objfile;
+pub mod overlay;
+pub mod project;
+pub mod tape;
+pub mod xref;
 
 #[macro_use]
 pub mod linker;
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,437 @@
+use super::{
+    assembler::OpCodes,
+    expression::Expr,
+    layout::Layout,
+    linker::link_objects,
+    objfile::ObjectFile,
+};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::{fs, str::FromStr};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Code,
+    Data,
+    Constant,
+    Extern,
+}
+
+impl SymbolKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SymbolKind::Code => "code",
+            SymbolKind::Data => "data",
+            SymbolKind::Constant => "constant",
+            SymbolKind::Extern => "extern",
+        }
+    }
+}
+
+pub enum DataValue {
+    Words(Vec<Expr>),
+    Text(Vec<u8>),
+    Fill(u32, Expr),
+}
+
+pub fn text_words(text: &[u8]) -> Vec<u32> {
+    let mut bytes = text.to_vec();
+    bytes.push(0);
+    while bytes.len() & 3 != 0 {
+        bytes.push(0);
+    }
+
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+pub struct DataEntry {
+    pub label: String,
+    pub value: DataValue,
+    pub line: usize,
+}
+
+impl DataEntry {
+    pub fn size(&self) -> usize {
+        match &self.value {
+            DataValue::Words(words) => words.len(),
+            DataValue::Text(text) => text.len() / 4 + 1,
+            DataValue::Fill(count, _) => *count as usize,
+        }
+    }
+}
+
+pub struct Instruction {
+    pub op: OpCodes,
+    pub irq: Option<u8>,
+    pub operand: Option<Expr>,
+    pub line: usize,
+}
+
+impl Instruction {
+    pub fn parse(mnemonic: &str, args: &str, line: usize) -> Result<Instruction, String> {
+        let op = match OpCodes::from_str(mnemonic) {
+            Err(_) => return Err(format!("Expected instruction at line {}\n\tfound {} instead", line + 1, mnemonic)),
+
+            Ok(op) => op,
+        };
+
+        match op {
+            OpCodes::IRQ => {
+                let (irq_type, arg) = match args.trim().split_once(char::is_whitespace) {
+                    Some((irq_type, arg)) => (irq_type, Some(arg.trim())),
+                    None => (args.trim(), None),
+                };
+
+                let irq = match irq_type.parse::<u8>() {
+                    Err(_) => return Err(format!("Expected integer at line {}\n\tfound {} instead", line + 1, irq_type)),
+
+                    Ok(v) => v,
+                };
+
+                let operand = match arg {
+                    Some(arg) => match irq {
+                        1..=2 => match Expr::parse(arg) {
+                            Err(why) => return Err(format!("{} at line {}", why, line + 1)),
+
+                            Ok(expr) => Some(expr),
+                        },
+                        3 => match u32::from_str_radix(arg, 2) {
+                            Err(_) => return Err(format!("Expected binary number as argument at line {}\n\tfound {} instead", line + 1, arg)),
+
+                            Ok(flags) => Some(Expr::Number(flags)),
+                        },
+                        0 | 4 => return Err(format!("Unexpected argument at line {}\n\t{}", line + 1, arg)),
+                        _ => return Err(format!("Unknown IRQ type at line {}\n\t{}", line + 1, irq_type)),
+                    },
+                    None => match irq {
+                        0 | 4 => None,
+                        1..=3 => return Err(format!("Expected label at line {}", line + 1)),
+                        _ => return Err(format!("Unknown IRQ type at line {}\n\t{}", line + 1, irq_type)),
+                    },
+                };
+
+                Ok(Instruction {
+                    op,
+                    irq: Some(irq),
+                    operand,
+                    line,
+                })
+            }
+            _ => match args.trim() {
+                "" => Err(format!("Expected label at line {}", line + 1)),
+
+                arg => match Expr::parse(arg) {
+                    Err(why) => Err(format!("{} at line {}", why, line + 1)),
+
+                    Ok(expr) => Ok(Instruction {
+                        op,
+                        irq: None,
+                        operand: Some(expr),
+                        line,
+                    }),
+                },
+            },
+        }
+    }
+
+    pub fn encode(&self, value: u32) -> Result<u32, String> {
+        match self.irq {
+            Some(irq @ 1..=2) => match value >> 16 {
+                1 => Ok(u32::from(irq) << 16 | (value & 0xFFFF)),
+                _ => Err(format!("Only data labels allowed for PRINT and READ instructions at line {}", self.line + 1)),
+            },
+            Some(3) => match value.leading_zeros() {
+                0..=15 => Err(format!("Flags out of range at line {}", self.line + 1)),
+                _ => Ok(3 << 16 | value),
+            },
+            Some(irq) => Ok(u32::from(irq >> 2)),
+            None => match value.leading_zeros() {
+                0..=13 => Err(format!("Operand out of range at line {}\n\t{}", self.line + 1, value)),
+                _ => Ok((self.op as u32) << 18 | value),
+            },
+        }
+    }
+}
+
+impl Instruction {
+    // What the operand field has to hold, None when the operand is not an address
+    pub fn expects(&self) -> Option<SymbolKind> {
+        match (self.op, self.irq) {
+            (OpCodes::IRQ, Some(1..=2)) => Some(SymbolKind::Data),
+
+            (OpCodes::IRQ | OpCodes::NEG | OpCodes::NOT | OpCodes::RET, _) => None,
+
+            (OpCodes::JMP | OpCodes::JAL | OpCodes::BEQ | OpCodes::BGT | OpCodes::BLT | OpCodes::BHS | OpCodes::BMI | OpCodes::BVS | OpCodes::BHI, _) => {
+                Some(SymbolKind::Code)
+            }
+
+            _ => Some(SymbolKind::Data),
+        }
+    }
+
+    pub fn mnemonic(&self) -> String {
+        match (self.op, self.irq) {
+            (OpCodes::IRQ, Some(1)) => "PRINT".to_owned(),
+            (OpCodes::IRQ, Some(2)) => "READ".to_owned(),
+            (OpCodes::IRQ, Some(irq)) => format!("IRQ {}", irq),
+            (op, _) => format!("{:?}", op),
+        }
+    }
+
+    // Inverse of encode, `operand` stands in for an operand field still waiting on relocation
+    pub fn decode(word: u32, operand: Option<Expr>, line: usize) -> Result<Instruction, String> {
+        let op = match OpCodes::from_repr((word >> 18) as u16) {
+            None => return Err(format!("Invalid instruction {:08X} at line {}", word, line + 1)),
+
+            Some(op) => op,
+        };
+
+        let irq = match op {
+            OpCodes::IRQ => match word >> 16 {
+                0 => Some(((word & 1) << 2) as u8),
+                irq => Some(irq as u8),
+            },
+            _ => None,
+        };
+
+        let operand = match (irq, operand) {
+            (_, Some(expr)) => Some(expr),
+            (Some(0 | 4), None) => None,
+            (Some(3), None) => Some(Expr::Number(word & 0xFFFF)),
+            (Some(_), None) => Some(Expr::Number(1 << 16 | (word & 0xFFFF))),
+            (None, None) => Some(Expr::Number(word & 0x3FFFF)),
+        };
+
+        Ok(Instruction { op, irq, operand, line })
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.irq, &self.operand) {
+            (Some(3), Some(Expr::Number(flags))) => write!(f, "IRQ 3 {:b}", flags),
+            (Some(irq), Some(operand)) => write!(f, "IRQ {} {}", irq, operand),
+            (Some(irq), None) => write!(f, "IRQ {}", irq),
+            (None, Some(operand)) => write!(f, "{:?} {}", self.op, operand),
+            (None, None) => write!(f, "{:?}", self.op),
+        }
+    }
+}
+
+// A contiguous run of data. Sections without an origin are merged with the
+// other modules' data by the linker, the rest stay where .org put them.
+#[derive(Default)]
+pub struct DataSection {
+    pub origin: Option<u32>,
+    pub entries: Vec<DataEntry>,
+}
+
+impl DataSection {
+    pub fn size(&self) -> usize {
+        self.entries.iter().map(|entry| entry.size()).sum()
+    }
+}
+
+#[derive(Default)]
+pub struct CodeSection {
+    pub origin: Option<u32>,
+    pub labels: Vec<(String, u32)>,
+    pub instructions: Vec<Instruction>,
+}
+
+#[pyclass]
+#[derive(Default)]
+pub struct ObjectModule {
+    #[pyo3(get, set)]
+    pub name: String,
+    pub data: Vec<DataSection>,
+    pub code: Vec<CodeSection>,
+    pub externs: Vec<String>,
+    pub exports: Vec<String>,
+    // Exported labels another module's strong definition can override
+    pub weak: Vec<String>,
+    pub entry: Option<String>,
+}
+
+impl ObjectModule {
+    pub fn entries(&self) -> impl Iterator<Item = &DataEntry> {
+        self.data.iter().flat_map(|section| section.entries.iter())
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = &Instruction> {
+        self.code.iter().flat_map(|section| section.instructions.iter())
+    }
+
+    pub fn defines(&self, label: &str) -> bool {
+        self.entries().any(|entry| entry.label == label)
+            || self.code.iter().any(|section| section.labels.iter().any(|(name, _)| name == label))
+    }
+
+    pub fn symbol_kind(&self, label: &str) -> Option<SymbolKind> {
+        if self.entries().any(|entry| entry.label == label) {
+            return Some(SymbolKind::Data);
+        }
+
+        if self.code.iter().any(|section| section.labels.iter().any(|(name, _)| name == label)) {
+            return Some(SymbolKind::Code);
+        }
+
+        match self.externs.iter().any(|name| name == label) {
+            true => Some(SymbolKind::Extern),
+            false => None,
+        }
+    }
+
+    // Line where a label was defined, code labels take the line of the instruction they point to
+    pub fn definition_line(&self, label: &str) -> Option<usize> {
+        if let Some(entry) = self.entries().find(|entry| entry.label == label) {
+            return Some(entry.line);
+        }
+
+        self.code.iter().find_map(|section| {
+            let (_, offset) = section.labels.iter().find(|(name, _)| name == label)?;
+            section.instructions.get(*offset as usize).or(section.instructions.last()).map(|instr| instr.line)
+        })
+    }
+
+    pub fn is_exported(&self, label: &str) -> bool {
+        self.exports.iter().any(|name| name == label)
+    }
+
+    pub fn is_weak(&self, label: &str) -> bool {
+        self.weak.iter().any(|name| name == label)
+    }
+
+    // Every label in a data word or operand, except the module's own section names
+    pub fn references(&self) -> Vec<(&str, usize)> {
+        let mut references = Vec::new();
+
+        for entry in self.entries() {
+            let words = match &entry.value {
+                DataValue::Words(words) => words.iter().collect(),
+                DataValue::Fill(_, value) => vec![value],
+                DataValue::Text(_) => Vec::new(),
+            };
+
+            for word in words {
+                references.extend(word.symbols().into_iter().map(|name| (name, entry.line)));
+            }
+        }
+
+        for instr in self.instructions() {
+            if let Some(operand) = &instr.operand {
+                references.extend(operand.symbols().into_iter().map(|name| (name, instr.line)));
+            }
+        }
+
+        references.retain(|(name, _)| *name != ".code" && *name != ".data");
+        references
+    }
+}
+
+#[pymethods]
+impl ObjectModule {
+    #[getter]
+    fn exported(&self) -> Vec<String> {
+        self.exports.clone()
+    }
+
+    #[getter]
+    fn weak(&self) -> Vec<String> {
+        self.weak.clone()
+    }
+
+    #[getter]
+    fn imported(&self) -> Vec<String> {
+        self.externs.clone()
+    }
+
+    #[getter]
+    fn entry(&self) -> Option<String> {
+        self.entry.clone()
+    }
+
+    pub fn write(&self, bdc: &str) -> PyResult<()> {
+        let obj = match ObjectFile::from_module(self) {
+            Err(why) => return Err(PyValueError::new_err(why)),
+
+            Ok(obj) => obj,
+        };
+
+        match fs::write(bdc, obj.write()) {
+            Err(why) => Err(PyValueError::new_err(why.to_string())),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    // Links the module on its own and loads the result
+    pub fn load(&self) -> PyResult<()> {
+        match link_objects(&[self], &Layout::default()) {
+            Err(why) => Err(PyValueError::new_err(why)),
+            Ok(executable) => executable.load(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::assembler::assemble_module;
+    use std::collections::HashMap;
+
+    fn encode(mnemonic: &str, args: &str, value: u32) -> Result<u32, String> {
+        Instruction::parse(mnemonic, args, 4)?.encode(value)
+    }
+
+    #[test]
+    fn instructions_encode_their_operand() {
+        assert_eq!(encode("LDA", "n", 0x10004), Ok(1 << 18 | 0x10004));
+        assert_eq!(encode("JMP", "start + 1", 0x21), Ok(19 << 18 | 0x21));
+        assert_eq!(encode("IRQ", "1 msg", 0x10010), Ok(1 << 16 | 0x10));
+        assert_eq!(encode("IRQ", "2 buffer", 0x1FFFF), Ok(2 << 16 | 0xFFFF));
+        assert_eq!(encode("IRQ", "3 10000", 0x10), Ok(3 << 16 | 0x10));
+        assert_eq!(encode("IRQ", "0", 0), Ok(0));
+        assert_eq!(encode("IRQ", "4", 0), Ok(1));
+    }
+
+    #[test]
+    fn instructions_decode_to_what_was_encoded() {
+        let round_trip = |mnemonic: &str, args: &str, value: u32| Instruction::decode(encode(mnemonic, args, value).unwrap(), None, 0).unwrap().to_string();
+
+        assert_eq!(round_trip("LDA", "n", 0x10004), "LDA 65540");
+        assert_eq!(round_trip("JMP", "start + 1", 0x21), "JMP 33");
+        assert_eq!(round_trip("IRQ", "1 msg", 0x10010), "IRQ 1 65552");
+        assert_eq!(round_trip("IRQ", "2 buffer", 0x1FFFF), "IRQ 2 131071");
+        assert_eq!(round_trip("IRQ", "3 10000", 0x10), "IRQ 3 10000");
+        assert_eq!(round_trip("IRQ", "0", 0), "IRQ 0");
+        assert_eq!(round_trip("IRQ", "4", 0), "IRQ 4");
+        assert_eq!(round_trip("RET", "0", 0), "RET 0");
+        assert_eq!(Instruction::decode(0xFFFF_FFFF, None, 4).map(|_| ()), Err("Invalid instruction FFFFFFFF at line 5".to_owned()));
+    }
+
+    #[test]
+    fn instructions_write_back_what_was_parsed() {
+        for (mnemonic, args, written) in [("JMP", "start + 1", "JMP start+1"), ("IRQ", "1 msg", "IRQ 1 msg"), ("IRQ", "3 101", "IRQ 3 101"), ("IRQ", "0", "IRQ 0")] {
+            let instr = Instruction::parse(mnemonic, args, 0).unwrap();
+            assert_eq!(instr.to_string(), written);
+        }
+    }
+
+    #[test]
+    fn operands_that_do_not_fit_are_rejected() {
+        assert_eq!(encode("IRQ", "1 msg", 0x20), Err("Only data labels allowed for PRINT and READ instructions at line 5".to_owned()));
+        assert_eq!(encode("LDA", "n", 1 << 18), Err("Operand out of range at line 5\n\t262144".to_owned()));
+        assert_eq!(encode("IRQ", "3 1", 1 << 16), Err("Flags out of range at line 5".to_owned()));
+        assert_eq!(encode("IRQ", "5", 0), Err("Unknown IRQ type at line 5\n\t5".to_owned()));
+        assert_eq!(encode("IRQ", "3 12", 0), Err("Expected binary number as argument at line 5\n\tfound 12 instead".to_owned()));
+        assert_eq!(encode("LDB", "n", 0), Err("Expected instruction at line 5\n\tfound LDB instead".to_owned()));
+    }
+
+    #[test]
+    fn text_literals_keep_their_escapes() {
+        let module = assemble_module("n: .word 5, n + 1\nmsg: .text \"say \\\"hi\\\"\\n\"\nBEGIN\nmain: IRQ 1 msg\nEND\n", HashMap::new()).unwrap();
+        assert!(matches!(&module.data[0].entries[1].value, DataValue::Text(text) if text == b"say \"hi\"\n"));
+    }
+}
@@ -0,0 +1,821 @@
+use super::{
+    expression::{BinaryOp, Expr},
+    library::{self, Library},
+    object::{text_words, CodeSection, DataEntry, DataSection, DataValue, Instruction, ObjectModule, SymbolKind},
+};
+use pyo3::prelude::*;
+use std::{collections::HashMap, fs};
+
+// Layout, all integers little endian:
+//   magic, version u16, flags u16
+//   section, symbol, relocation and line counts, u32 each
+//   sections:    kind u8, has origin u8, origin u32, word count u32, words
+//   symbols:     name length u16, name, section u16, value u32, binding u8
+//   relocations: section u16, offset u32, expression length u16, expression
+//   lines:       section u16, offset u32, line u32
+//   entry:       symbol index u32
+pub const MAGIC: &[u8; 4] = b"SBDC";
+pub const VERSION: u16 = 1;
+
+const DEBUG_LINES: u16 = 1;
+const ENTRY: u16 = 2;
+const UNDEFINED: u16 = u16::MAX;
+
+const BIND_LOCAL: u8 = 0;
+const BIND_GLOBAL: u8 = 1;
+const BIND_WEAK: u8 = 2;
+
+// Relocation expressions are stored in postfix
+const EXPR_NUMBER: u8 = 0;
+const EXPR_SYMBOL: u8 = 1;
+const EXPR_SECTION: u8 = 2;
+const EXPR_NEGATE: u8 = 3;
+const EXPR_BINARY: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SectionKind {
+    Data,
+    Code,
+}
+
+pub struct Section {
+    pub kind: SectionKind,
+    pub origin: Option<u32>,
+    pub words: Vec<u32>,
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub section: Option<u16>,
+    pub value: u32,
+    pub global: bool,
+    pub weak: bool,
+}
+
+// Word at `offset` in `section` still needs `expr` added in by the linker
+pub struct Relocation {
+    pub section: u16,
+    pub offset: u32,
+    pub expr: Expr,
+}
+
+pub struct ObjectFile {
+    pub version: u16,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+    pub lines: Option<Vec<(u16, u32, u32)>>,
+    pub entry: Option<String>,
+}
+
+// Little endian cursor over a file, failing instead of reading past its end
+pub struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub name: &'a str,
+}
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err(format!("Unexpected end of file {}", self.name));
+        }
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+fn place(words: &mut Vec<u32>, relocations: &mut Vec<Relocation>, section: u16, expr: &Expr) {
+    match expr.value() {
+        Some(value) => words.push(value),
+
+        None => {
+            relocations.push(Relocation {
+                section,
+                offset: words.len() as u32,
+                expr: expr.clone(),
+            });
+            words.push(0);
+        }
+    }
+}
+
+fn write_expr(buf: &mut Vec<u8>, expr: &Expr, symbols: &[Symbol]) {
+    match expr {
+        Expr::Number(value) => {
+            buf.push(EXPR_NUMBER);
+            buf.extend(value.to_le_bytes());
+        }
+        Expr::Symbol(name) if name == ".data" || name == ".code" => {
+            buf.push(EXPR_SECTION);
+            buf.push((name == ".code") as u8);
+        }
+        Expr::Symbol(name) => {
+            let index = symbols.iter().position(|symbol| &symbol.name == name).unwrap_or(u32::MAX as usize);
+            buf.push(EXPR_SYMBOL);
+            buf.extend((index as u32).to_le_bytes());
+        }
+        Expr::Negate(inner) => {
+            write_expr(buf, inner, symbols);
+            buf.push(EXPR_NEGATE);
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            write_expr(buf, lhs, symbols);
+            write_expr(buf, rhs, symbols);
+            buf.push(EXPR_BINARY);
+            buf.push(*op as u8);
+        }
+    }
+}
+
+fn read_expr(reader: &mut Reader, symbols: &[Symbol]) -> Result<Expr, String> {
+    let name = reader.name;
+    let invalid = || format!("Invalid relocation expression in {}", name);
+
+    let mut stack: Vec<Expr> = Vec::new();
+    let len = reader.u16()? as usize;
+    let mut code = Reader {
+        bytes: reader.take(len)?,
+        name,
+    };
+
+    while !code.bytes.is_empty() {
+        let expr = match code.u8()? {
+            EXPR_NUMBER => Expr::Number(code.u32()?),
+            EXPR_SYMBOL => match symbols.get(code.u32()? as usize) {
+                None => return Err(invalid()),
+
+                Some(symbol) => Expr::Symbol(symbol.name.clone()),
+            },
+            EXPR_SECTION => match code.u8()? {
+                0 => Expr::Symbol(".data".to_owned()),
+                _ => Expr::Symbol(".code".to_owned()),
+            },
+            EXPR_NEGATE => match stack.pop() {
+                None => return Err(invalid()),
+
+                Some(inner) => Expr::Negate(Box::new(inner)),
+            },
+            EXPR_BINARY => match (BinaryOp::from_repr(code.u8()?), stack.pop(), stack.pop()) {
+                (Some(op), Some(rhs), Some(lhs)) => Expr::Binary(op, Box::new(lhs), Box::new(rhs)),
+
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+
+        stack.push(expr);
+    }
+
+    match (stack.pop(), stack.is_empty()) {
+        (Some(expr), true) => Ok(expr),
+        _ => Err(invalid()),
+    }
+}
+
+impl ObjectFile {
+    pub fn from_module(module: &ObjectModule) -> Result<ObjectFile, String> {
+        let mut file = ObjectFile {
+            version: VERSION,
+            sections: Vec::new(),
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+            lines: None,
+            entry: module.entry.clone(),
+        };
+        let mut lines = Vec::new();
+
+        for section in &module.data {
+            let index = file.sections.len() as u16;
+            let mut words = Vec::new();
+
+            for entry in &section.entries {
+                if !entry.label.is_empty() {
+                    file.symbols.push(Symbol {
+                        name: entry.label.clone(),
+                        section: Some(index),
+                        value: words.len() as u32,
+                        global: module.is_exported(&entry.label),
+                        weak: module.is_weak(&entry.label),
+                    });
+                }
+
+                lines.push((index, words.len() as u32, entry.line as u32));
+
+                match &entry.value {
+                    DataValue::Words(values) => values.iter().for_each(|value| place(&mut words, &mut file.relocations, index, value)),
+                    DataValue::Fill(count, value) => (0..*count).for_each(|_| place(&mut words, &mut file.relocations, index, value)),
+                    DataValue::Text(text) => words.extend(text_words(text)),
+                }
+            }
+
+            file.sections.push(Section {
+                kind: SectionKind::Data,
+                origin: section.origin,
+                words,
+            });
+        }
+
+        for section in &module.code {
+            let index = file.sections.len() as u16;
+            let mut words = Vec::new();
+
+            for (label, value) in &section.labels {
+                file.symbols.push(Symbol {
+                    name: label.clone(),
+                    section: Some(index),
+                    value: *value,
+                    global: module.is_exported(label),
+                    weak: module.is_weak(label),
+                });
+            }
+
+            for instr in &section.instructions {
+                lines.push((index, words.len() as u32, instr.line as u32));
+
+                // Symbolic operands leave the field empty, PRINT and READ still need a data address to encode
+                let word = match instr.operand.as_ref().map(|operand| operand.value()) {
+                    Some(None) => {
+                        file.relocations.push(Relocation {
+                            section: index,
+                            offset: words.len() as u32,
+                            expr: instr.operand.clone().unwrap(),
+                        });
+
+                        instr.encode(match instr.irq {
+                            Some(1..=2) => 1 << 16,
+                            _ => 0,
+                        })?
+                    }
+
+                    value => instr.encode(value.flatten().unwrap_or(0))?,
+                };
+
+                words.push(word);
+            }
+
+            file.sections.push(Section {
+                kind: SectionKind::Code,
+                origin: section.origin,
+                words,
+            });
+        }
+
+        for name in &module.externs {
+            file.symbols.push(Symbol {
+                name: name.clone(),
+                section: None,
+                value: 0,
+                global: true,
+                weak: false,
+            });
+        }
+
+        // Labels used without EXTERN are still looked up in the other modules
+        let referenced: Vec<&str> = file.relocations.iter().flat_map(|relocation| relocation.expr.symbols()).chain(module.entry.as_deref()).collect();
+        let mut undefined: Vec<Symbol> = Vec::new();
+        for name in referenced {
+            if name != ".data" && name != ".code" && !file.symbols.iter().chain(&undefined).any(|symbol| symbol.name == name) {
+                undefined.push(Symbol {
+                    name: name.to_owned(),
+                    section: None,
+                    value: 0,
+                    global: false,
+                    weak: false,
+                });
+            }
+        }
+        file.symbols.extend(undefined);
+
+        file.lines = Some(lines);
+        Ok(file)
+    }
+
+    pub fn to_module(&self, name: &str) -> Result<ObjectModule, String> {
+        let mut module = ObjectModule {
+            name: name.to_owned(),
+            entry: self.entry.clone(),
+            ..Default::default()
+        };
+
+        for symbol in &self.symbols {
+            match (symbol.section, symbol.global) {
+                (None, true) => module.externs.push(symbol.name.clone()),
+                (Some(_), true) => module.exports.push(symbol.name.clone()),
+                _ => (),
+            }
+
+            if symbol.weak {
+                module.weak.push(symbol.name.clone());
+            }
+        }
+
+        for (index, section) in self.sections.iter().enumerate() {
+            let index = index as u16;
+
+            let relocations: HashMap<u32, &Expr> = self
+                .relocations
+                .iter()
+                .filter(|relocation| relocation.section == index)
+                .map(|relocation| (relocation.offset, &relocation.expr))
+                .collect();
+
+            let mut lines: Vec<(u32, u32)> = match &self.lines {
+                None => Vec::new(),
+                Some(lines) => lines.iter().filter(|(section, _, _)| *section == index).map(|(_, offset, line)| (*offset, *line)).collect(),
+            };
+            lines.sort_unstable();
+            let line_at = |offset: u32| match lines.iter().rev().find(|(start, _)| *start <= offset) {
+                None => 0,
+                Some((_, line)) => *line as usize,
+            };
+
+            let mut labels: Vec<(&str, u32)> = Vec::new();
+            for symbol in self.symbols.iter().filter(|symbol| symbol.section == Some(index)) {
+                if symbol.value as usize > section.words.len() {
+                    return Err(format!("Symbol {} out of section range in {}", symbol.name, name));
+                }
+
+                labels.push((symbol.name.as_str(), symbol.value));
+            }
+            labels.sort_by_key(|(_, value)| *value);
+
+            let expr_at = |offset: u32| match relocations.get(&offset) {
+                None => Expr::Number(section.words[offset as usize]),
+                Some(expr) => (*expr).clone(),
+            };
+
+            match section.kind {
+                SectionKind::Data => {
+                    // Entries are cut wherever a label or a source line starts
+                    let mut starts: Vec<u32> = labels.iter().map(|(_, value)| *value).chain(lines.iter().map(|(offset, _)| *offset)).collect();
+                    starts.push(0);
+                    starts.sort_unstable();
+                    starts.dedup();
+                    starts.retain(|start| (*start as usize) < section.words.len() || labels.iter().any(|(_, value)| value == start));
+
+                    let mut entries = Vec::new();
+                    for (i, start) in starts.iter().enumerate() {
+                        let end = starts.get(i + 1).copied().unwrap_or(section.words.len() as u32).max(*start);
+                        let mut names: Vec<&str> = labels.iter().filter(|(_, value)| value == start).map(|(name, _)| *name).collect();
+                        let last = names.pop().unwrap_or("");
+
+                        for label in names {
+                            entries.push(DataEntry {
+                                label: label.to_owned(),
+                                value: DataValue::Words(Vec::new()),
+                                line: line_at(*start),
+                            });
+                        }
+
+                        entries.push(DataEntry {
+                            label: last.to_owned(),
+                            value: DataValue::Words((*start..end).map(expr_at).collect()),
+                            line: line_at(*start),
+                        });
+                    }
+
+                    module.data.push(DataSection {
+                        origin: section.origin,
+                        entries,
+                    });
+                }
+                SectionKind::Code => {
+                    let instructions = (0..section.words.len() as u32)
+                        .map(|offset| {
+                            let line = line_at(offset);
+                            match Instruction::decode(section.words[offset as usize], relocations.get(&offset).map(|expr| (*expr).clone()), line) {
+                                Err(why) => Err(format!("{} in {}", why, name)),
+                                Ok(instr) => Ok(instr),
+                            }
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    module.code.push(CodeSection {
+                        origin: section.origin,
+                        labels: labels.into_iter().map(|(name, value)| (name.to_owned(), value)).collect(),
+                        instructions,
+                    });
+                }
+            }
+        }
+
+        if let Some(label) = module.exports.iter().find(|label| !module.defines(label)) {
+            return Err(format!("Exported label {} is not defined in {}", label, name));
+        }
+
+        Ok(module)
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        buf.extend(MAGIC);
+        buf.extend(self.version.to_le_bytes());
+        let mut flags = 0;
+        if self.lines.is_some() {
+            flags |= DEBUG_LINES;
+        }
+        if self.entry.is_some() {
+            flags |= ENTRY;
+        }
+        buf.extend(flags.to_le_bytes());
+
+        buf.extend((self.sections.len() as u32).to_le_bytes());
+        buf.extend((self.symbols.len() as u32).to_le_bytes());
+        buf.extend((self.relocations.len() as u32).to_le_bytes());
+        buf.extend((self.lines.as_ref().map_or(0, |lines| lines.len()) as u32).to_le_bytes());
+
+        for section in &self.sections {
+            buf.push((section.kind == SectionKind::Code) as u8);
+            buf.push(section.origin.is_some() as u8);
+            buf.extend(section.origin.unwrap_or(0).to_le_bytes());
+            buf.extend((section.words.len() as u32).to_le_bytes());
+            for word in &section.words {
+                buf.extend(word.to_le_bytes());
+            }
+        }
+
+        for symbol in &self.symbols {
+            buf.extend((symbol.name.len() as u16).to_le_bytes());
+            buf.extend(symbol.name.as_bytes());
+            buf.extend(symbol.section.unwrap_or(UNDEFINED).to_le_bytes());
+            buf.extend(symbol.value.to_le_bytes());
+            buf.push(match (symbol.global, symbol.weak) {
+                (_, true) => BIND_WEAK,
+                (true, false) => BIND_GLOBAL,
+                (false, false) => BIND_LOCAL,
+            });
+        }
+
+        for relocation in &self.relocations {
+            let mut expr = Vec::new();
+            write_expr(&mut expr, &relocation.expr, &self.symbols);
+
+            buf.extend(relocation.section.to_le_bytes());
+            buf.extend(relocation.offset.to_le_bytes());
+            buf.extend((expr.len() as u16).to_le_bytes());
+            buf.extend(expr);
+        }
+
+        for (section, offset, line) in self.lines.iter().flatten() {
+            buf.extend(section.to_le_bytes());
+            buf.extend(offset.to_le_bytes());
+            buf.extend(line.to_le_bytes());
+        }
+
+        if let Some(entry) = &self.entry {
+            let index = self.symbols.iter().position(|symbol| &symbol.name == entry).unwrap_or(u32::MAX as usize);
+            buf.extend((index as u32).to_le_bytes());
+        }
+
+        buf
+    }
+
+    pub fn read(bytes: &[u8], name: &str) -> Result<ObjectFile, String> {
+        let mut reader = Reader { bytes, name };
+
+        if reader.take(4).ok() != Some(MAGIC.as_slice()) {
+            return Err(format!("{} is not an object file", name));
+        }
+
+        let version = reader.u16()?;
+        if version > VERSION {
+            return Err(format!("Unsupported object file version {} in {}", version, name));
+        }
+
+        let flags = reader.u16()?;
+        let section_count = reader.u32()?;
+        let symbol_count = reader.u32()?;
+        let relocation_count = reader.u32()?;
+        let line_count = reader.u32()?;
+
+        let mut file = ObjectFile {
+            version,
+            sections: Vec::new(),
+            symbols: Vec::new(),
+            relocations: Vec::new(),
+            lines: None,
+            entry: None,
+        };
+
+        for _ in 0..section_count {
+            let kind = match reader.u8()? {
+                0 => SectionKind::Data,
+                _ => SectionKind::Code,
+            };
+            let origin = match (reader.u8()?, reader.u32()?) {
+                (0, _) => None,
+                (_, origin) => Some(origin),
+            };
+            let words = (0..reader.u32()?).map(|_| reader.u32()).collect::<Result<Vec<_>, _>>()?;
+
+            file.sections.push(Section { kind, origin, words });
+        }
+
+        for _ in 0..symbol_count {
+            let len = reader.u16()? as usize;
+            let symbol_name = match String::from_utf8(reader.take(len)?.to_vec()) {
+                Err(_) => return Err(format!("Invalid symbol name in {}", name)),
+
+                Ok(s) => s,
+            };
+
+            let section = match reader.u16()? {
+                UNDEFINED => None,
+
+                index if index as usize >= file.sections.len() => return Err(format!("Symbol {} in missing section {} in {}", symbol_name, index, name)),
+
+                index => Some(index),
+            };
+
+            let value = reader.u32()?;
+            let (global, weak) = match reader.u8()? {
+                BIND_LOCAL => (false, false),
+                BIND_GLOBAL => (true, false),
+                BIND_WEAK if section.is_some() => (true, true),
+                _ => return Err(format!("Invalid binding for symbol {} in {}", symbol_name, name)),
+            };
+
+            file.symbols.push(Symbol {
+                name: symbol_name,
+                section,
+                value,
+                global,
+                weak,
+            });
+        }
+
+        for _ in 0..relocation_count {
+            let section = reader.u16()?;
+            let offset = reader.u32()?;
+
+            match file.sections.get(section as usize) {
+                Some(s) if (offset as usize) < s.words.len() => (),
+
+                _ => return Err(format!("Relocation outside of any section in {}", name)),
+            }
+
+            let expr = read_expr(&mut reader, &file.symbols)?;
+            file.relocations.push(Relocation { section, offset, expr });
+        }
+
+        if flags & DEBUG_LINES != 0 {
+            file.lines = Some((0..line_count).map(|_| Ok((reader.u16()?, reader.u32()?, reader.u32()?))).collect::<Result<Vec<_>, String>>()?);
+        }
+
+        if flags & ENTRY != 0 {
+            match file.symbols.get(reader.u32()? as usize) {
+                None => return Err(format!("Entry point is not in the symbol table of {}", name)),
+
+                Some(symbol) => file.entry = Some(symbol.name.clone()),
+            }
+        }
+
+        Ok(file)
+    }
+
+    pub fn dump(&self, name: &str) -> String {
+        let mut buf = String::new();
+
+        buf.push_str(format!("{}: object file version {}\n", name, self.version).as_str());
+        if let Some(entry) = &self.entry {
+            buf.push_str(format!("entry point {}\n", entry).as_str());
+        }
+
+        buf.push_str("\nSECTIONS\n");
+        buf.push_str(" IDX  KIND  ORIGIN  SIZE\n");
+        for (index, section) in self.sections.iter().enumerate() {
+            let kind = match section.kind {
+                SectionKind::Data => "data",
+                SectionKind::Code => "code",
+            };
+            let origin = match section.origin {
+                None => "reloc".to_owned(),
+                Some(origin) => format!("{:04X}", origin),
+            };
+            buf.push_str(format!("{:4}  {}  {:6}  {:04X}\n", index, kind, origin, section.words.len()).as_str());
+        }
+
+        buf.push_str("\nSYMBOLS\n");
+        buf.push_str(format!(" {:24}  {:7}  {:5}  {:6}  {}\n", "NAME", "SECTION", "VALUE", "KIND", "BIND").as_str());
+        for symbol in &self.symbols {
+            let (section, value) = match symbol.section {
+                None => ("UND".to_owned(), "".to_owned()),
+                Some(index) => (index.to_string(), format!("{:04X}", symbol.value)),
+            };
+            let kind = match symbol.section.map(|index| self.sections[index as usize].kind) {
+                None => SymbolKind::Extern,
+                Some(SectionKind::Data) => SymbolKind::Data,
+                Some(SectionKind::Code) => SymbolKind::Code,
+            };
+            let bind = match (symbol.global, symbol.weak) {
+                (_, true) => "weak",
+                (true, false) => "global",
+                (false, false) => "local",
+            };
+            buf.push_str(format!(" {:24}  {:>7}  {:5}  {:6}  {}\n", symbol.name, section, value, kind.name(), bind).as_str());
+        }
+
+        buf.push_str("\nRELOCATIONS\n");
+        buf.push_str(" SECTION  OFFSET  EXPRESSION\n");
+        for relocation in &self.relocations {
+            buf.push_str(format!(" {:>7}  {:04X}    {}\n", relocation.section, relocation.offset, relocation.expr).as_str());
+        }
+
+        match &self.lines {
+            None => buf.push_str("\nNo debug line table\n"),
+
+            Some(lines) => {
+                buf.push_str("\nDEBUG LINES\n");
+                buf.push_str(" SECTION  OFFSET  LINE\n");
+                for (section, offset, line) in lines {
+                    buf.push_str(format!(" {:>7}  {:04X}    {}\n", section, offset, line + 1).as_str());
+                }
+            }
+        }
+
+        for (index, section) in self.sections.iter().enumerate() {
+            let index = index as u16;
+            let base = section.origin.unwrap_or(0);
+
+            match section.kind {
+                SectionKind::Data => buf.push_str(format!("\nCONTENTS OF SECTION {}\n", index).as_str()),
+                SectionKind::Code => buf.push_str(format!("\nDISASSEMBLY OF SECTION {}\n", index).as_str()),
+            }
+
+            for (offset, word) in section.words.iter().enumerate() {
+                let offset = offset as u32;
+
+                for symbol in self.symbols.iter().filter(|symbol| symbol.section == Some(index) && symbol.value == offset) {
+                    buf.push_str(format!("{}:\n", symbol.name).as_str());
+                }
+
+                let relocation = self.relocations.iter().find(|relocation| relocation.section == index && relocation.offset == offset);
+                let text = match (section.kind, relocation) {
+                    (SectionKind::Data, None) => String::new(),
+                    (SectionKind::Data, Some(relocation)) => relocation.expr.to_string(),
+                    (SectionKind::Code, _) => match Instruction::decode(*word, relocation.map(|relocation| relocation.expr.clone()), 0) {
+                        Err(_) => "???".to_owned(),
+                        Ok(instr) => instr.to_string(),
+                    },
+                };
+
+                buf.push_str(format!("  {:04X}  {:08X}  {}", base + offset, word, text).trim_end());
+                buf.push('\n');
+            }
+        }
+
+        buf
+    }
+}
+
+// Object files from before the binary format are text, their source has to be assembled again
+pub fn read_object(bytes: &[u8], name: &str) -> Result<ObjectModule, String> {
+    if bytes.starts_with(MAGIC) {
+        ObjectFile::read(bytes, name)?.to_module(name)
+    } else if std::str::from_utf8(bytes).map_or(false, is_text_object) {
+        Err(format!("{} is in the old text object format, assemble its source again", name))
+    } else {
+        Err(format!("{} is not an object file", name))
+    }
+}
+
+// The text format started with the number of labels, each on its own `label:value` or `label line` line
+fn is_text_object(s: &str) -> bool {
+    let mut lines = s.lines();
+
+    match lines.next().and_then(|line| line.trim().parse::<usize>().ok()) {
+        None => false,
+
+        Some(header_len) => (0..header_len).all(|_| lines.next().map_or(false, |line| line.contains([':', ' ']))),
+    }
+}
+
+#[pyfunction]
+pub fn objdump(obj: &str, out: Option<&str>) -> PyResult<(bool, String)> {
+    let bytes = match fs::read(obj) {
+        Err(why) => return Ok((false, why.to_string())),
+
+        Ok(bytes) => bytes,
+    };
+
+    // Libraries are dumped one member after the other
+    let members = match bytes.starts_with(library::MAGIC) {
+        true => match Library::read(&bytes, obj) {
+            Err(why) => return Ok((false, why)),
+
+            Ok(library) => library.members.into_iter().map(|member| (format!("{}({})", obj, member.name), member.bytes)).collect(),
+        },
+        false => vec![(obj.to_owned(), bytes)],
+    };
+
+    let mut dump = String::new();
+    for (name, bytes) in members {
+        let file = match bytes.starts_with(MAGIC) {
+            true => ObjectFile::read(&bytes, &name),
+            false => read_object(&bytes, &name).and_then(|module| ObjectFile::from_module(&module)),
+        };
+
+        match file {
+            Err(why) => return Ok((false, why)),
+
+            Ok(file) => {
+                if !dump.is_empty() {
+                    dump.push('\n');
+                }
+                dump.push_str(file.dump(&name).as_str());
+            }
+        }
+    }
+
+    match out {
+        None => Ok((true, dump)),
+
+        Some(out) => match fs::write(out, &dump) {
+            Err(why) => Ok((false, why.to_string())),
+            Ok(_) => Ok((true, dump)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{
+        executable::Executable,
+        layout::Layout,
+        linker::link_objects,
+        testing::{error, module},
+    };
+
+    const SOURCE: &str = "EXTERN put\n.weak fmt\n.global main, fmt\nn: .word 5, put, main + 2\nmsg: .text \"hi\\n\"\nbuf: .fill 3, n\n.org 0x40\nflag: .word .v\n.v: .word -1\nBEGIN\nmain: LDA n + 1\n JAL put\n PRINT msg\n HALT\n.org 0x80\nfmt: JMP main\nEND\n";
+
+    fn written(module: &ObjectModule) -> Vec<u8> {
+        ObjectFile::from_module(module).unwrap().write()
+    }
+
+    fn linked(main: &ObjectModule) -> Executable {
+        link_objects(&[main, &module("io.bdc", ".global put\nBEGIN\nput: RET 0\nEND\n")], &Layout::default()).unwrap()
+    }
+
+    #[test]
+    fn modules_read_back_link_to_the_same_program() {
+        let module = module("main.bdc", SOURCE);
+
+        let bytes = written(&module);
+        let read = read_object(&bytes, "main.bdc").unwrap();
+        assert_eq!(written(&read), bytes);
+        let sorted = |names: &[String]| {
+            let mut names = names.to_vec();
+            names.sort();
+            names
+        };
+        assert_eq!((sorted(&read.exports), sorted(&read.weak), sorted(&read.externs)), (sorted(&module.exports), sorted(&module.weak), sorted(&module.externs)));
+
+        let (original, read) = (linked(&module), linked(&read));
+        assert_eq!((&read.data, &read.code), (&original.data, &original.code));
+        assert_eq!((&read.symbols, &read.lines), (&original.symbols, &original.lines));
+        assert_eq!(read.data[0x40..], [0x10041, u32::MAX]);
+    }
+
+    #[test]
+    fn damaged_object_files_are_rejected() {
+        let bytes = written(&module("main.bdc", SOURCE));
+
+        for len in 0..bytes.len() {
+            assert!(read_object(&bytes[..len], "cut.bdc").is_err(), "read {} of {} bytes", len, bytes.len());
+        }
+        assert_eq!(error(read_object(&bytes[..10], "cut.bdc")), "Unexpected end of file cut.bdc");
+
+        let mut newer = bytes.clone();
+        newer[4] = VERSION as u8 + 1;
+        assert_eq!(error(read_object(&newer, "new.bdc")), format!("Unsupported object file version {} in new.bdc", VERSION + 1));
+        assert_eq!(error(read_object(&[0xFF, 0xFE, 0x00], "junk.bdc")), "junk.bdc is not an object file");
+    }
+
+    #[test]
+    fn text_objects_from_the_old_assembler_ask_to_assemble_again() {
+        // What the assembler wrote before the binary format, for a data word, a text and three instructions
+        let old = b"3\nn:5\nmsg:hi\"\nstart 0\nLDA n\nIRQ 1 msg\nIRQ 0\n";
+
+        assert_eq!(error(read_object(old, "old.bdc")), "old.bdc is in the old text object format, assemble its source again");
+        assert_eq!(error(read_object(b"BEGIN\n HALT\nEND\n", "main.qck")), "main.qck is not an object file");
+        assert_eq!(error(read_object(b"2\nn:5\nHALT\n", "short.bdc")), "short.bdc is not an object file");
+    }
+}
@@ -0,0 +1,117 @@
+use super::{cpu::write_many, executable::Segment};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+struct Installed {
+    program: String,
+    segment: Segment,
+    loaded: bool,
+}
+
+// Overlays of every program in memory, checked each time the CPU fetches an instruction
+static OVERLAYS: Mutex<Vec<Installed>> = Mutex::new(Vec::new());
+
+// Whether any program has overlays, so the CPU only takes the lock when it has to
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Replaces the overlays of `program`, none of them loaded yet
+pub fn install(program: &str, segments: &[Segment]) {
+    let mut overlays = OVERLAYS.lock().unwrap();
+
+    overlays.retain(|installed| installed.program != program);
+    overlays.extend(segments.iter().map(|segment| Installed {
+        program: program.to_owned(),
+        segment: segment.clone(),
+        loaded: false,
+    }));
+    ACTIVE.store(!overlays.is_empty(), Ordering::Relaxed);
+}
+
+pub fn remove(program: &str) {
+    install(program, &[]);
+}
+
+// When `pc` is the stub of an overlay, loads it and the overlays above it that aren't loaded yet
+pub fn enter(pc: u32) -> pyo3::PyResult<()> {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let mut overlays = OVERLAYS.lock().unwrap();
+
+    let target = match overlays.iter().position(|installed| installed.segment.stubs.contains(&pc)) {
+        None => return Ok(()),
+
+        Some(target) => target,
+    };
+    let first = overlays.iter().position(|installed| installed.program == overlays[target].program).unwrap_or(0);
+
+    let mut path = Vec::new();
+    let mut current = Some(target);
+    while let Some(index) = current {
+        path.push(index);
+        current = overlays[index].segment.parent.map(|parent| first + parent);
+    }
+
+    for index in path.into_iter().rev() {
+        if overlays[index].loaded {
+            continue;
+        }
+
+        let (address, size) = (overlays[index].segment.address, overlays[index].segment.code.len() as u32);
+        for other in overlays.iter_mut() {
+            if other.segment.address < address + size && address < other.segment.address + other.segment.code.len() as u32 {
+                other.loaded = false;
+            }
+        }
+
+        unsafe { write_many(address, overlays[index].segment.code.clone())? };
+        overlays[index].loaded = true;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{
+        cpu::{cycle, read_memory, TEST_MACHINE},
+        layout::Layout,
+        linker::link_objects,
+        testing::{error, module},
+    };
+
+    const LAYOUT: &str = "REGION code 0 0x100\nREGION swap 0x100 0x10\nREGION data 0x10000 0x100\nSECTION .code code\nSECTION .data data\nOVERLAY a swap\nOVERLAY b swap\nMODULE a.bdc a\nMODULE b.bdc b\n";
+
+    #[test]
+    fn overlays_sharing_a_region_are_loaded_when_called() {
+        let _machine = TEST_MACHINE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let layout = Layout::parse(LAYOUT, "swap.ld").unwrap();
+        let main = module("main.bdc", "total: .word 0\none: .word 1\nten: .word 10\n.global total, one, ten\nEXTERN add_ten\nEXTERN add_one\nBEGIN main\nmain: JAL add_ten\n JAL add_one\n JAL add_ten\n LDA total\n HALT\nEND\n");
+        let a = module("a.bdc", "EXTERN total\nEXTERN ten\n.global add_ten\nBEGIN\nadd_ten: LDA total\n ADD ten\n STA total\n RET 0\nEND\n");
+        let b = module("b.bdc", "EXTERN total\nEXTERN one\n.global add_one\nBEGIN\nadd_one: LDA total\n ADD one\n STA total\n RET 0\nEND\n");
+
+        let executable = link_objects(&[&main, &a, &b], &layout).unwrap();
+        assert_eq!(executable.overlays(), [("a".to_owned(), 0x100, 4), ("b".to_owned(), 0x100, 4)]);
+
+        unsafe {
+            executable.run(false).unwrap();
+            cycle().unwrap();
+            assert_eq!(read_memory(0x10000).unwrap(), 21);
+        }
+
+        remove("");
+        assert!(!ACTIVE.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn overlay_code_can_not_be_placed_with_org() {
+        let layout = Layout::parse(LAYOUT, "swap.ld").unwrap();
+        let a = module("a.bdc", "BEGIN\n.org 0x100\nf: RET 0\nEND\n");
+
+        assert_eq!(error(link_objects(&[&a], &layout)), "Code placed with .org in a.bdc can't go in overlay a");
+    }
+}
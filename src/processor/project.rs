@@ -0,0 +1,385 @@
+use super::{
+    assembler::assemble,
+    executable::crc32,
+    expression::{parse_string, strip_comment},
+    linker::link,
+};
+use pyo3::prelude::*;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// Manifests are a small part of TOML, one `key = value` per line, `#` starts a comment:
+//   name = "program"
+//   sources = ["main.qck", "io.qck"]
+//   libraries = ["std.lib"]
+//   layout = "memory.ld"
+//   output = "program.fita"
+//   map = true
+//   gc = true
+//   shared = true
+// Only name and sources are required, paths are relative to the manifest. Lists may span lines.
+pub struct Manifest {
+    pub name: String,
+    pub sources: Vec<String>,
+    pub libraries: Vec<String>,
+    pub layout: Option<String>,
+    pub output: String,
+    pub map: bool,
+    pub gc: bool,
+    pub shared: bool,
+}
+
+enum Value {
+    Text(String),
+    List(Vec<String>),
+    Flag(bool),
+}
+
+fn text(s: &str) -> Result<(String, &str), String> {
+    let (bytes, rest) = parse_string(s)?;
+    match String::from_utf8(bytes) {
+        Err(_) => Err("Invalid UTF-8 in string".to_owned()),
+
+        Ok(text) => Ok((text, rest.trim_start())),
+    }
+}
+
+// The items of the list `s` starts with and what follows it, None when `s` ends before the list does
+fn parse_list(s: &str) -> Result<Option<(Vec<String>, &str)>, String> {
+    let mut items = Vec::new();
+    let mut rest = s[1..].trim_start();
+
+    loop {
+        if rest.is_empty() {
+            return Ok(None);
+        }
+
+        if let Some(after) = rest.strip_prefix(']') {
+            return Ok(Some((items, after.trim_start())));
+        }
+
+        let (item, after) = text(rest)?;
+        items.push(item);
+
+        rest = match (after.strip_prefix(','), after.starts_with(']')) {
+            (Some(after), _) => after.trim_start(),
+            (None, true) => after,
+            (None, false) if after.is_empty() => return Ok(None),
+            (None, false) => return Err("Expected , or ] in list".to_owned()),
+        };
+    }
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    let (value, rest) = match s {
+        "true" => (Value::Flag(true), ""),
+        "false" => (Value::Flag(false), ""),
+
+        _ if s.starts_with('"') => {
+            let (value, rest) = text(s)?;
+            (Value::Text(value), rest)
+        }
+
+        _ if s.starts_with('[') => match parse_list(s)? {
+            None => return Err("Missing ] at end of list".to_owned()),
+
+            Some((items, rest)) => (Value::List(items), rest),
+        },
+
+        _ => return Err(format!("Expected string, list, true or false\n\tfound {} instead", s)),
+    };
+
+    match rest.is_empty() {
+        true => Ok(value),
+        false => Err(format!("Unexpected text after value\n\t{}", rest)),
+    }
+}
+
+impl Manifest {
+    pub fn parse(s: &str, name: &str) -> Result<Manifest, String> {
+        let mut values: HashMap<&str, Value> = HashMap::new();
+        let mut lines = s.lines().map(|line| strip_comment(line, "#")).enumerate();
+
+        while let Some((i, line)) = lines.next() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                None => return Err(format!("Expected key = value at line {} in {}", i + 1, name)),
+
+                Some((key, value)) => (key.trim(), value.trim()),
+            };
+
+            // A list goes on until its closing bracket, errors in it are reported by parse_value
+            let mut value = value.to_owned();
+            while value.starts_with('[') && matches!(parse_list(&value), Ok(None)) {
+                match lines.next() {
+                    None => return Err(format!("Unterminated list for {} at line {} in {}", key, i + 1, name)),
+
+                    Some((_, line)) => {
+                        value.push(' ');
+                        value.push_str(line.trim());
+                    }
+                }
+            }
+
+            let value = match parse_value(&value) {
+                Err(why) => return Err(match why.split_once('\n') {
+                    Some((first, rest)) => format!("{} at line {} in {}\n{}", first, i + 1, name, rest),
+                    None => format!("{} at line {} in {}", why, i + 1, name),
+                }),
+
+                Ok(value) => value,
+            };
+
+            let expected = match key {
+                "name" | "layout" | "output" => "string",
+                "sources" | "libraries" => "list",
+                "map" | "gc" | "shared" => "boolean",
+                _ => return Err(format!("Unknown key {} at line {} in {}", key, i + 1, name)),
+            };
+
+            if !matches!((&value, expected), (Value::Text(_), "string") | (Value::List(_), "list") | (Value::Flag(_), "boolean")) {
+                return Err(format!("Expected {} for {} at line {} in {}", expected, key, i + 1, name));
+            }
+
+            if values.insert(key, value).is_some() {
+                return Err(format!("Found repeated key {} at line {} in {}", key, i + 1, name));
+            }
+        }
+
+        let string = |key: &str| match values.get(key) {
+            Some(Value::Text(text)) => Some(text.clone()),
+            _ => None,
+        };
+        let list = |key: &str| match values.get(key) {
+            Some(Value::List(list)) => list.clone(),
+            _ => Vec::new(),
+        };
+        let flag = |key: &str| matches!(values.get(key), Some(Value::Flag(true)));
+
+        let project = match string("name") {
+            None => return Err(format!("Missing name in {}", name)),
+
+            Some(project) => project,
+        };
+        let sources = list("sources");
+        if sources.is_empty() {
+            return Err(format!("Missing sources in {}", name));
+        }
+
+        Ok(Manifest {
+            output: string("output").unwrap_or(format!("{}.fita", project)),
+            name: project,
+            sources,
+            libraries: list("libraries"),
+            layout: string("layout"),
+            map: flag("map"),
+            gc: flag("gc"),
+            shared: flag("shared"),
+        })
+    }
+
+    pub fn read(path: &str) -> Result<Manifest, String> {
+        match fs::read_to_string(path) {
+            Err(why) => Err(format!("Read error: {}", why)),
+
+            Ok(s) => Manifest::parse(&s, path),
+        }
+    }
+}
+
+// Checksum of every source as of its last successful assembly, one `crc path` per line
+fn read_state(path: &Path) -> HashMap<String, u32> {
+    let s = fs::read_to_string(path).unwrap_or_default();
+
+    s.lines()
+        .filter_map(|line| {
+            let (crc, source) = line.split_once(' ')?;
+            Some((source.to_owned(), u32::from_str_radix(crc, 16).ok()?))
+        })
+        .collect()
+}
+
+fn path_str(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[pyfunction]
+pub fn build(manifest: &str, force: Option<bool>) -> PyResult<(bool, String)> {
+    let project = match Manifest::read(manifest) {
+        Err(why) => return Ok((false, why)),
+
+        Ok(project) => project,
+    };
+
+    let root = Path::new(manifest).parent().unwrap_or(Path::new("")).to_path_buf();
+    let state_path = Path::new(manifest).with_extension("build");
+    let previous = match force.unwrap_or(false) {
+        true => HashMap::new(),
+        false => read_state(&state_path),
+    };
+
+    let mut state: Vec<(String, u32)> = Vec::new();
+    let mut messages = Vec::new();
+    let mut errors = Vec::new();
+    let mut objects: Vec<PathBuf> = Vec::new();
+
+    for source in &project.sources {
+        let path = root.join(source);
+        let object = path.with_extension("bdc");
+
+        let crc = match fs::read(&path) {
+            Err(why) => {
+                errors.push(format!("Read error in {}: {}", source, why));
+                continue;
+            }
+
+            Ok(bytes) => crc32(&bytes),
+        };
+
+        if previous.get(source) == Some(&crc) && object.exists() {
+            messages.push(format!("Unchanged {}", source));
+        } else {
+            match assemble(&path_str(&path), Some(&path_str(&object)), None, None)? {
+                (false, why) => {
+                    errors.push(match why.split_once('\n') {
+                        Some((first, rest)) => format!("{} in {}\n{}", first, source, rest),
+                        None => format!("{} in {}", why, source),
+                    });
+                    continue;
+                }
+
+                (true, _) => messages.push(format!("Assembled {}", source)),
+            }
+        }
+
+        state.push((source.clone(), crc));
+        objects.push(object);
+    }
+
+    // Sources that failed are left out, so they are assembled again next time
+    let state: String = state.iter().map(|(source, crc)| format!("{:08X} {}\n", crc, source)).collect();
+    if let Err(why) = fs::write(&state_path, state) {
+        errors.push(why.to_string());
+    }
+
+    // What was assembled is still reported, the objects it wrote are up to date
+    if !errors.is_empty() {
+        messages.extend(errors);
+        return Ok((false, messages.join("\n")));
+    }
+
+    let inputs: Vec<String> = objects.iter().map(|object| path_str(object)).chain(project.libraries.iter().map(|library| path_str(&root.join(library)))).collect();
+    let output = root.join(&project.output);
+    let map = output.with_extension("map");
+    let layout = project.layout.as_ref().map(|layout| path_str(&root.join(layout)));
+
+    let (linked, message) = link(
+        inputs.iter().map(|input| input.as_str()).collect(),
+        Some(&path_str(&output)),
+        project.map.then(|| path_str(&map)).as_deref(),
+        Some(project.gc),
+        layout.as_deref(),
+        Some(project.shared),
+    )?;
+    if !linked {
+        messages.push(message);
+        return Ok((false, messages.join("\n")));
+    }
+
+    let mut report = format!("Build successful: {}", project.output);
+    for line in messages.iter().map(|line| line.as_str()).chain(message.lines().skip(1)) {
+        report.push('\n');
+        report.push_str(line);
+    }
+
+    Ok((true, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::testing::{error, scratch};
+
+    fn manifest_error(s: &str) -> String {
+        error(Manifest::parse(s, "app.toml"))
+    }
+
+    #[test]
+    fn manifests_fill_in_what_they_leave_out() {
+        let manifest = Manifest::parse("# app\nname = \"app\"\nsources = [\"main.qck\"] # one\n", "app.toml").unwrap();
+        assert_eq!(manifest.name, "app");
+        assert_eq!(manifest.sources, ["main.qck"]);
+        assert!(manifest.libraries.is_empty());
+        assert_eq!(manifest.layout, None);
+        assert_eq!(manifest.output, "app.fita");
+        assert!(!manifest.map && !manifest.gc);
+
+        let manifest = Manifest::parse(
+            "name = \"a#b\"\nsources = [\n  \"main.qck\",\n  \"io.qck\", # io\n]\nlibraries = []\nlayout = \"memory.ld\"\noutput = \"out.fita\"\nmap = true\ngc = false\n",
+            "app.toml",
+        )
+        .unwrap();
+        assert_eq!(manifest.name, "a#b");
+        assert_eq!(manifest.sources, ["main.qck", "io.qck"]);
+        assert!(manifest.libraries.is_empty());
+        assert_eq!(manifest.layout.as_deref(), Some("memory.ld"));
+        assert_eq!(manifest.output, "out.fita");
+        assert!(manifest.map && !manifest.gc);
+
+        let manifest = Manifest::parse("name = \"app\"\nsources = [\"v[1]\",\n  \"io.qck\"]\n", "app.toml").unwrap();
+        assert_eq!(manifest.sources, ["v[1]", "io.qck"]);
+    }
+
+    #[test]
+    fn manifest_errors_say_where_they_are() {
+        let sources = "sources = [\"main.qck\"]\n";
+        for (s, why) in [
+            ("name\n", "Expected key = value at line 1 in app.toml"),
+            ("name = \"app\"\nsources = [\"main.qck\",\n", "Unterminated list for sources at line 2 in app.toml"),
+            ("name = app\n", "Expected string, list, true or false at line 1 in app.toml\n\tfound app instead"),
+            ("name = \"app\" x\n", "Unexpected text after value at line 1 in app.toml\n\tx"),
+            ("sources = [\"main.qck\"] x\nname = \"app\"\n", "Unexpected text after value at line 1 in app.toml\n\tx"),
+            ("sources = [\"a\" \"b\"]\n", "Expected , or ] in list at line 1 in app.toml"),
+            ("version = \"1\"\n", "Unknown key version at line 1 in app.toml"),
+            ("map = \"yes\"\n", "Expected boolean for map at line 1 in app.toml"),
+            ("sources = \"main.qck\"\n", "Expected list for sources at line 1 in app.toml"),
+            ("name = \"a\"\nname = \"b\"\n", "Found repeated key name at line 2 in app.toml"),
+            (sources, "Missing name in app.toml"),
+            ("name = \"app\"\nsources = []\n", "Missing sources in app.toml"),
+        ] {
+            assert_eq!(manifest_error(s), why);
+        }
+
+        assert!(Manifest::read("missing.toml").is_err());
+    }
+
+    #[test]
+    fn failed_builds_still_report_what_was_assembled() {
+        let root = scratch(
+            "failed-build",
+            &[
+                ("app.toml", "name = \"app\"\nsources = [\"main.qck\", \"broken.qck\"]\n"),
+                ("main.qck", "BEGIN main\nmain: HALT\nEND\n"),
+                ("broken.qck", "n: .word 1\nBEGIN\n JMP n\nEND\n"),
+            ],
+        );
+        let manifest = path_str(&root.join("app.toml"));
+
+        let (built, report) = build(&manifest, None).unwrap();
+        assert!(!built);
+        assert_eq!(report, "Assembled main.qck\nJMP at line 3 expects a code label in broken.qck\n\tfound data label n");
+
+        fs::write(root.join("broken.qck"), "util: .word 0\nBEGIN\n HALT\nEND\n").unwrap();
+        let (built, report) = build(&manifest, None).unwrap();
+        assert!(built, "{}", report);
+        assert_eq!(report.lines().take(3).collect::<Vec<&str>>(), ["Build successful: app.fita", "Unchanged main.qck", "Assembled broken.qck"]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::{fs, sync::Mutex};
+
+struct Tape {
+    name: String,
+    words: Vec<u32>,
+    position: usize,
+}
+
+// While a tape is mounted READ takes its words from it instead of waiting for the keyboard
+static TAPE: Mutex<Option<Tape>> = Mutex::new(None);
+
+// The tape holds the file's bytes as little endian words, the last one padded with zeros
+pub fn mount(name: &str, bytes: &[u8]) {
+    let words = bytes
+        .chunks(4)
+        .map(|chunk| {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u32::from_le_bytes(word)
+        })
+        .collect();
+
+    *TAPE.lock().unwrap() = Some(Tape {
+        name: name.to_owned(),
+        words,
+        position: 0,
+    });
+}
+
+pub fn unmount() {
+    *TAPE.lock().unwrap() = None;
+}
+
+// Next word under the head, None when no tape is mounted or it ran out
+pub fn next() -> Option<u32> {
+    let mut tape = TAPE.lock().unwrap();
+    let tape = tape.as_mut()?;
+
+    let word = tape.words.get(tape.position).copied()?;
+    tape.position += 1;
+    Some(word)
+}
+
+#[pyfunction]
+pub fn mount_tape(fita: &str) -> PyResult<()> {
+    match fs::read(fita) {
+        Err(why) => Err(PyValueError::new_err(format!("Read error: {}", why))),
+
+        Ok(bytes) => {
+            mount(fita, &bytes);
+            Ok(())
+        }
+    }
+}
+
+#[pyfunction]
+pub fn unmount_tape() -> PyResult<()> {
+    unmount();
+    Ok(())
+}
+
+// Name, words read and length of the mounted tape
+#[pyfunction]
+pub fn get_tape() -> PyResult<Option<(String, usize, usize)>> {
+    Ok(TAPE.lock().unwrap().as_ref().map(|tape| (tape.name.clone(), tape.position, tape.words.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::cpu::TEST_MACHINE;
+
+    #[test]
+    fn tapes_hold_little_endian_words() {
+        let _machine = TEST_MACHINE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        assert_eq!(next(), None);
+
+        mount("tape", &[0x46, 0x49, 0x54, 0x41, 1, 2, 3, 4, 5]);
+        assert_eq!(get_tape().unwrap(), Some(("tape".to_owned(), 0, 3)));
+        assert_eq!(next(), Some(0x41544946));
+        assert_eq!(next(), Some(0x04030201));
+        assert_eq!(next(), Some(5));
+        assert_eq!(next(), None);
+        assert_eq!(get_tape().unwrap(), Some(("tape".to_owned(), 3, 3)));
+
+        unmount();
+        assert_eq!(get_tape().unwrap(), None);
+        assert_eq!(next(), None);
+    }
+}
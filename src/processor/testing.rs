@@ -0,0 +1,38 @@
+use super::{assembler::assemble_module, object::ObjectModule};
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// A module assembled from `s`, named as if it was read from `name`
+pub fn module(name: &str, s: &str) -> ObjectModule {
+    let mut module = assemble_module(s, HashMap::new()).unwrap();
+    module.name = name.to_owned();
+    module
+}
+
+// The error a call was expected to fail with
+pub fn error<T>(result: Result<T, String>) -> String {
+    match result {
+        Err(why) => why,
+        Ok(_) => panic!("expected an error"),
+    }
+}
+
+static SCRATCH: AtomicUsize = AtomicUsize::new(0);
+
+// A fresh directory named after the test, holding the given files. Every call gets its own,
+// so tests running at the same time don't share files
+pub fn scratch(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let root = env::temp_dir().join(format!("sisprog-{}-{}-{}", test, process::id(), SCRATCH.fetch_add(1, Ordering::Relaxed)));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    for (name, s) in files {
+        fs::write(root.join(name), s).unwrap();
+    }
+    root
+}
@@ -0,0 +1,326 @@
+use super::{
+    executable::{self, Executable},
+    layout::Layout,
+    linker::{cross_reference, read_inputs},
+    object::{Instruction, ObjectModule, SymbolKind},
+};
+use pyo3::prelude::*;
+use std::fs;
+
+// An instruction or data word holding a symbol's address
+#[derive(PartialEq)]
+pub struct Reference {
+    pub module: Option<String>,
+    pub line: Option<usize>,
+    pub address: u32,
+}
+
+pub struct Definition {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub address: u32,
+    pub module: Option<String>,
+    pub line: Option<usize>,
+    pub references: Vec<Reference>,
+}
+
+fn location(module: &Option<String>, line: Option<usize>) -> String {
+    match (module, line) {
+        (Some(module), Some(line)) => format!("{} line {}", module, line + 1),
+        (Some(module), None) => module.clone(),
+        (None, _) => "?".to_owned(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut buf = String::from("\"");
+
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => buf.push_str(format!("\\u{:04x}", c as u32).as_str()),
+            c => buf.push(c),
+        }
+    }
+
+    buf.push('"');
+    buf
+}
+
+fn json_location(module: &Option<String>, line: Option<usize>) -> String {
+    let module = match module {
+        None => "null".to_owned(),
+        Some(module) => json_string(module),
+    };
+    let line = match line {
+        None => "null".to_owned(),
+        Some(line) => (line + 1).to_string(),
+    };
+
+    format!("\"module\": {}, \"line\": {}", module, line)
+}
+
+pub fn to_text(definitions: &[Definition], name: &str) -> String {
+    let mut buf = String::new();
+
+    buf.push_str(format!("Cross reference for {}\n\n", name).as_str());
+    buf.push_str(format!(" {:24}  {:4}  {:7}  {}\n", "SYMBOL", "KIND", "ADDRESS", "DEFINED AT").as_str());
+    for definition in definitions {
+        buf.push_str(
+            format!(
+                " {:24}  {:4}  {:05X}    {}\n",
+                definition.name,
+                definition.kind.name(),
+                definition.address,
+                location(&definition.module, definition.line)
+            )
+            .as_str(),
+        );
+
+        for reference in &definition.references {
+            buf.push_str(format!("     referenced at {:05X} in {}\n", reference.address, location(&reference.module, reference.line)).as_str());
+        }
+    }
+
+    buf
+}
+
+// Lines are 1-based, as in the text report, and null when unknown
+pub fn to_json(definitions: &[Definition]) -> String {
+    let symbols: Vec<String> = definitions
+        .iter()
+        .map(|definition| {
+            let references: Vec<String> = definition
+                .references
+                .iter()
+                .map(|reference| format!("{{\"address\": {}, {}}}", reference.address, json_location(&reference.module, reference.line)))
+                .collect();
+
+            format!(
+                "  {{\"name\": {}, \"kind\": \"{}\", \"address\": {}, {}, \"references\": [{}]}}",
+                json_string(&definition.name),
+                definition.kind.name(),
+                definition.address,
+                json_location(&definition.module, definition.line),
+                references.join(", ")
+            )
+        })
+        .collect();
+
+    format!("[\n{}\n]\n", symbols.join(",\n"))
+}
+
+// Rebuilds the references from the words a linked program relocates, or from every operand
+// when it carries no relocations
+pub fn from_executable(executable: &Executable) -> Vec<Definition> {
+    let data_end = executable.data_address + executable.data.len() as u32;
+    let source = |address: u32| match executable.lines.iter().find(|(other, _, _)| *other == address) {
+        None => (None, None),
+
+        Some((_, file, line)) => (executable.files.get(*file as usize).cloned(), Some(*line as usize)),
+    };
+
+    let mut definitions: Vec<Definition> = executable
+        .symbols
+        .iter()
+        .map(|(name, address)| {
+            let (module, line) = source(*address);
+            Definition {
+                name: name.clone(),
+                kind: match (executable.data_address..data_end).contains(address) {
+                    true => SymbolKind::Data,
+                    false => SymbolKind::Code,
+                },
+                address: *address,
+                module,
+                line,
+                references: Vec::new(),
+            }
+        })
+        .collect();
+
+    let operand = |word: u32| match Instruction::decode(word, None, 0) {
+        Ok(instr) if instr.expects().is_some() => instr.operand.and_then(|operand| operand.value()),
+        _ => None,
+    };
+    let code_end = executable.code_address + executable.code.len() as u32;
+
+    let uses: Vec<(u32, u32)> = match &executable.relocations {
+        Some(relocations) => relocations
+            .iter()
+            .filter_map(|(address, _)| match (executable.code_address..code_end).contains(address) {
+                true => operand(executable.code[(address - executable.code_address) as usize]).map(|value| (*address, value)),
+                false => executable.data.get(address.wrapping_sub(executable.data_address) as usize).map(|value| (*address, *value)),
+            })
+            .collect(),
+
+        None => (executable.code_address..)
+            .zip(&executable.code)
+            .filter_map(|(address, word)| operand(*word).map(|value| (address, value)))
+            .collect(),
+    };
+
+    // Labels sharing an address can't be told apart once linked
+    for (address, value) in uses {
+        for definition in definitions.iter_mut().filter(|definition| definition.address == value) {
+            let (module, line) = source(address);
+            definition.references.push(Reference { module, line, address });
+        }
+    }
+
+    definitions
+}
+
+fn read_definitions(files: &[&str], layout: Option<&str>) -> Result<Vec<Definition>, String> {
+    if let [fita] = files {
+        let bytes = match fs::read(fita) {
+            Err(why) => return Err(why.to_string()),
+
+            Ok(bytes) => bytes,
+        };
+
+        if bytes.starts_with(executable::MAGIC) || fita.ends_with(".fita") {
+            return Executable::from_bytes(&bytes, fita).map(|executable| from_executable(&executable));
+        }
+    }
+
+    let layout = match layout {
+        None => Layout::default(),
+        Some(layout) => Layout::read(layout)?,
+    };
+    let (modules, shared) = read_inputs(files)?;
+
+    cross_reference(&modules.iter().collect::<Vec<&ObjectModule>>(), &shared, &layout)
+}
+
+#[pyfunction]
+pub fn xref(files: Vec<&str>, out: Option<&str>, json: Option<bool>, layout: Option<&str>) -> PyResult<(bool, String)> {
+    let definitions = match read_definitions(&files, layout) {
+        Err(why) => return Ok((false, why)),
+
+        Ok(definitions) => definitions,
+    };
+
+    let report = match json.unwrap_or(false) {
+        true => to_json(&definitions),
+        false => to_text(&definitions, files.join(", ").as_str()),
+    };
+
+    match out {
+        None => Ok((true, report)),
+
+        Some(out) => match fs::write(out, &report) {
+            Err(why) => Ok((false, why.to_string())),
+            Ok(_) => Ok((true, report)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{linker::link_objects, testing::module};
+
+    // Both modules keep a label n of their own
+    const MAIN: &str = "n: .word 3\np: .word n\nEXTERN twice\nBEGIN main\nmain: LDA n\n JAL twice\n HALT\nEND\n";
+    const TWICE: &str = "n: .word 4\n.global twice\nBEGIN\ntwice: ADD n\n RET 0\nEND\n";
+
+    fn modules(names: [&str; 2]) -> Vec<ObjectModule> {
+        [MAIN, TWICE]
+            .iter()
+            .zip(names)
+            .map(|(s, name)| module(name, s))
+            .collect()
+    }
+
+    fn references(definitions: &[Definition]) -> Vec<(String, u32, Vec<u32>)> {
+        definitions
+            .iter()
+            .map(|definition| (definition.name.clone(), definition.address, definition.references.iter().map(|reference| reference.address).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn references_go_to_the_label_in_scope() {
+        for names in [["main.bdc", "twice.bdc"], ["lib/x.bdc", "lib/x.bdc"]] {
+            let modules = modules(names);
+            let modules: Vec<&ObjectModule> = modules.iter().collect();
+
+            assert_eq!(
+                references(&cross_reference(&modules, &[], &Layout::default()).unwrap()),
+                [
+                    ("main".to_owned(), 0, vec![]),
+                    ("twice".to_owned(), 3, vec![1]),
+                    ("n".to_owned(), 0x10000, vec![0x10001, 0]),
+                    ("p".to_owned(), 0x10001, vec![]),
+                    ("n".to_owned(), 0x10002, vec![3]),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn linked_programs_give_the_references_they_relocate() {
+        let modules = modules(["main.bdc", "twice.bdc"]);
+        let modules: Vec<&ObjectModule> = modules.iter().collect();
+        let mut executable = link_objects(&modules, &Layout::default()).unwrap();
+
+        let mut expected = references(&cross_reference(&modules, &[], &Layout::default()).unwrap());
+        expected.sort();
+        let mut linked = references(&from_executable(&executable));
+        linked.sort();
+        assert_eq!(linked, expected);
+
+        // Without relocations only operands are known to hold addresses
+        executable.relocations = None;
+        let mut linked = references(&from_executable(&executable));
+        linked.sort();
+        assert_eq!(linked[0], ("main".to_owned(), 0, vec![]));
+        assert_eq!(linked[1], ("n".to_owned(), 0x10000, vec![0]));
+    }
+
+    #[test]
+    fn reports_show_where_labels_are_defined_and_used() {
+        let modules = modules(["main.bdc", "twice.bdc"]);
+        let modules: Vec<&ObjectModule> = modules.iter().collect();
+        let definitions = cross_reference(&modules, &[], &Layout::default()).unwrap();
+
+        assert_eq!(
+            to_text(&definitions[..3], "main.bdc, twice.bdc"),
+            "Cross reference for main.bdc, twice.bdc
+
+ SYMBOL                    KIND  ADDRESS  DEFINED AT
+ main                      code  00000    main.bdc line 5
+ twice                     code  00003    twice.bdc line 4
+     referenced at 00001 in main.bdc line 6
+ n                         data  10000    main.bdc line 1
+     referenced at 10001 in main.bdc line 2
+     referenced at 00000 in main.bdc line 5
+"
+        );
+
+        assert_eq!(
+            to_json(&definitions[1..2]),
+            "[
+  {\"name\": \"twice\", \"kind\": \"code\", \"address\": 3, \"module\": \"twice.bdc\", \"line\": 4, \"references\": [{\"address\": 1, \"module\": \"main.bdc\", \"line\": 6}]}
+]
+"
+        );
+
+        let unknown = Definition {
+            name: "a\"b\\c\n\u{1}".to_owned(),
+            kind: SymbolKind::Code,
+            address: 7,
+            module: None,
+            line: None,
+            references: Vec::new(),
+        };
+        assert_eq!(
+            to_json(&[unknown]),
+            "[\n  {\"name\": \"a\\\"b\\\\c\\n\\u0001\", \"kind\": \"code\", \"address\": 7, \"module\": null, \"line\": null, \"references\": []}\n]\n"
+        );
+    }
+}
@@ -14,7 +14,7 @@ from pythonLib.memoryApps import memoryApps
 from pythonLib.interface import interface
 from pythonLib.codePeeker import codePeeker
 from pythonLib.memoryDump import memoryDump
-from sisprog import assemble, link, execute, CPUState, get_state, get_print, cycle, feed_read
+from sisprog import assemble, archive, build, link, objdump, xref, execute, CPUState, get_state, get_print, cycle, feed_read
 
 class _cmdLine(Widget):
     _instance = None
@@ -37,6 +37,7 @@ class _cmdLine(Widget):
         # "run",
         "simulate",
         "load",
+        "boot",
         "unload",
         "delete",
         "peek",
@@ -44,6 +45,10 @@ class _cmdLine(Widget):
         "link",
         "assemble",
         "link",
+        "objdump",
+        "lib",
+        "xref",
+        "build",
         "step",
         "see",
     ]
@@ -165,9 +170,7 @@ class _cmdLine(Widget):
             self.printError("Faltam argumentos para " + args[0])  
         elif len(args) == 2:
             if memoryApps().appsList.count(args[1]) == 1:
-                index = memoryApps().appsList.index(args[1])
-                instStart = memoryApps().appsPos[index][2]
-                execute(instStart, True)
+                execute(memoryApps().entry(args[1]), True)
                 while True:
                     self.printSuccess(str(get_state()))
                     if get_state() == CPUState.OUTPUT:
@@ -192,9 +195,7 @@ class _cmdLine(Widget):
                 interface().changeMode("Simulation")
                 codePeeker("Simulation").setPath(args[1][:-4] + "qck")
                 codePeeker("Simulation").activeLine = codePeeker("Simulation").startLine+2
-                index = memoryApps().appsList.index(args[1])
-                instStart = memoryApps().appsPos[index][2]
-                execute(instStart, True)
+                execute(memoryApps().entry(args[1]), True)
             else:
                 self.printError("Arquivo não está na memória: " + args[1])
         else:
@@ -207,7 +208,11 @@ class _cmdLine(Widget):
             if args[1][-4:] == "fita":
                 if os.path.exists("./root/" + args[1]):
                     if memoryApps().appsList.count(args[1]) == 0:
-                        memoryApps().addApp(args[1])
+                        try:
+                            memoryApps().addApp(args[1])
+                        except ValueError as why:
+                            self.printError(str(why))
+                            return
                         interface().refresher()
                         self.printSuccess(args[1] + " adicionado a memória")
                     else:
@@ -219,6 +224,29 @@ class _cmdLine(Widget):
         else:
             self.printError("Argumentos demais: " + str(args[2:]))
     
+    def cmdBoot(self, args: iter):
+        if len(args) == 1:
+            self.printError("Faltam argumentos para " + args[0])  
+        elif len(args) == 2:
+            if args[1][-4:] == "fita":
+                if os.path.exists("./root/" + args[1]):
+                    if memoryApps().appsList.count(args[1]) == 0:
+                        try:
+                            memoryApps().bootApp(args[1])
+                        except ValueError as why:
+                            self.printError(str(why))
+                            return
+                        interface().refresher()
+                        self.printSuccess(args[1] + " lido da fita pelo loader")
+                    else:
+                        self.printError("Arquivo já carregado")
+                else:
+                    self.printError("Arquivo inexistente: " + args[1])
+            else:
+                self.printError("Só é possível fazer BOOT de arquivos '.fita'")
+        else:
+            self.printError("Argumentos demais: " + str(args[2:]))
+    
     def cmdUnload(self, args: iter):
         if len(args) == 1:
             self.printError("Faltam argumentos para " + args[0])  
@@ -301,6 +329,18 @@ class _cmdLine(Widget):
             self.printError("Argumentos demais: " + args[4:])
     
     def cmdLink(self, args: iter):
+        writeMap = "-m" in args
+        removeDead = "-g" in args
+        shared = "-s" in args
+        args = [arg for arg in args if arg != "-m" and arg != "-g" and arg != "-s"]
+        layoutFile = None
+        if args.count("-l") != 0:
+            k = args.index("-l")
+            if k == len(args) - 1:
+                self.printError("Falta o arquivo de layout depois de '-l'")
+                return
+            layoutFile = "./root/" + args[k + 1]
+            args = args[:k] + args[k + 2:]
         if len(args) == 1:
             self.printError("Faltam argumentos para " + args[0])
         elif args.count("-o") == 0:
@@ -314,9 +354,15 @@ class _cmdLine(Widget):
             if len(pathError) != 0:
                 self.printError("Arquivos não encontrados: " + str(pathError))
             else:
-                result = link(toLink, "./root/" + args[1][:-3] + "fita")
+                out = "./root/" + args[1][:-3] + "fita"
+                result = link(toLink, out, os.path.splitext(out)[0] + ".map" if writeMap else None, removeDead, layoutFile, shared)
                 if result[0]:
                     self.printSuccess("Linked " + str(args[1:]))
+                    for line in result[1].splitlines()[1:]:
+                        if line.startswith("Removed"):
+                            self.printExit(line)
+                        else:
+                            self.printError(line)
                     interface().refresher()
                 else:
                     self.printError(result[1])
@@ -332,15 +378,105 @@ class _cmdLine(Widget):
                 if len(pathError) != 0:
                     self.printError("Arquivos não encontrados: " + str(pathError))
                 else:
-                    result = link(toLink, "./root/" + args[-1])
+                    out = "./root/" + args[-1]
+                    result = link(toLink, out, os.path.splitext(out)[0] + ".map" if writeMap else None, removeDead, layoutFile, shared)
                     if result[0]:
                         self.printSuccess("Linked " + str(args[1:-2]) + " to " + args[-1])
+                        for line in result[1].splitlines()[1:]:
+                            if line.startswith("Removed"):
+                                self.printExit(line)
+                            else:
+                                self.printError(line)
                         interface().refresher()
                     else:
                         self.printError(result[1])
             else:
                 self.printError("Posicao errada do argumento '-o'")
     
+    def cmdObjdump(self, args: iter):
+        if len(args) == 1:
+            self.printError("Faltam argumentos para " + args[0])
+        elif len(args) == 2:
+            if os.path.exists("./root/" + args[1]):
+                result = objdump("./root/" + args[1], "./root/" + args[1][:-3] + "txt")
+                if result[0]:
+                    self.printSuccess("Dumped " + args[1] + " into " + args[1][:-3] + "txt")
+                    interface().refresher()
+                else:
+                    self.printError(result[1])
+            else:
+                self.printError("Arquivo inexistente: " + args[1])
+        else:
+            self.printError("Argumentos demais: " + str(args[2:]))
+    
+    def cmdLib(self, args: iter):
+        if len(args) < 3:
+            self.printError("Faltam argumentos para " + args[0])
+        else:
+            toArchive = list()
+            pathError = list()
+            for k in range(2, len(args)):
+                if os.path.exists("./root/" + args[k]):
+                    toArchive.append("./root/" + args[k])
+                else:
+                    pathError.append(args[k])
+            if len(pathError) != 0:
+                self.printError("Arquivos não encontrados: " + str(pathError))
+            else:
+                result = archive(toArchive, "./root/" + args[1])
+                if result[0]:
+                    self.printSuccess("Archived " + str(args[2:]) + " into " + args[1])
+                    interface().refresher()
+                else:
+                    self.printError(result[1])
+
+    def cmdXref(self, args: iter):
+        asJson = "-j" in args
+        args = [arg for arg in args if arg != "-j"]
+        if len(args) == 1:
+            self.printError("Faltam argumentos para " + args[0])
+        else:
+            toRead = list()
+            pathError = list()
+            for k in range(1, len(args)):
+                if os.path.exists("./root/" + args[k]):
+                    toRead.append("./root/" + args[k])
+                else:
+                    pathError.append(args[k])
+            if len(pathError) != 0:
+                self.printError("Arquivos não encontrados: " + str(pathError))
+            else:
+                out = os.path.splitext(args[1])[0] + (".xref.json" if asJson else ".xref.txt")
+                result = xref(toRead, "./root/" + out, asJson)
+                if result[0]:
+                    self.printSuccess("Cross referenced " + str(args[1:]) + " into " + out)
+                    interface().refresher()
+                else:
+                    self.printError(result[1])
+
+    def cmdBuild(self, args: iter):
+        force = "-f" in args
+        args = [arg for arg in args if arg != "-f"]
+        if len(args) > 2:
+            self.printError("Argumentos demais: " + str(args[2:]))
+        else:
+            manifest = args[1] if len(args) == 2 else "sisprog.toml"
+            if os.path.exists("./root/" + manifest):
+                result = build("./root/" + manifest, force)
+                if result[0]:
+                    lines = result[1].splitlines()
+                    self.printSuccess(lines[0])
+                    for line in lines[1:]:
+                        if line.startswith("Warning"):
+                            self.printError(line)
+                        else:
+                            self.printExit(line)
+                    interface().refresher()
+                else:
+                    self.printError(result[1])
+            else:
+                self.printError("Arquivo inexistente: " + manifest)
+
     def cmdStep(self, args: iter):
         if get_state() == CPUState.IDLE:
             self.printError("A simulação já acabou")
@@ -378,6 +514,8 @@ class _cmdLine(Widget):
             self.cmdSimulate(cmd)
         elif cmd[0] == "load":
             self.cmdLoad(cmd)
+        elif cmd[0] == "boot":
+            self.cmdBoot(cmd)
         elif cmd[0] == "unload":
             self.cmdUnload(cmd)
         elif cmd[0] == "delete":
@@ -388,6 +526,14 @@ class _cmdLine(Widget):
             self.cmdAssemble(cmd)
         elif cmd[0] == "link":
             self.cmdLink(cmd)
+        elif cmd[0] == "objdump":
+            self.cmdObjdump(cmd)
+        elif cmd[0] == "lib":
+            self.cmdLib(cmd)
+        elif cmd[0] == "xref":
+            self.cmdXref(cmd)
+        elif cmd[0] == "build":
+            self.cmdBuild(cmd)
         elif cmd[0] == "step":
             self.cmdStep(cmd)
         elif cmd[0] == "see":
@@ -10,8 +10,13 @@ class helpList(Widget):
     
     helpContents = [
         ["[b]ASSEMBLE [i]arquivo[/i] \[-o saida][/]", "Monta [i]arquivo[i/]"],
-        ["[b]LINK [i]arquivos[/i] \[-o saida][/]", "Liga [i]arquivo[/i]"],
+        ["[b]LINK [i]arquivos[/i] \[-o saida] \[-m] \[-g] \[-l layout] \[-s][/]", "Liga [i]arquivo[/i], -m gera o mapa de ligação, -g remove código não usado, -l usa as regiões de memória de [i]layout[/i], -s gera um módulo compartilhado"],
+        ["[b]BUILD \[projeto] \[-f][/]", "Monta os fontes alterados do [i]projeto[/i] (sisprog.toml) e liga, -f monta todos"],
+        ["[b]LIB [i]saida arquivos[/]", "Junta os [i]arquivos[/i] na biblioteca [i]saida[/i]"],
+        ["[b]OBJDUMP [i]arquivo[/]", "Descreve o objeto [i]arquivo[/i] em um .txt"],
+        ["[b]XREF [i]arquivos[/i] \[-j][/]", "Lista onde cada símbolo de [i]arquivos[/i] é definido e usado, -j gera JSON"],
         ["[b]LOAD [i]arquivo[/]", "Carrega [i]arquivo[/i] na memória"],
+        ["[b]BOOT [i]arquivo[/]", "Roda o loader na CPU para ler [i]arquivo[/i] da fita e colocá-lo onde foi ligado"],
         ["[b]UNLOAD [i]arquivo[/]", "Descarrega [i]arquivo[/i] da memória"],
         ["[b]PEEK [i]arquivo[/]", "Abre uma prévia do [i]arquivo[/i]"],
         ["[b]DELETE [i]arquivo[/]", "Apaga [i]arquivo[/i] da pasta [b]src[/]"],
@@ -1,12 +1,131 @@
-from sisprog import  print_debug, assemble, link
+import json
+import os
+import tempfile
+
+from sisprog import print_debug, assemble, link, build, xref, execute, cycle, get_acc, Loader
+
+MAIN = """n: .word 20
+EXTERN twice
+BEGIN main
+main: LDA n
+ JAL twice
+ HALT
+END
+"""
+
+TWICE = """.global twice
+BEGIN
+twice: ADD n
+ RET 0
+n: .word 0
+END
+"""
+
+IO = """v: .word 42
+.global get
+BEGIN
+ HALT
+get: LDA v
+ RET 0
+END
+"""
+
+USER = """EXTERN get
+BEGIN main
+main: JAL get
+ HALT
+END
+"""
+
+def write(folder, name, s):
+    path = os.path.join(folder, name)
+    with open(path, "w") as file:
+        file.write(s)
+    return path
+
+def run(entry):
+    execute(entry, False)
+    cycle()
+    return get_acc()
+
+def check_assemble_and_link(folder):
+    main = write(folder, "main.qck", MAIN)
+    twice = write(folder, "twice.qck", TWICE)
+
+    assert assemble(twice, os.path.join(folder, "twice.bdc")) == (False, "Found .word directive in code section at line 5")
+    write(folder, "twice.qck", "n: .word 22\n" + TWICE.replace("n: .word 0\n", ""))
+
+    for source in (main, twice):
+        assert assemble(source, source.replace(".qck", ".bdc")) == (True, "Assembly successful")
+
+    objects = [os.path.join(folder, "main.bdc"), os.path.join(folder, "twice.bdc")]
+    fita = os.path.join(folder, "main.fita")
+    assert link(objects[:1], fita)[0] is False
+    assert link(objects, fita) == (True, "Linking successful")
+
+    loader = Loader()
+    assert run(loader.load("main", fita)) == 42
+    loader.unload("main")
+
+    linked, report = xref(objects)
+    assert linked and "referenced at" in report, report
+    symbols = json.loads(xref([fita], None, True)[1])
+    assert [symbol["name"] for symbol in symbols if symbol["kind"] == "code"] == ["main", "twice"]
+
+def check_build(folder):
+    manifest = write(folder, "app.toml", 'name = "app"\nsources = [\n  "main.qck",\n  "twice.qck",\n]\n')
+
+    built, report = build(manifest)
+    assert built and report.startswith("Build successful: app.fita"), report
+    assert "Unchanged main.qck" in build(manifest)[1]
+    assert "Assembled main.qck" in build(manifest, True)[1]
+
+    write(folder, "app.toml", 'name = "app"\n')
+    assert build(manifest) == (False, "Missing sources in " + manifest)
+
+def check_loader(folder):
+    for name, s in (("io", IO), ("user", USER)):
+        assert assemble(write(folder, name + ".qck", s), os.path.join(folder, name + ".bdc"))[0]
+    io = os.path.join(folder, "io.fita")
+    user = os.path.join(folder, "user.fita")
+    assert link([os.path.join(folder, "io.bdc")], io, None, None, None, True)[0]
+    assert link([os.path.join(folder, "user.bdc"), io], user)[0]
+
+    loader = Loader()
+    try:
+        loader.load("user", user)
+        assert False, "loaded without its shared module"
+    except ValueError:
+        pass
+
+    loader.load(io, io)
+    assert run(loader.load("user", user)) == 42
+    try:
+        loader.unload(io)
+        assert False, "unloaded a shared module still in use"
+    except ValueError as why:
+        assert str(why) == io + " is still used by user"
+    loader.unload("user")
+    loader.unload(io)
+
+    loader.load("loader", "loader.fita", True)
+    assert run(loader.boot("main", os.path.join(folder, "main.fita"))) == 42
+    assert [program[0] for program in loader.programs] == ["loader", "main"]
 
 if __name__ == "__main__":
-    assemblyResult = assemble("div.qck", "div.bdc")[1]
-    if assemblyResult == "Assembly successful":
-        linkingResult = link(["div.bdc"], "div.fita")[1]
-        if linkingResult == "Linking successful":
-            print_debug("div.fita")
+    if os.path.exists("div.qck"):
+        assemblyResult = assemble("div.qck", "div.bdc")[1]
+        if assemblyResult == "Assembly successful":
+            linkingResult = link(["div.bdc"], "div.fita")[1]
+            if linkingResult == "Linking successful":
+                print_debug("div.fita")
+            else:
+                print(linkingResult)
         else:
-            print(linkingResult)
-    else:
-        print(assemblyResult)
\ No newline at end of file
+            print(assemblyResult)
+
+    with tempfile.TemporaryDirectory() as folder:
+        check_assemble_and_link(folder)
+        check_build(folder)
+        check_loader(folder)
+    print("All checks passed")